@@ -0,0 +1,848 @@
+use crate::errors::Result;
+use crate::service::adapters::relays;
+use crate::service::app::commands::{downloader, mute_lists, notifier};
+use crate::service::domain::events;
+use crate::service::domain::limits;
+use crate::service::ports::http;
+use crate::service::ports::http::rate_limits;
+use clap::Parser;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Prefix of all environment variables which are read by the service, e.g.
+// NOS_NOTIFICATION_SERVICE_LISTEN_ADDRESS.
+pub const ENV_PREFIX: &str = "NOS_NOTIFICATION_SERVICE_";
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:7878";
+const DEFAULT_REST_LISTEN_ADDRESS: &str = "127.0.0.1:7879";
+const DEFAULT_DATABASE_PATH: &str = "/tmp/db.sqlite";
+const DEFAULT_LOG_LEVEL: log::LevelFilter = log::LevelFilter::Info;
+const DEFAULT_SERVICE_NAME: &str = "nos-notification-service";
+
+pub struct Config {
+    pub listen_address: String,
+    pub database_path: PathBuf,
+    pub log_level: log::LevelFilter,
+    pub service: ServiceConfig,
+    pub limits: http::Limits,
+    pub registrations: limits::RegistrationLimits,
+    pub rate_limits: rate_limits::RateLimits,
+    pub rest: RestConfig,
+    pub downloader: DownloaderConfig,
+}
+
+pub struct ServiceConfig {
+    pub name: String,
+    pub keys: nostr::Keys,
+    pub public_url: nostr::Url,
+    pub auth_required: bool,
+    pub registration_kind: nostr::Kind,
+    pub unregistration_kind: nostr::Kind,
+    pub freshness_window: Duration,
+}
+
+pub struct RestConfig {
+    pub enabled: bool,
+    pub listen_address: String,
+    pub public_url: nostr::Url,
+}
+
+// Controls downloading events from relays and sending notifications about them.
+pub struct DownloaderConfig {
+    pub enabled: bool,
+    pub relay_timeout: Duration,
+    pub update_interval: Duration,
+    pub mute_list_refresh_interval: Duration,
+    pub notifier_interval: Duration,
+}
+
+impl Config {
+    // Loads the config from a TOML file, environment variables and command line flags. Flags take
+    // precedence over environment variables which take precedence over the file. The secret key
+    // can't be passed as a flag so that it doesn't end up in the list of processes.
+    pub fn load() -> Result<Config> {
+        let env: HashMap<String, String> = std::env::vars().collect();
+        Config::new(Flags::parse(), &env)
+    }
+
+    fn new(flags: Flags, env: &HashMap<String, String>) -> Result<Config> {
+        let config_path = flags
+            .config
+            .clone()
+            .or_else(|| env.get(&env_name("CONFIG")).map(PathBuf::from));
+
+        let file = match config_path {
+            Some(path) => Layer::from_file(&path)?,
+            None => Layer::default(),
+        };
+
+        file.merge(Layer::from_env(env)?)
+            .merge(Layer::from_flags(flags))
+            .build()
+    }
+}
+
+#[derive(Parser)]
+#[command(about = "Sends push notifications about nostr events to registered devices")]
+struct Flags {
+    /// Path to a TOML config file
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 0.0.0.0:7878
+    #[arg(long)]
+    listen_address: Option<String>,
+
+    /// Address to listen on for HTTP requests, e.g. 0.0.0.0:7879
+    #[arg(long)]
+    rest_listen_address: Option<String>,
+
+    /// Path to the SQLite database
+    #[arg(long)]
+    database_path: Option<PathBuf>,
+
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long)]
+    log_level: Option<String>,
+}
+
+// Values set by a single source of configuration. Values which aren't set are taken from sources
+// with lower precedence or defaults.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Layer {
+    listen_address: Option<String>,
+    database_path: Option<PathBuf>,
+    log_level: Option<String>,
+    service: ServiceLayer,
+    limits: LimitsLayer,
+    registrations: RegistrationsLayer,
+    rate_limits: RateLimitsLayer,
+    rest: RestLayer,
+    downloader: DownloaderLayer,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServiceLayer {
+    name: Option<String>,
+    secret_key: Option<String>,
+    public_url: Option<String>,
+    auth_required: Option<bool>,
+    registration_kind: Option<u64>,
+    unregistration_kind: Option<u64>,
+    freshness_window_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsLayer {
+    max_connections: Option<usize>,
+    max_message_size: Option<usize>,
+    read_timeout_secs: Option<u64>,
+    idle_timeout_secs: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RegistrationsLayer {
+    max_relays_per_registration: Option<usize>,
+    max_relays: Option<usize>,
+    max_registrations: Option<usize>,
+    max_content_size: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitsLayer {
+    per_ip_burst: Option<u32>,
+    per_ip_per_minute: Option<u32>,
+    per_pub_key_burst: Option<u32>,
+    per_pub_key_per_minute: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RestLayer {
+    enabled: Option<bool>,
+    listen_address: Option<String>,
+    public_url: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DownloaderLayer {
+    enabled: Option<bool>,
+    relay_timeout_secs: Option<u64>,
+    update_interval_secs: Option<u64>,
+    mute_list_refresh_interval_secs: Option<u64>,
+    notifier_interval_secs: Option<u64>,
+}
+
+impl Layer {
+    fn from_file(path: &PathBuf) -> Result<Layer> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("error reading config file '{}': {err}", path.display()))?;
+        let layer = toml::from_str(&content)
+            .map_err(|err| format!("error parsing config file '{}': {err}", path.display()))?;
+        Ok(layer)
+    }
+
+    fn from_env(env: &HashMap<String, String>) -> Result<Layer> {
+        Ok(Layer {
+            listen_address: env_value(env, "LISTEN_ADDRESS")?,
+            database_path: env_value(env, "DATABASE_PATH")?,
+            log_level: env_value(env, "LOG_LEVEL")?,
+            service: ServiceLayer {
+                name: env_value(env, "NAME")?,
+                secret_key: env_value(env, "SECRET_KEY")?,
+                public_url: env_value(env, "PUBLIC_URL")?,
+                auth_required: env_value(env, "AUTH_REQUIRED")?,
+                registration_kind: env_value(env, "REGISTRATION_KIND")?,
+                unregistration_kind: env_value(env, "UNREGISTRATION_KIND")?,
+                freshness_window_secs: env_value(env, "FRESHNESS_WINDOW_SECS")?,
+            },
+            limits: LimitsLayer {
+                max_connections: env_value(env, "MAX_CONNECTIONS")?,
+                max_message_size: env_value(env, "MAX_MESSAGE_SIZE")?,
+                read_timeout_secs: env_value(env, "READ_TIMEOUT_SECS")?,
+                idle_timeout_secs: env_value(env, "IDLE_TIMEOUT_SECS")?,
+            },
+            registrations: RegistrationsLayer {
+                max_relays_per_registration: env_value(env, "MAX_RELAYS_PER_REGISTRATION")?,
+                max_relays: env_value(env, "MAX_RELAYS")?,
+                max_registrations: env_value(env, "MAX_REGISTRATIONS")?,
+                max_content_size: env_value(env, "MAX_CONTENT_SIZE")?,
+            },
+            rate_limits: RateLimitsLayer {
+                per_ip_burst: env_value(env, "RATE_LIMIT_PER_IP_BURST")?,
+                per_ip_per_minute: env_value(env, "RATE_LIMIT_PER_IP_PER_MINUTE")?,
+                per_pub_key_burst: env_value(env, "RATE_LIMIT_PER_PUB_KEY_BURST")?,
+                per_pub_key_per_minute: env_value(env, "RATE_LIMIT_PER_PUB_KEY_PER_MINUTE")?,
+            },
+            rest: RestLayer {
+                enabled: env_value(env, "REST_ENABLED")?,
+                listen_address: env_value(env, "REST_LISTEN_ADDRESS")?,
+                public_url: env_value(env, "REST_PUBLIC_URL")?,
+            },
+            downloader: DownloaderLayer {
+                enabled: env_value(env, "DOWNLOADER_ENABLED")?,
+                relay_timeout_secs: env_value(env, "DOWNLOADER_RELAY_TIMEOUT_SECS")?,
+                update_interval_secs: env_value(env, "DOWNLOADER_UPDATE_INTERVAL_SECS")?,
+                mute_list_refresh_interval_secs: env_value(
+                    env,
+                    "DOWNLOADER_MUTE_LIST_REFRESH_INTERVAL_SECS",
+                )?,
+                notifier_interval_secs: env_value(env, "DOWNLOADER_NOTIFIER_INTERVAL_SECS")?,
+            },
+        })
+    }
+
+    fn from_flags(flags: Flags) -> Layer {
+        Layer {
+            listen_address: flags.listen_address,
+            database_path: flags.database_path,
+            log_level: flags.log_level,
+            rest: RestLayer {
+                listen_address: flags.rest_listen_address,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // Values set in the other layer take precedence.
+    fn merge(self, other: Layer) -> Layer {
+        Layer {
+            listen_address: other.listen_address.or(self.listen_address),
+            database_path: other.database_path.or(self.database_path),
+            log_level: other.log_level.or(self.log_level),
+            service: ServiceLayer {
+                name: other.service.name.or(self.service.name),
+                secret_key: other.service.secret_key.or(self.service.secret_key),
+                public_url: other.service.public_url.or(self.service.public_url),
+                auth_required: other.service.auth_required.or(self.service.auth_required),
+                registration_kind: other
+                    .service
+                    .registration_kind
+                    .or(self.service.registration_kind),
+                unregistration_kind: other
+                    .service
+                    .unregistration_kind
+                    .or(self.service.unregistration_kind),
+                freshness_window_secs: other
+                    .service
+                    .freshness_window_secs
+                    .or(self.service.freshness_window_secs),
+            },
+            limits: LimitsLayer {
+                max_connections: other.limits.max_connections.or(self.limits.max_connections),
+                max_message_size: other
+                    .limits
+                    .max_message_size
+                    .or(self.limits.max_message_size),
+                read_timeout_secs: other
+                    .limits
+                    .read_timeout_secs
+                    .or(self.limits.read_timeout_secs),
+                idle_timeout_secs: other
+                    .limits
+                    .idle_timeout_secs
+                    .or(self.limits.idle_timeout_secs),
+            },
+            registrations: RegistrationsLayer {
+                max_relays_per_registration: other
+                    .registrations
+                    .max_relays_per_registration
+                    .or(self.registrations.max_relays_per_registration),
+                max_relays: other
+                    .registrations
+                    .max_relays
+                    .or(self.registrations.max_relays),
+                max_registrations: other
+                    .registrations
+                    .max_registrations
+                    .or(self.registrations.max_registrations),
+                max_content_size: other
+                    .registrations
+                    .max_content_size
+                    .or(self.registrations.max_content_size),
+            },
+            rate_limits: RateLimitsLayer {
+                per_ip_burst: other
+                    .rate_limits
+                    .per_ip_burst
+                    .or(self.rate_limits.per_ip_burst),
+                per_ip_per_minute: other
+                    .rate_limits
+                    .per_ip_per_minute
+                    .or(self.rate_limits.per_ip_per_minute),
+                per_pub_key_burst: other
+                    .rate_limits
+                    .per_pub_key_burst
+                    .or(self.rate_limits.per_pub_key_burst),
+                per_pub_key_per_minute: other
+                    .rate_limits
+                    .per_pub_key_per_minute
+                    .or(self.rate_limits.per_pub_key_per_minute),
+            },
+            rest: RestLayer {
+                enabled: other.rest.enabled.or(self.rest.enabled),
+                listen_address: other.rest.listen_address.or(self.rest.listen_address),
+                public_url: other.rest.public_url.or(self.rest.public_url),
+            },
+            downloader: DownloaderLayer {
+                enabled: other.downloader.enabled.or(self.downloader.enabled),
+                relay_timeout_secs: other
+                    .downloader
+                    .relay_timeout_secs
+                    .or(self.downloader.relay_timeout_secs),
+                update_interval_secs: other
+                    .downloader
+                    .update_interval_secs
+                    .or(self.downloader.update_interval_secs),
+                mute_list_refresh_interval_secs: other
+                    .downloader
+                    .mute_list_refresh_interval_secs
+                    .or(self.downloader.mute_list_refresh_interval_secs),
+                notifier_interval_secs: other
+                    .downloader
+                    .notifier_interval_secs
+                    .or(self.downloader.notifier_interval_secs),
+            },
+        }
+    }
+
+    fn build(self) -> Result<Config> {
+        let listen_address = self
+            .listen_address
+            .unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.to_string());
+        if listen_address.is_empty() {
+            return Err(invalid("listen_address", "empty address"));
+        }
+
+        let database_path = self
+            .database_path
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DATABASE_PATH));
+
+        let log_level = match self.log_level {
+            Some(log_level) => log::LevelFilter::from_str(&log_level)
+                .map_err(|_| invalid("log_level", format!("unknown level '{log_level}'")))?,
+            None => DEFAULT_LOG_LEVEL,
+        };
+
+        Ok(Config {
+            service: self.service.build(&listen_address)?,
+            limits: self.limits.build()?,
+            registrations: self.registrations.build()?,
+            rate_limits: self.rate_limits.build()?,
+            rest: self.rest.build()?,
+            downloader: self.downloader.build()?,
+            listen_address,
+            database_path,
+            log_level,
+        })
+    }
+}
+
+impl ServiceLayer {
+    // Unless configured otherwise the service assumes that clients connect to it directly.
+    fn build(self, listen_address: &str) -> Result<ServiceConfig> {
+        let name = self
+            .name
+            .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+        if name.is_empty() {
+            return Err(invalid("service.name", "empty name"));
+        }
+
+        let secret_key = self.secret_key.ok_or_else(|| {
+            format!(
+                "missing service.secret_key, set it in the config file or using {}",
+                env_name("SECRET_KEY")
+            )
+        })?;
+        let secret_key = nostr::secp256k1::SecretKey::from_str(&secret_key)
+            .map_err(|err| invalid("service.secret_key", err))?;
+
+        let public_url = self
+            .public_url
+            .unwrap_or_else(|| format!("ws://{listen_address}"));
+        let public_url =
+            nostr::Url::parse(&public_url).map_err(|err| invalid("service.public_url", err))?;
+
+        let registration_kind = self
+            .registration_kind
+            .unwrap_or(events::DEFAULT_REGISTRATION_KIND);
+        let unregistration_kind = self
+            .unregistration_kind
+            .unwrap_or(events::DEFAULT_UNREGISTRATION_KIND);
+        if registration_kind == unregistration_kind {
+            return Err(invalid(
+                "service.unregistration_kind",
+                "must be different from service.registration_kind",
+            ));
+        }
+
+        let freshness_window = self
+            .freshness_window_secs
+            .map(Duration::from_secs)
+            .unwrap_or(events::DEFAULT_FRESHNESS_WINDOW);
+        if freshness_window.is_zero() {
+            return Err(invalid(
+                "service.freshness_window_secs",
+                "must be greater than zero",
+            ));
+        }
+
+        Ok(ServiceConfig {
+            name,
+            keys: nostr::Keys::new(secret_key),
+            public_url,
+            auth_required: self.auth_required.unwrap_or(false),
+            registration_kind: nostr::Kind::from(registration_kind),
+            unregistration_kind: nostr::Kind::from(unregistration_kind),
+            freshness_window,
+        })
+    }
+}
+
+impl LimitsLayer {
+    fn build(self) -> Result<http::Limits> {
+        let defaults = http::Limits::default();
+        http::Limits::new(
+            self.max_connections.unwrap_or(defaults.max_connections()),
+            self.max_message_size.unwrap_or(defaults.max_message_size()),
+            self.read_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.read_timeout()),
+            self.idle_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.idle_timeout()),
+        )
+        .map_err(|err| invalid("limits", err))
+    }
+}
+
+impl RegistrationsLayer {
+    fn build(self) -> Result<limits::RegistrationLimits> {
+        let defaults = limits::RegistrationLimits::default();
+        limits::RegistrationLimits::new(
+            self.max_relays_per_registration
+                .unwrap_or(defaults.max_relays_per_registration()),
+            self.max_relays.unwrap_or(defaults.max_relays()),
+            self.max_registrations
+                .unwrap_or(defaults.max_registrations()),
+            self.max_content_size.unwrap_or(defaults.max_content_size()),
+        )
+        .map_err(|err| invalid("registrations", err))
+    }
+}
+
+impl RateLimitsLayer {
+    fn build(self) -> Result<rate_limits::RateLimits> {
+        let defaults = rate_limits::RateLimits::default();
+        let per_ip = rate_limits::RateLimit::new(
+            self.per_ip_burst.unwrap_or(defaults.per_ip().burst()),
+            self.per_ip_per_minute
+                .unwrap_or(defaults.per_ip().per_minute()),
+        )
+        .map_err(|err| invalid("rate_limits.per_ip", err))?;
+        let per_pub_key = rate_limits::RateLimit::new(
+            self.per_pub_key_burst
+                .unwrap_or(defaults.per_pub_key().burst()),
+            self.per_pub_key_per_minute
+                .unwrap_or(defaults.per_pub_key().per_minute()),
+        )
+        .map_err(|err| invalid("rate_limits.per_pub_key", err))?;
+        Ok(rate_limits::RateLimits::new(per_ip, per_pub_key))
+    }
+}
+
+impl RestLayer {
+    fn build(self) -> Result<RestConfig> {
+        let listen_address = self
+            .listen_address
+            .unwrap_or_else(|| DEFAULT_REST_LISTEN_ADDRESS.to_string());
+        if listen_address.is_empty() {
+            return Err(invalid("rest.listen_address", "empty address"));
+        }
+
+        let public_url = self
+            .public_url
+            .unwrap_or_else(|| format!("http://{listen_address}"));
+        let public_url =
+            nostr::Url::parse(&public_url).map_err(|err| invalid("rest.public_url", err))?;
+        if !matches!(public_url.scheme(), "http" | "https") {
+            return Err(invalid(
+                "rest.public_url",
+                format!("unsupported scheme '{}'", public_url.scheme()),
+            ));
+        }
+
+        Ok(RestConfig {
+            enabled: self.enabled.unwrap_or(true),
+            listen_address,
+            public_url,
+        })
+    }
+}
+
+impl DownloaderLayer {
+    fn build(self) -> Result<DownloaderConfig> {
+        Ok(DownloaderConfig {
+            enabled: self.enabled.unwrap_or(true),
+            relay_timeout: positive_duration(
+                "downloader.relay_timeout_secs",
+                self.relay_timeout_secs,
+                relays::DEFAULT_TIMEOUT,
+            )?,
+            update_interval: positive_duration(
+                "downloader.update_interval_secs",
+                self.update_interval_secs,
+                downloader::UPDATE_INTERVAL,
+            )?,
+            mute_list_refresh_interval: positive_duration(
+                "downloader.mute_list_refresh_interval_secs",
+                self.mute_list_refresh_interval_secs,
+                mute_lists::REFRESH_INTERVAL,
+            )?,
+            notifier_interval: positive_duration(
+                "downloader.notifier_interval_secs",
+                self.notifier_interval_secs,
+                notifier::PROCESS_INTERVAL,
+            )?,
+        })
+    }
+}
+
+fn positive_duration(key: &str, secs: Option<u64>, default: Duration) -> Result<Duration> {
+    match secs {
+        Some(0) => Err(invalid(key, "must be greater than zero")),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Ok(default),
+    }
+}
+
+fn env_name(name: &str) -> String {
+    format!("{ENV_PREFIX}{name}")
+}
+
+fn env_value<T>(env: &HashMap<String, String>, name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let name = env_name(name);
+    match env.get(&name) {
+        Some(value) => match T::from_str(value) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => Err(format!("invalid value of {name}: {err}").into()),
+        },
+        None => Ok(None),
+    }
+}
+
+fn invalid(key: &str, err: impl fmt::Display) -> Box<dyn std::error::Error> {
+    format!("invalid {key}: {err}").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const SECRET_KEY: &str = "5c0c523f52a5b6fad39ed2403092df8cebc36318b39383bca6c00808626fab3a";
+
+    #[test]
+    fn defaults_are_used_if_nothing_is_set() -> Result<()> {
+        let config = Config::new(flags(&[]), &env(&[("SECRET_KEY", SECRET_KEY)]))?;
+
+        assert_eq!(config.listen_address, DEFAULT_LISTEN_ADDRESS);
+        assert_eq!(config.database_path, PathBuf::from(DEFAULT_DATABASE_PATH));
+        assert_eq!(config.log_level, DEFAULT_LOG_LEVEL);
+        assert_eq!(config.service.name, DEFAULT_SERVICE_NAME);
+        assert_eq!(config.service.public_url.as_str(), "ws://127.0.0.1:7878/");
+        assert!(!config.service.auth_required);
+        assert!(config.rest.enabled);
+        assert_eq!(config.rest.listen_address, DEFAULT_REST_LISTEN_ADDRESS);
+        assert_eq!(config.rest.public_url.as_str(), "http://127.0.0.1:7879/");
+        assert!(config.downloader.enabled);
+        assert_eq!(config.downloader.relay_timeout, relays::DEFAULT_TIMEOUT);
+        assert_eq!(
+            config.downloader.update_interval,
+            downloader::UPDATE_INTERVAL
+        );
+        assert_eq!(
+            config.downloader.mute_list_refresh_interval,
+            mute_lists::REFRESH_INTERVAL
+        );
+        assert_eq!(
+            config.downloader.notifier_interval,
+            notifier::PROCESS_INTERVAL
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn flags_take_precedence_over_env_which_takes_precedence_over_the_file() -> Result<()> {
+        let file = config_file(&format!(
+            r#"
+            listen_address = "0.0.0.0:1000"
+            database_path = "/file.sqlite"
+            log_level = "debug"
+
+            [service]
+            name = "file-service"
+            secret_key = "{SECRET_KEY}"
+            "#
+        ));
+
+        let config = Config::new(
+            flags(&[
+                "--config",
+                file.path().to_str().unwrap(),
+                "--listen-address",
+                "0.0.0.0:3000",
+            ]),
+            &env(&[
+                ("LISTEN_ADDRESS", "0.0.0.0:2000"),
+                ("DATABASE_PATH", "/env.sqlite"),
+            ]),
+        )?;
+
+        assert_eq!(config.listen_address, "0.0.0.0:3000");
+        assert_eq!(config.database_path, PathBuf::from("/env.sqlite"));
+        assert_eq!(config.log_level, log::LevelFilter::Debug);
+        assert_eq!(config.service.name, "file-service");
+        Ok(())
+    }
+
+    #[test]
+    fn config_file_can_be_passed_using_env() -> Result<()> {
+        let file = config_file("[downloader]\nenabled = false\n");
+
+        let config = Config::new(
+            flags(&[]),
+            &env(&[
+                ("CONFIG", file.path().to_str().unwrap()),
+                ("SECRET_KEY", SECRET_KEY),
+            ]),
+        )?;
+
+        assert!(!config.downloader.enabled);
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limits_can_be_configured() -> Result<()> {
+        let file = config_file("[rate_limits]\nper_ip_burst = 100\nper_pub_key_per_minute = 2\n");
+
+        let config = Config::new(
+            flags(&["--config", file.path().to_str().unwrap()]),
+            &env(&[
+                ("SECRET_KEY", SECRET_KEY),
+                ("RATE_LIMIT_PER_IP_PER_MINUTE", "600"),
+            ]),
+        )?;
+
+        let defaults = rate_limits::RateLimits::default();
+        assert_eq!(
+            config.rate_limits.per_ip(),
+            &rate_limits::RateLimit::new(100, 600)?
+        );
+        assert_eq!(
+            config.rate_limits.per_pub_key(),
+            &rate_limits::RateLimit::new(defaults.per_pub_key().burst(), 2)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn registration_limits_can_be_configured() -> Result<()> {
+        let file =
+            config_file("[registrations]\nmax_relays_per_registration = 4\nmax_relays = 100\n");
+
+        let config = Config::new(
+            flags(&["--config", file.path().to_str().unwrap()]),
+            &env(&[("SECRET_KEY", SECRET_KEY), ("MAX_RELAYS", "200")]),
+        )?;
+
+        let defaults = limits::RegistrationLimits::default();
+        assert_eq!(
+            config.registrations,
+            limits::RegistrationLimits::new(
+                4,
+                200,
+                defaults.max_registrations(),
+                defaults.max_content_size()
+            )?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn downloader_intervals_can_be_configured() -> Result<()> {
+        let file = config_file("[downloader]\nrelay_timeout_secs = 10\nupdate_interval_secs = 5\n");
+
+        let config = Config::new(
+            flags(&["--config", file.path().to_str().unwrap()]),
+            &env(&[
+                ("SECRET_KEY", SECRET_KEY),
+                ("DOWNLOADER_UPDATE_INTERVAL_SECS", "20"),
+                ("DOWNLOADER_MUTE_LIST_REFRESH_INTERVAL_SECS", "60"),
+                ("DOWNLOADER_NOTIFIER_INTERVAL_SECS", "2"),
+            ]),
+        )?;
+
+        assert_eq!(config.downloader.relay_timeout, Duration::from_secs(10));
+        assert_eq!(config.downloader.update_interval, Duration::from_secs(20));
+        assert_eq!(
+            config.downloader.mute_list_refresh_interval,
+            Duration::from_secs(60)
+        );
+        assert_eq!(config.downloader.notifier_interval, Duration::from_secs(2));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_configs_are_reported() {
+        let cases = vec![
+            (
+                vec![],
+                String::from("missing service.secret_key, set it in the config file or using NOS_NOTIFICATION_SERVICE_SECRET_KEY"),
+            ),
+            (
+                vec![("SECRET_KEY", SECRET_KEY), ("LOG_LEVEL", "loud")],
+                String::from("invalid log_level: unknown level 'loud'"),
+            ),
+            (
+                vec![("SECRET_KEY", SECRET_KEY), ("AUTH_REQUIRED", "yes")],
+                String::from("invalid value of NOS_NOTIFICATION_SERVICE_AUTH_REQUIRED: provided string was not `true` or `false`"),
+            ),
+            (
+                vec![("SECRET_KEY", SECRET_KEY), ("UNREGISTRATION_KIND", "26666")],
+                String::from("invalid service.unregistration_kind: must be different from service.registration_kind"),
+            ),
+            (
+                vec![("SECRET_KEY", SECRET_KEY), ("MAX_CONNECTIONS", "0")],
+                String::from("invalid limits: max connections must be greater than zero"),
+            ),
+            (
+                vec![("SECRET_KEY", SECRET_KEY), ("MAX_RELAYS", "1")],
+                String::from("invalid registrations: max relays can't be lower than max relays per registration"),
+            ),
+            (
+                vec![("SECRET_KEY", SECRET_KEY), ("RATE_LIMIT_PER_PUB_KEY_BURST", "0")],
+                String::from("invalid rate_limits.per_pub_key: burst must be greater than zero"),
+            ),
+            (
+                vec![("SECRET_KEY", SECRET_KEY), ("REST_PUBLIC_URL", "ws://example.com")],
+                String::from("invalid rest.public_url: unsupported scheme 'ws'"),
+            ),
+            (
+                vec![("SECRET_KEY", SECRET_KEY), ("DOWNLOADER_UPDATE_INTERVAL_SECS", "0")],
+                String::from("invalid downloader.update_interval_secs: must be greater than zero"),
+            ),
+        ];
+
+        for (vars, expected_error) in cases {
+            match Config::new(flags(&[]), &env(&vars)) {
+                Ok(_) => panic!("expected an error: {expected_error}"),
+                Err(err) => assert_eq!(err.to_string(), expected_error),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_keys_in_the_config_file_are_rejected() {
+        let file = config_file("listen_adress = \"0.0.0.0:1000\"\n");
+
+        let result = Config::new(
+            flags(&["--config", file.path().to_str().unwrap()]),
+            &env(&[("SECRET_KEY", SECRET_KEY)]),
+        );
+
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => assert!(err.to_string().contains("unknown field `listen_adress`")),
+        }
+    }
+
+    #[test]
+    fn missing_config_file_is_reported() {
+        let result = Config::new(
+            flags(&["--config", "/does/not/exist.toml"]),
+            &env(&[("SECRET_KEY", SECRET_KEY)]),
+        );
+
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => assert!(err
+                .to_string()
+                .starts_with("error reading config file '/does/not/exist.toml'")),
+        }
+    }
+
+    fn flags(args: &[&str]) -> Flags {
+        Flags::try_parse_from(
+            std::iter::once("nos_notification_service").chain(args.iter().copied()),
+        )
+        .unwrap()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (env_name(name), value.to_string()))
+            .collect()
+    }
+
+    fn config_file(content: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+}
@@ -1,31 +1,628 @@
+use crate::errors::Result;
+use crate::migrations::MigrationCallable;
+use crate::service::adapters::sqlite as sqliteadapters;
+use crate::service::app::commands;
+use crate::service::app::common;
+use crate::service::app::queries;
 use crate::service::domain;
+use crate::service::domain::encryption;
+use crate::service::domain::events;
 use rand::{distributions::Alphanumeric, Rng};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::ops::RangeBounds;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const SERVICE: &str = "some-service";
+pub const RELAY: &str = "wss://relay.example.com";
+pub const APNS_TOKEN: &str = "740f4707bebcf74f9b7c25d48e3358945f6aa01da5ddb387462c7eaf61bb78ad";
 
 pub fn some_relay_address() -> domain::RelayAddress {
-    return domain::RelayAddress::new(String::from(format!(
-        "wss://some-relay-address-{}",
-        random_string()
-    )))
-    .unwrap();
+    domain::RelayAddress::new(format!("wss://some-relay-address-{}", random_string())).unwrap()
 }
 
 pub fn some_pub_key() -> domain::PubKey {
     let (_sk, pk) = nostr::secp256k1::generate_keypair(&mut rand::rngs::OsRng {});
-    return domain::PubKey::new(nostr::key::XOnlyPublicKey::from(pk));
+    domain::PubKey::new(nostr::key::XOnlyPublicKey::from(pk))
+}
+
+pub fn some_push_token() -> domain::PushToken {
+    domain::PushToken::new(
+        domain::PushProvider::ApnsProduction,
+        String::from(APNS_TOKEN),
+    )
+    .unwrap()
 }
 
-pub fn some_apns_token() -> domain::APNSToken {
-    return domain::APNSToken::new(String::from("apns_token")).unwrap();
+pub fn fcm_token(token: &str) -> domain::PushToken {
+    domain::PushToken::new(domain::PushProvider::Fcm, String::from(token)).unwrap()
 }
 
 pub fn some_locale() -> domain::Locale {
-    return domain::Locale::new(String::from("some locale")).unwrap();
+    domain::Locale::new(String::from("en-US")).unwrap()
 }
 
 fn random_string() -> String {
-    return rand::thread_rng()
+    rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(7)
         .map(char::from)
-        .collect();
+        .collect()
+}
+
+pub fn some_registration() -> domain::Registration {
+    domain::Registration::new(
+        some_pub_key(),
+        some_push_token(),
+        vec![some_relay_address(), some_relay_address()],
+        some_locale(),
+        domain::notifications::Preferences::default(),
+        None,
+        some_event_info(),
+    )
+    .unwrap()
+}
+
+pub fn some_event_info() -> domain::EventInfo {
+    let id: [u8; 32] = rand::random();
+    domain::EventInfo::new(
+        nostr::EventId::from_slice(&id).unwrap(),
+        nostr::Timestamp::now(),
+    )
+}
+
+pub fn mute_list_event(keys: &nostr::Keys, tags: Vec<nostr::Tag>) -> nostr::Event {
+    nostr::EventBuilder::new(nostr::Kind::MuteList, "", &tags)
+        .to_event(keys)
+        .unwrap()
+}
+
+pub fn contact_list_event(keys: &nostr::Keys, tags: Vec<nostr::Tag>) -> nostr::Event {
+    nostr::EventBuilder::new(nostr::Kind::ContactList, "", &tags)
+        .to_event(keys)
+        .unwrap()
+}
+
+// In-memory database with every migration applied.
+pub fn new_sqlite() -> Result<sqliteadapters::SqliteConnectionAdapter> {
+    let conn = sqliteadapters::SqliteConnectionAdapter::new(sqlite::open(":memory:")?)?;
+    run_migrations(&conn, ..)?;
+    Ok(conn)
+}
+
+// Runs the migrations with the given numbers in order so that tests can insert rows saved by older
+// versions of the service before running the remaining migrations.
+pub fn run_migrations(
+    conn: &sqliteadapters::SqliteConnectionAdapter,
+    numbers: impl RangeBounds<usize>,
+) -> Result<()> {
+    let migrations: &[&dyn MigrationCallable] = &[
+        &sqliteadapters::RegistrationRepositoryMigration0001::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0002::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0003::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0004::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0005::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0006::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0007::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0008::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0009::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0010::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0011::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0012::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0013::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0014::new(conn.clone()),
+        &sqliteadapters::RegistrationRepositoryMigration0015::new(conn.clone()),
+    ];
+    for (i, migration) in migrations.iter().enumerate() {
+        if numbers.contains(&(i + 1)) {
+            migration.run()?;
+        }
+    }
+    Ok(())
+}
+
+pub fn service_keys() -> nostr::Keys {
+    let secret_key = nostr::secp256k1::SecretKey::from_str(
+        "5c0c523f52a5b6fad39ed2403092df8cebc36318b39383bca6c00808626fab3a",
+    )
+    .unwrap();
+    nostr::Keys::new(secret_key)
+}
+
+pub fn some_keys() -> nostr::Keys {
+    nostr::Keys::generate()
+}
+
+pub fn some_registration_event_content() -> events::RegistrationEventContent {
+    events::RegistrationEventContent {
+        provider: Some(some_push_token().provider().as_str().to_string()),
+        token: Some(some_push_token().as_ref().to_string()),
+        apns_token: None,
+        relays: vec![some_relay_address().as_ref().to_string()],
+        locale: some_locale().as_ref().to_string(),
+        preferences: None,
+        quiet_hours: None,
+    }
+}
+
+pub fn some_registration_event(keys: &nostr::Keys) -> nostr::Event {
+    registration_event(
+        keys,
+        nostr::Kind::from(events::DEFAULT_REGISTRATION_KIND),
+        vec![service_tag(SERVICE)],
+    )
+}
+
+pub fn registration_event(
+    keys: &nostr::Keys,
+    kind: nostr::Kind,
+    tags: Vec<nostr::Tag>,
+) -> nostr::Event {
+    let content = encrypt_for_service(keys, &some_registration_event_content());
+    event_with_content(keys, kind, tags, content)
+}
+
+pub fn some_unregistration_event(
+    keys: &nostr::Keys,
+    push_token: &domain::PushToken,
+) -> nostr::Event {
+    let content = encrypt_for_service(
+        keys,
+        &events::UnregistrationEventContent {
+            provider: Some(push_token.provider().as_str().to_string()),
+            token: Some(push_token.as_ref().to_string()),
+            apns_token: None,
+        },
+    );
+    event_with_content(
+        keys,
+        nostr::Kind::from(events::DEFAULT_UNREGISTRATION_KIND),
+        vec![service_tag(SERVICE)],
+        content,
+    )
+}
+
+pub fn encrypt_for_service(keys: &nostr::Keys, content: &impl serde::Serialize) -> String {
+    let content = serde_json::to_string(content).unwrap();
+    let conversation_key = encryption::nip44::ConversationKey::new(
+        &keys.secret_key().unwrap(),
+        &service_keys().public_key(),
+    );
+    encryption::nip44::encrypt(&conversation_key, &content).unwrap()
+}
+
+// Signs the event again as if it was created at a different time.
+pub fn with_created_at(
+    keys: &nostr::Keys,
+    event: nostr::Event,
+    created_at: nostr::Timestamp,
+) -> nostr::Event {
+    let unsigned = nostr::UnsignedEvent {
+        id: nostr::EventId::new(
+            &event.pubkey,
+            created_at,
+            &event.kind,
+            &event.tags,
+            &event.content,
+        ),
+        pubkey: event.pubkey,
+        created_at,
+        kind: event.kind,
+        tags: event.tags,
+        content: event.content,
+    };
+    unsigned.sign(keys).unwrap()
+}
+
+pub fn event_with_content(
+    keys: &nostr::Keys,
+    kind: nostr::Kind,
+    tags: Vec<nostr::Tag>,
+    content: String,
+) -> nostr::Event {
+    nostr::EventBuilder::new(kind, content, &tags)
+        .to_event(keys)
+        .unwrap()
+}
+
+pub fn service_tag(service: &str) -> nostr::Tag {
+    nostr::Tag::Generic(
+        nostr::event::TagKind::Custom(String::from(events::SERVICE_TAG)),
+        vec![String::from(service)],
+    )
+}
+
+// Changes the content of a signed event without updating its id or signature.
+pub fn tampered_event(mut event: nostr::Event) -> nostr::Event {
+    event.content = serde_json::to_string(&some_registration_event_content()).unwrap();
+    event
+}
+
+// Claims that the event was authored by the given pub key. The id is recomputed so that only the
+// signature gives the forgery away.
+pub fn forged_event(mut event: nostr::Event, pub_key: nostr::key::XOnlyPublicKey) -> nostr::Event {
+    event.pubkey = pub_key;
+    event.id = nostr::EventId::new(
+        &event.pubkey,
+        event.created_at,
+        &event.kind,
+        &event.tags,
+        &event.content,
+    );
+    event
+}
+
+pub fn auth_event(keys: &nostr::Keys, challenge: &str, relay: &str) -> nostr::Event {
+    let tags = [
+        nostr::Tag::Generic(nostr::event::TagKind::Relay, vec![String::from(relay)]),
+        nostr::Tag::Challenge(String::from(challenge)),
+    ];
+    nostr::EventBuilder::new(nostr::Kind::Authentication, "", &tags)
+        .to_event(keys)
+        .unwrap()
+}
+
+pub fn http_auth_event(
+    keys: &nostr::Keys,
+    url: &str,
+    method: &str,
+    payload: Option<&[u8]>,
+) -> nostr::Event {
+    let mut tags = vec![
+        nostr::Tag::Generic(
+            nostr::event::TagKind::Custom(String::from("u")),
+            vec![String::from(url)],
+        ),
+        nostr::Tag::Generic(
+            nostr::event::TagKind::Custom(String::from("method")),
+            vec![String::from(method)],
+        ),
+    ];
+    if let Some(payload) = payload {
+        tags.push(nostr::Tag::Generic(
+            nostr::event::TagKind::Custom(String::from("payload")),
+            vec![sha256_hex(payload)],
+        ));
+    }
+    nostr::EventBuilder::new(nostr::Kind::from(events::HTTP_AUTH_KIND), "", &tags)
+        .to_event(keys)
+        .unwrap()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use nostr::hashes::Hash;
+    nostr::hashes::sha256::Hash::hash(data).to_string()
+}
+
+pub struct RegisterHandlerMock {
+    error: Option<fn() -> Box<dyn std::error::Error>>,
+    pub_keys: Mutex<Vec<domain::PubKey>>,
+}
+
+impl RegisterHandlerMock {
+    pub fn new() -> Self {
+        Self {
+            error: None,
+            pub_keys: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn new_failing() -> Self {
+        Self {
+            error: Some(|| "mock error".into()),
+            pub_keys: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn new_outdated() -> Self {
+        Self {
+            error: Some(|| commands::OutdatedEventError.into()),
+            pub_keys: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn new_limited() -> Self {
+        Self {
+            error: Some(|| {
+                commands::LimitExceededError("the service doesn't accept new registrations".into())
+                    .into()
+            }),
+            pub_keys: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn pub_keys(&self) -> Vec<domain::PubKey> {
+        self.pub_keys.lock().unwrap().clone()
+    }
+}
+
+impl commands::RegisterHandler for RegisterHandlerMock {
+    fn handle(&self, cmd: &commands::Register) -> Result<()> {
+        if let Some(error) = self.error {
+            return Err(error());
+        }
+        self.pub_keys
+            .lock()
+            .unwrap()
+            .push(cmd.registration.pub_key());
+        Ok(())
+    }
+}
+
+pub struct UnregisterHandlerMock {
+    commands: Mutex<Vec<(domain::PubKey, String)>>,
+}
+
+impl UnregisterHandlerMock {
+    pub fn new() -> Self {
+        Self {
+            commands: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn commands(&self) -> Vec<(domain::PubKey, String)> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+impl commands::UnregisterHandler for UnregisterHandlerMock {
+    fn handle(&self, cmd: &commands::Unregister) -> Result<()> {
+        self.commands
+            .lock()
+            .unwrap()
+            .push((cmd.pub_key.clone(), cmd.push_token.as_ref().to_string()));
+        Ok(())
+    }
+}
+
+pub struct NotificationSenderMock {
+    notifications: Mutex<Vec<domain::notifications::Notification>>,
+    digests: Mutex<Vec<domain::quiet_hours::Digest>>,
+}
+
+impl NotificationSenderMock {
+    pub fn new() -> Self {
+        Self {
+            notifications: Mutex::new(vec![]),
+            digests: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn notifications(&self) -> Vec<domain::notifications::Notification> {
+        self.notifications.lock().unwrap().clone()
+    }
+
+    pub fn digests(&self) -> Vec<domain::quiet_hours::Digest> {
+        self.digests.lock().unwrap().clone()
+    }
+}
+
+impl common::NotificationSender for NotificationSenderMock {
+    fn send(&self, notification: &domain::notifications::Notification) -> Result<()> {
+        self.notifications
+            .lock()
+            .unwrap()
+            .push(notification.clone());
+        Ok(())
+    }
+
+    fn send_digest(&self, digest: &domain::quiet_hours::Digest) -> Result<()> {
+        self.digests.lock().unwrap().push(digest.clone());
+        Ok(())
+    }
+}
+
+pub struct GetRegistrationHandlerMock {
+    registrations: Vec<common::RegistrationInfo>,
+    pub_keys: Mutex<Vec<domain::PubKey>>,
+}
+
+impl GetRegistrationHandlerMock {
+    pub fn new(registrations: Vec<common::RegistrationInfo>) -> Self {
+        Self {
+            registrations,
+            pub_keys: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn pub_keys(&self) -> Vec<domain::PubKey> {
+        self.pub_keys.lock().unwrap().clone()
+    }
+}
+
+impl queries::GetRegistrationHandler for GetRegistrationHandlerMock {
+    fn handle(&self, query: &queries::GetRegistration) -> Result<Vec<common::RegistrationInfo>> {
+        self.pub_keys.lock().unwrap().push(query.pub_key.clone());
+        Ok(self.registrations.clone())
+    }
+}
+
+// Relay which serves the given events to every REQ whose filters match them by kind and author.
+// Plain HTTP requests are answered with the relay information document if there is one.
+// Connections are served one at a time. The relay stops once it is dropped.
+pub struct FakeRelay {
+    address: SocketAddr,
+    requests: Arc<Mutex<Vec<Vec<nostr::Filter>>>>,
+    information_requests: Arc<Mutex<Vec<String>>>,
+    stopped: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl FakeRelay {
+    pub fn new(events: Vec<nostr::Event>) -> Result<FakeRelay> {
+        Self::start(events, None)
+    }
+
+    pub fn new_with_information(events: Vec<nostr::Event>, document: &str) -> Result<FakeRelay> {
+        Self::start(events, Some(document.to_string()))
+    }
+
+    fn start(events: Vec<nostr::Event>, document: Option<String>) -> Result<FakeRelay> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(vec![]));
+        let information_requests = Arc::new(Mutex::new(vec![]));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let requests = requests.clone();
+            let information_requests = information_requests.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    if let Ok(stream) = stream {
+                        let _ = serve_fake_relay_connection(
+                            stream,
+                            &events,
+                            document.as_deref(),
+                            &requests,
+                            &information_requests,
+                        );
+                    }
+                }
+            })
+        };
+
+        Ok(FakeRelay {
+            address,
+            requests,
+            information_requests,
+            stopped,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn address(&self) -> domain::RelayAddress {
+        domain::RelayAddress::new(format!("ws://{}", self.address)).unwrap()
+    }
+
+    pub fn requests(&self) -> Vec<Vec<nostr::Filter>> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // Accept headers of the requests for the relay information document.
+    pub fn information_requests(&self) -> Vec<String> {
+        self.information_requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakeRelay {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = TcpStream::connect(self.address);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve_fake_relay_connection(
+    mut stream: TcpStream,
+    events: &[nostr::Event],
+    document: Option<&str>,
+    requests: &Mutex<Vec<Vec<nostr::Filter>>>,
+    information_requests: &Mutex<Vec<String>>,
+) -> Result<()> {
+    // The request is only peeked at so that websocket handshakes can still be read by tungstenite.
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let head = peek_http_head(&stream)?;
+    let header = |name: &str| {
+        head.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim().to_string())
+        })
+    };
+
+    if !header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")) {
+        let mut buf = vec![0; head.len()];
+        stream.read_exact(&mut buf)?;
+        information_requests
+            .lock()
+            .unwrap()
+            .push(header("accept").unwrap_or_default());
+
+        let response = match document {
+            Some(document) => format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: application/nostr+json\r\nContent-Length: {}\r\n\r\n{document}",
+                document.len()
+            ),
+            None => String::from("HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n"),
+        };
+        stream.write_all(response.as_bytes())?;
+        return Ok(());
+    }
+
+    let mut websocket = tungstenite::accept(stream)?;
+    loop {
+        let text = match websocket.read_message()? {
+            tungstenite::Message::Text(text) => text,
+            tungstenite::Message::Close(_) => return Ok(()),
+            _ => continue,
+        };
+
+        if let nostr::ClientMessage::Req {
+            subscription_id,
+            filters,
+        } = nostr::ClientMessage::from_json(text)?
+        {
+            for event in events {
+                if filters
+                    .iter()
+                    .any(|filter| fake_relay_matches(filter, event))
+                {
+                    let message =
+                        nostr::RelayMessage::new_event(subscription_id.clone(), event.clone());
+                    websocket.write_message(tungstenite::Message::Text(message.as_json()))?;
+                }
+            }
+            let eose = nostr::RelayMessage::new_eose(subscription_id);
+            websocket.write_message(tungstenite::Message::Text(eose.as_json()))?;
+            requests.lock().unwrap().push(filters);
+        }
+    }
+}
+
+fn peek_http_head(stream: &TcpStream) -> Result<String> {
+    let mut buf = [0; 8192];
+    for _ in 0..500 {
+        let n = stream.peek(&mut buf)?;
+        if n == 0 {
+            return Err("connection closed".into());
+        }
+        let data = std::str::from_utf8(&buf[..n])?;
+        if let Some(end) = data.find("\r\n\r\n") {
+            return Ok(data[..end + 4].to_string());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Err("incomplete http request".into())
+}
+
+fn fake_relay_matches(filter: &nostr::Filter, event: &nostr::Event) -> bool {
+    let kind_matches = filter
+        .kinds
+        .as_ref()
+        .is_none_or(|kinds| kinds.contains(&event.kind));
+    let author_matches = filter
+        .authors
+        .as_ref()
+        .is_none_or(|authors| authors.contains(&event.pubkey.to_string()));
+    let pub_key_matches = filter.pubkeys.as_ref().is_none_or(|pub_keys| {
+        event.tags.iter().any(|tag| match tag.as_vec().as_slice() {
+            [name, value, ..] if name == "p" => {
+                pub_keys.iter().any(|pub_key| &pub_key.to_string() == value)
+            }
+            _ => false,
+        })
+    });
+    kind_matches && author_matches && pub_key_matches
 }
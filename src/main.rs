@@ -1,4 +1,5 @@
 #![forbid(unsafe_code)]
+mod config;
 mod errors;
 mod migrations;
 mod service;
@@ -6,15 +7,46 @@ mod service;
 #[cfg(test)]
 mod fixtures;
 
+use crate::errors::Result;
 use crate::service::app;
 use crate::service::app::commands::implementation as commandsimpl;
-use crate::service::ports::http;
+use crate::service::app::queries::implementation as queriesimpl;
+use crate::service::ports::{http, rest};
+use service::adapters::push;
+use service::adapters::relays;
 use service::adapters::sqlite as sqliteadapters;
 use service::app::commands::downloader::Downloader;
+use service::app::commands::mute_lists;
+use service::app::commands::notifier::Notifier;
+use std::sync::mpsc;
 
 fn main() {
-    let conn = sqlite::Connection::open("/tmp/db.sqlite").unwrap();
-    let conn_adapter = sqliteadapters::SqliteConnectionAdapter::new(conn);
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error loading the config: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .init();
+
+    if let Err(err) = run(config) {
+        log::error!("{err}");
+        std::process::exit(1);
+    }
+}
+
+fn run(config: config::Config) -> Result<()> {
+    let conn = sqlite::Connection::open(&config.database_path).map_err(|err| {
+        format!(
+            "error opening the database '{}': {err}",
+            config.database_path.display()
+        )
+    })?;
+    let conn_adapter = sqliteadapters::SqliteConnectionAdapter::new(conn)?;
 
     //let adapters_factory_fn = new_adapters_factory_fn();
 
@@ -24,34 +56,227 @@ fn main() {
     let migration_registration_0001_create_tables =
         sqliteadapters::RegistrationRepositoryMigration0001::new(conn_adapter.clone());
 
-    migrations.push(
-        migrations::Migration::new(
-            "registration.0001_create_tables",
-            &migration_registration_0001_create_tables,
-        )
-        .unwrap(),
-    );
+    migrations.push(migrations::Migration::new(
+        "registration.0001_create_tables",
+        &migration_registration_0001_create_tables,
+    )?);
+
+    let migration_registration_0002_add_event =
+        sqliteadapters::RegistrationRepositoryMigration0002::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0002_add_event",
+        &migration_registration_0002_add_event,
+    )?);
+
+    let migration_registration_0003_add_devices =
+        sqliteadapters::RegistrationRepositoryMigration0003::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0003_add_devices",
+        &migration_registration_0003_add_devices,
+    )?);
+
+    let migration_registration_0004_add_push_providers =
+        sqliteadapters::RegistrationRepositoryMigration0004::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0004_add_push_providers",
+        &migration_registration_0004_add_push_providers,
+    )?);
+
+    let migration_registration_0005_normalize_relay_addresses =
+        sqliteadapters::RegistrationRepositoryMigration0005::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0005_normalize_relay_addresses",
+        &migration_registration_0005_normalize_relay_addresses,
+    )?);
+
+    let migration_registration_0006_add_notification_preferences =
+        sqliteadapters::RegistrationRepositoryMigration0006::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0006_add_notification_preferences",
+        &migration_registration_0006_add_notification_preferences,
+    )?);
+
+    let migration_registration_0007_add_mute_lists =
+        sqliteadapters::RegistrationRepositoryMigration0007::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0007_add_mute_lists",
+        &migration_registration_0007_add_mute_lists,
+    )?);
+
+    let migration_registration_0008_add_quiet_hours =
+        sqliteadapters::RegistrationRepositoryMigration0008::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0008_add_quiet_hours",
+        &migration_registration_0008_add_quiet_hours,
+    )?);
+
+    let migration_registration_0009_add_events =
+        sqliteadapters::RegistrationRepositoryMigration0009::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0009_add_events",
+        &migration_registration_0009_add_events,
+    )?);
+
+    let migration_registration_0010_add_relay_health =
+        sqliteadapters::RegistrationRepositoryMigration0010::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0010_add_relay_health",
+        &migration_registration_0010_add_relay_health,
+    )?);
+
+    let migration_registration_0011_add_relay_information =
+        sqliteadapters::RegistrationRepositoryMigration0011::new(conn_adapter.clone());
 
-    let migrations = migrations::Migrations::new(migrations).unwrap();
+    migrations.push(migrations::Migration::new(
+        "registration.0011_add_relay_information",
+        &migration_registration_0011_add_relay_information,
+    )?);
+
+    let migration_registration_0012_add_unregistrations =
+        sqliteadapters::RegistrationRepositoryMigration0012::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0012_add_unregistrations",
+        &migration_registration_0012_add_unregistrations,
+    )?);
+
+    let migration_registration_0013_remove_invalid_tokens =
+        sqliteadapters::RegistrationRepositoryMigration0013::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0013_remove_invalid_tokens",
+        &migration_registration_0013_remove_invalid_tokens,
+    )?);
+
+    let migration_registration_0014_normalize_locales =
+        sqliteadapters::RegistrationRepositoryMigration0014::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0014_normalize_locales",
+        &migration_registration_0014_normalize_locales,
+    )?);
+
+    let migration_registration_0015_add_relay_since =
+        sqliteadapters::RegistrationRepositoryMigration0015::new(conn_adapter.clone());
+
+    migrations.push(migrations::Migration::new(
+        "registration.0015_add_relay_since",
+        &migration_registration_0015_add_relay_since,
+    )?);
+
+    let migrations = migrations::Migrations::new(migrations)?;
 
     let transaction_provider = sqliteadapters::TransactionProvider::new(conn_adapter.clone());
 
-    let register = commandsimpl::RegisterHandler::new();
+    let register = commandsimpl::RegisterHandler::new(
+        sqliteadapters::TransactionProvider::new(conn_adapter.clone()),
+        config.registrations,
+    );
+
+    let unregister = commandsimpl::UnregisterHandler::new(
+        sqliteadapters::TransactionProvider::new(conn_adapter.clone()),
+    );
 
-    let commands = app::Commands::new(&register);
-    let queries = app::Queries::new();
+    let commands = app::Commands::new(&register, &unregister);
+    let get_registration = queriesimpl::GetRegistrationHandler::new(
+        sqliteadapters::TransactionProvider::new(conn_adapter.clone()),
+    );
+
+    let queries = app::Queries::new(&get_registration);
     let app = app::Application::new(&commands, &queries);
 
-    let migration_status_repository =
-        sqliteadapters::MigrationStatusRepository::new(conn_adapter).unwrap();
+    let mute_list_updater = config.downloader.enabled.then(|| {
+        mute_lists::MuteListUpdater::new(
+            sqliteadapters::TransactionProvider::new(conn_adapter.clone()),
+            relays::RelayClient::new(config.downloader.relay_timeout),
+        )
+    });
+
+    let notifier = config.downloader.enabled.then(|| {
+        Notifier::new(
+            sqliteadapters::TransactionProvider::new(conn_adapter.clone()),
+            push::LogSender,
+        )
+    });
+
+    let migration_status_repository = sqliteadapters::MigrationStatusRepository::new(conn_adapter)?;
     let runner = migrations::Runner::new(migration_status_repository);
 
-    let server = http::Server::new(&app);
+    let rest_config = rest::Config::new(
+        config.rest.listen_address,
+        config.rest.public_url,
+        &config.limits,
+        &config.rate_limits,
+    )?;
+    let server_config = http::Config::new(
+        config.listen_address,
+        http::Events::new(
+            config.service.registration_kind,
+            config.service.unregistration_kind,
+            config.service.freshness_window,
+        )?,
+        config.service.name,
+        &config.service.keys,
+        http::Auth::new(config.service.public_url, config.service.auth_required),
+        config.limits,
+        config.rate_limits,
+    )?;
+    let server = http::Server::new(&app, server_config);
+
+    let downloader = config.downloader.enabled.then(|| {
+        Downloader::new(
+            transaction_provider,
+            relays::RelayClient::new(config.downloader.relay_timeout),
+        )
+    });
+
+    runner.run(&migrations)?;
+
+    let rest_server = match config.rest.enabled {
+        true => Some(rest::Server::new(&app, rest_config)?),
+        false => None,
+    };
+
+    std::thread::scope(|s| {
+        if let Some(rest_server) = &rest_server {
+            s.spawn(|| rest_server.serve());
+        }
+
+        let (stop_mute_list_updater, stopped) = mpsc::channel();
+        if let Some(mute_list_updater) = &mute_list_updater {
+            s.spawn(move || {
+                mute_list_updater.run(config.downloader.mute_list_refresh_interval, stopped)
+            });
+        }
+
+        let (stop_downloader, stopped) = mpsc::channel();
+        if let Some(mut downloader) = downloader {
+            s.spawn(move || downloader.run(config.downloader.update_interval, stopped));
+        }
 
-    let _downloader = Downloader::new(transaction_provider);
+        let (stop_notifier, stopped) = mpsc::channel();
+        if let Some(notifier) = &notifier {
+            s.spawn(move || notifier.run(config.downloader.notifier_interval, stopped));
+        }
 
-    runner.run(&migrations).unwrap();
-    server.listen_and_serve();
+        let result = server.listen_and_serve();
+        if let Some(rest_server) = &rest_server {
+            rest_server.shut_down();
+        }
+        drop(stop_mute_list_updater);
+        drop(stop_downloader);
+        drop(stop_notifier);
+        result
+    })
 }
 
 //fn new_adapters_factory_fn<T>() -> Box<dyn sqliteadapters::AdaptersFactoryFn<T, AdaptersImpl<T>>>
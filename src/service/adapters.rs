@@ -1 +1,3 @@
+pub mod push;
+pub mod relays;
 pub mod sqlite;
@@ -0,0 +1,44 @@
+use crate::errors::Result;
+use crate::service::app::common;
+use crate::service::domain::notifications;
+use crate::service::domain::quiet_hours;
+
+// Writes notifications to the log instead of sending them. None of the push providers are
+// integrated yet, this sender lets the service decide what to notify about without them. Locales
+// are logged together with the ones which translations would fall back to.
+pub struct LogSender;
+
+impl common::NotificationSender for LogSender {
+    fn send(&self, notification: &notifications::Notification) -> Result<()> {
+        let locales: Vec<String> = notification
+            .locale()
+            .fallback_chain()
+            .iter()
+            .map(|locale| locale.as_ref().to_string())
+            .collect();
+        log::info!(
+            "notifying {} about event {} ({}) on a {} device in {}",
+            notification.pub_key().hex(),
+            notification.event_id(),
+            notification.category().as_str(),
+            notification.push_token().provider().as_str(),
+            locales.join(" > ")
+        );
+        Ok(())
+    }
+
+    fn send_digest(&self, digest: &quiet_hours::Digest) -> Result<()> {
+        let counts: Vec<String> = digest
+            .counts()
+            .iter()
+            .map(|(category, count)| format!("{} {}", count, category.as_str()))
+            .collect();
+        log::info!(
+            "notifying {} about {} held during quiet hours on a {} device",
+            digest.pub_key().hex(),
+            counts.join(", "),
+            digest.push_token().provider().as_str()
+        );
+        Ok(())
+    }
+}
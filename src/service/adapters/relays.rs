@@ -0,0 +1,432 @@
+use crate::errors::Result;
+use crate::service::app::common;
+use crate::service::domain;
+use crate::service::domain::events;
+use crate::service::domain::relay_information;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tungstenite::stream::MaybeTlsStream;
+
+// How long fetching events from a relay can take unless a different timeout is used.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Relay information documents are small, anything larger is most likely not one.
+const MAX_INFORMATION_SIZE: usize = 64 * 1024;
+
+// Connects to relays over websockets. Fetching events sends a REQ and collects the events which
+// the relay sends until it sends EOSE, every such request uses its own connection.
+pub struct RelayClient {
+    timeout: Duration,
+}
+
+impl RelayClient {
+    // The timeout applies to the whole request, including connecting to the relay. Long-lived
+    // connections use it only for connecting.
+    pub fn new(timeout: Duration) -> RelayClient {
+        RelayClient { timeout }
+    }
+
+    fn open(
+        &self,
+        relay: &domain::RelayAddress,
+        deadline: Instant,
+    ) -> Result<tungstenite::WebSocket<MaybeTlsStream<TcpStream>>> {
+        let stream = connect_tcp(&nostr::Url::parse(relay.as_ref())?, deadline)?;
+        let (websocket, _) = tungstenite::client_tls(relay.as_ref(), stream)
+            .map_err(|err| format!("handshake failed: {err}"))?;
+        Ok(websocket)
+    }
+}
+
+fn connect_tcp(url: &nostr::Url, deadline: Instant) -> Result<TcpStream> {
+    let host = url.host_str().ok_or("missing host")?;
+    let port = url.port_or_known_default().ok_or("missing port")?;
+
+    let mut last_err: Box<dyn std::error::Error> = "host has no addresses".into();
+    for address in (host.trim_matches(['[', ']']), port).to_socket_addrs()? {
+        let timeout = remaining(deadline)?;
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(err) => last_err = err.into(),
+        }
+    }
+    Err(last_err)
+}
+
+impl common::RelayClient for RelayClient {
+    fn fetch_events(
+        &self,
+        relay: &domain::RelayAddress,
+        filters: Vec<nostr::Filter>,
+    ) -> Result<Vec<nostr::Event>> {
+        let deadline = Instant::now() + self.timeout;
+        let mut websocket = self
+            .open(relay, deadline)
+            .map_err(|err| format!("error connecting to '{}': {err}", relay.as_ref()))?;
+
+        let subscription_id = nostr::SubscriptionId::generate();
+        websocket.write_message(tungstenite::Message::Text(
+            nostr::ClientMessage::new_req(subscription_id.clone(), filters).as_json(),
+        ))?;
+
+        let mut events = vec![];
+        loop {
+            tcp_stream(websocket.get_ref())?.set_read_timeout(Some(remaining(deadline)?))?;
+
+            let text = match websocket.read_message()? {
+                tungstenite::Message::Text(text) => text,
+                tungstenite::Message::Close(_) => {
+                    return Err("relay closed the connection before sending EOSE".into())
+                }
+                _ => continue,
+            };
+
+            match parse_message(&text)? {
+                Some(common::RelayMessage::Event {
+                    subscription_id: id,
+                    event,
+                }) if id == subscription_id => events.push(*event),
+                Some(common::RelayMessage::EndOfStoredEvents(id)) if id == subscription_id => break,
+                Some(common::RelayMessage::Closed {
+                    subscription_id: id,
+                    message,
+                }) if id == subscription_id => {
+                    return Err(format!("relay closed the subscription: {message}").into())
+                }
+                _ => {}
+            }
+        }
+
+        if let Err(err) = close(&mut websocket, subscription_id) {
+            log::debug!("error closing a connection to '{}': {err}", relay.as_ref());
+        }
+
+        Ok(events)
+    }
+
+    fn connect(&self, relay: &domain::RelayAddress) -> Result<Box<dyn common::RelayConnection>> {
+        let websocket = self
+            .open(relay, Instant::now() + self.timeout)
+            .map_err(|err| format!("error connecting to '{}': {err}", relay.as_ref()))?;
+        Ok(Box::new(RelayConnection { websocket }))
+    }
+
+    // The document is served over HTTP on the address of the relay. HTTP/1.0 is used so that the
+    // response is never chunked and ends when the connection is closed.
+    fn fetch_information(
+        &self,
+        relay: &domain::RelayAddress,
+    ) -> Result<relay_information::RelayInformation> {
+        let deadline = Instant::now() + self.timeout;
+        let url = nostr::Url::parse(relay.as_ref())?;
+        let host = url.host_str().ok_or("missing host")?;
+        let stream = connect_tcp(&url, deadline)
+            .map_err(|err| format!("error connecting to '{}': {err}", relay.as_ref()))?;
+
+        let host_header = match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {host_header}\r\nAccept: application/nostr+json\r\n\r\n",
+            url.path()
+        );
+
+        let response = match url.scheme() {
+            "wss" => {
+                let connection = rustls::ClientConnection::new(
+                    tls_config(),
+                    rustls::ServerName::try_from(host.trim_matches(['[', ']']))?,
+                )?;
+                exchange(rustls::StreamOwned::new(connection, stream), &request)?
+            }
+            _ => exchange(stream, &request)?,
+        };
+
+        let body = parse_response(&response)?;
+        relay_information::RelayInformation::new(body, nostr::Timestamp::now())
+    }
+}
+
+// Sends the request and reads the response until the connection is closed. Servers often close
+// TLS connections without notifying the other side so that is treated as the end of the
+// response.
+fn exchange(mut stream: impl io::Read + io::Write, request: &str) -> Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut response = vec![];
+    let mut buf = [0; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(response),
+            Ok(n) => {
+                response.extend_from_slice(&buf[..n]);
+                if response.len() > MAX_INFORMATION_SIZE {
+                    return Err("relay information document is too large".into());
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(response),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn parse_response(response: &[u8]) -> Result<String> {
+    let response = std::str::from_utf8(response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("invalid http response")?;
+
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or("invalid http response")?;
+    if status != "200" {
+        return Err(format!("relay returned status {status}").into());
+    }
+    Ok(body.to_string())
+}
+
+fn tls_config() -> Arc<rustls::ClientConfig> {
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    Arc::new(
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store)
+            .with_no_client_auth(),
+    )
+}
+
+pub struct RelayConnection {
+    websocket: tungstenite::WebSocket<MaybeTlsStream<TcpStream>>,
+}
+
+impl common::RelayConnection for RelayConnection {
+    fn subscribe(
+        &mut self,
+        subscription_id: &nostr::SubscriptionId,
+        filters: Vec<nostr::Filter>,
+    ) -> Result<()> {
+        let message = nostr::ClientMessage::new_req(subscription_id.clone(), filters).as_json();
+        self.websocket
+            .write_message(tungstenite::Message::Text(message))?;
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, subscription_id: &nostr::SubscriptionId) -> Result<()> {
+        let message = nostr::ClientMessage::close(subscription_id.clone()).as_json();
+        self.websocket
+            .write_message(tungstenite::Message::Text(message))?;
+        Ok(())
+    }
+
+    fn next_message(&mut self, timeout: Duration) -> Result<Option<common::RelayMessage>> {
+        tcp_stream(self.websocket.get_ref())?.set_read_timeout(Some(timeout))?;
+
+        match self.websocket.read_message() {
+            Ok(tungstenite::Message::Text(text)) => parse_message(&text),
+            Ok(tungstenite::Message::Close(_)) => Err("relay closed the connection".into()),
+            Ok(_) => Ok(None),
+            Err(tungstenite::Error::Io(err))
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Drop for RelayConnection {
+    fn drop(&mut self) {
+        if self.websocket.close(None).is_ok() {
+            let _ = self.websocket.write_pending();
+        }
+    }
+}
+
+// Messages which aren't needed are skipped. Events with invalid signatures are skipped as well as a
+// relay could serve anything.
+fn parse_message(text: &str) -> Result<Option<common::RelayMessage>> {
+    let mut value: serde_json::Value = serde_json::from_str(text)?;
+    let message = value.as_array_mut().ok_or("message is not an array")?;
+
+    let subscription_id = |v: Option<&serde_json::Value>| {
+        v.and_then(serde_json::Value::as_str)
+            .map(nostr::SubscriptionId::new)
+            .ok_or("missing subscription id")
+    };
+
+    match message.first().and_then(serde_json::Value::as_str) {
+        Some("EVENT") if message.len() == 3 => {
+            let subscription_id = subscription_id(message.get(1))?;
+            let received = events::ReceivedEvent::new(message.remove(2))?;
+            match received.verify() {
+                Ok(event) => Ok(Some(common::RelayMessage::Event {
+                    subscription_id,
+                    event: Box::new(event.clone()),
+                })),
+                Err(err) => {
+                    log::debug!("skipping an event: {err}");
+                    Ok(None)
+                }
+            }
+        }
+        Some("EOSE") => Ok(Some(common::RelayMessage::EndOfStoredEvents(
+            subscription_id(message.get(1))?,
+        ))),
+        Some("CLOSED") => Ok(Some(common::RelayMessage::Closed {
+            subscription_id: subscription_id(message.get(1))?,
+            message: message
+                .get(2)
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+        })),
+        Some("NOTICE") => {
+            log::debug!("notice: {}", text);
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn close(
+    websocket: &mut tungstenite::WebSocket<MaybeTlsStream<TcpStream>>,
+    subscription_id: nostr::SubscriptionId,
+) -> Result<()> {
+    let message = nostr::ClientMessage::close(subscription_id).as_json();
+    websocket.write_message(tungstenite::Message::Text(message))?;
+    websocket.close(None)?;
+    Ok(())
+}
+
+fn tcp_stream(stream: &MaybeTlsStream<TcpStream>) -> Result<&TcpStream> {
+    match stream {
+        MaybeTlsStream::Plain(stream) => Ok(stream),
+        MaybeTlsStream::Rustls(stream) => Ok(&stream.sock),
+        _ => Err("unsupported stream".into()),
+    }
+}
+
+fn remaining(deadline: Instant) -> Result<Duration> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err("timed out".into());
+    }
+    Ok(remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::service::app::common::RelayClient as _;
+
+    #[test]
+    fn events_are_fetched_until_eose() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let mute_list = fixtures::mute_list_event(&keys, vec![]);
+        let forged = fixtures::tampered_event(fixtures::mute_list_event(&keys, vec![]));
+        let relay = fixtures::FakeRelay::new(vec![mute_list.clone(), forged])?;
+
+        let client = RelayClient::new(Duration::from_secs(5));
+        let events = client.fetch_events(
+            &relay.address(),
+            vec![nostr::Filter::new().kind(nostr::Kind::MuteList)],
+        )?;
+
+        assert_eq!(events, vec![mute_list]);
+        Ok(())
+    }
+
+    #[test]
+    fn connections_receive_messages_of_subscriptions() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let mute_list = fixtures::mute_list_event(&keys, vec![]);
+        let relay = fixtures::FakeRelay::new(vec![mute_list.clone()])?;
+
+        let client = RelayClient::new(Duration::from_secs(5));
+        let mut connection = client.connect(&relay.address())?;
+        let subscription_id = nostr::SubscriptionId::generate();
+        connection.subscribe(
+            &subscription_id,
+            vec![nostr::Filter::new().kind(nostr::Kind::MuteList)],
+        )?;
+
+        let mut messages = vec![];
+        while messages.len() < 2 {
+            if let Some(message) = connection.next_message(Duration::from_secs(5))? {
+                messages.push(message);
+            }
+        }
+
+        assert_eq!(
+            messages,
+            vec![
+                common::RelayMessage::Event {
+                    subscription_id: subscription_id.clone(),
+                    event: Box::new(mute_list),
+                },
+                common::RelayMessage::EndOfStoredEvents(subscription_id),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn relay_information_is_fetched_over_http() -> Result<()> {
+        let document = r#"{"name": "relay", "limitation": {"max_limit": 100}}"#;
+        let relay = fixtures::FakeRelay::new_with_information(vec![], document)?;
+
+        let client = RelayClient::new(Duration::from_secs(5));
+        let information = client.fetch_information(&relay.address())?;
+
+        assert_eq!(information.document(), document);
+        assert_eq!(information.max_limit(), Some(100));
+        assert_eq!(
+            relay.information_requests(),
+            vec![String::from("application/nostr+json")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn missing_relay_information_returns_an_error() -> Result<()> {
+        let relay = fixtures::FakeRelay::new(vec![])?;
+
+        let client = RelayClient::new(Duration::from_secs(5));
+        assert!(client.fetch_information(&relay.address()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unreachable_relays_return_an_error() -> Result<()> {
+        let address = {
+            let relay = fixtures::FakeRelay::new(vec![])?;
+            relay.address()
+        };
+
+        let client = RelayClient::new(Duration::from_secs(5));
+        assert!(client.fetch_events(&address, vec![]).is_err());
+        Ok(())
+    }
+}
@@ -1,9 +1,10 @@
 pub mod commands;
 pub mod common;
+pub mod queries;
 
 pub struct Application<'a> {
     pub commands: &'a Commands<'a>,
-    pub queries: &'a Queries,
+    pub queries: &'a Queries<'a>,
 }
 
 impl<'a> Application<'_> {
@@ -14,18 +15,27 @@ impl<'a> Application<'_> {
 
 pub struct Commands<'a> {
     pub register: &'a (dyn commands::RegisterHandler + Sync),
+    pub unregister: &'a (dyn commands::UnregisterHandler + Sync),
 }
 
-impl Commands<'_> {
-    pub fn new(register: &(dyn commands::RegisterHandler + Sync)) -> Commands {
-        Commands { register }
+impl<'a> Commands<'a> {
+    pub fn new(
+        register: &'a (dyn commands::RegisterHandler + Sync),
+        unregister: &'a (dyn commands::UnregisterHandler + Sync),
+    ) -> Commands<'a> {
+        Commands {
+            register,
+            unregister,
+        }
     }
 }
 
-pub struct Queries {}
+pub struct Queries<'a> {
+    pub get_registration: &'a (dyn queries::GetRegistrationHandler + Sync),
+}
 
-impl Queries {
-    pub fn new() -> Queries {
-        Queries {}
+impl<'a> Queries<'a> {
+    pub fn new(get_registration: &'a (dyn queries::GetRegistrationHandler + Sync)) -> Queries<'a> {
+        Queries { get_registration }
     }
 }
@@ -1,13 +1,53 @@
 pub mod downloader;
 pub mod implementation;
+pub mod mute_lists;
+pub mod notifier;
 
 use crate::errors::Result;
-use crate::service::domain::Registration;
+use crate::service::domain::{EventInfo, PubKey, PushToken, Registration};
+use std::fmt;
 
 pub struct Register {
     pub registration: Registration,
+    // Size of the content which the registration was read from, in bytes.
+    pub content_size: usize,
 }
 
 pub trait RegisterHandler {
     fn handle(&self, cmd: &Register) -> Result<()>;
 }
+
+pub struct Unregister {
+    pub pub_key: PubKey,
+    pub push_token: PushToken,
+    pub event: EventInfo,
+}
+
+pub trait UnregisterHandler {
+    fn handle(&self, cmd: &Unregister) -> Result<()>;
+}
+
+// Returned if a command was created from an event which is older than the event that the stored
+// registration was created from, e.g. because the event was replayed.
+#[derive(Debug)]
+pub struct OutdatedEventError;
+
+impl fmt::Display for OutdatedEventError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a newer registration is already stored")
+    }
+}
+
+impl std::error::Error for OutdatedEventError {}
+
+// Returned if accepting a registration would exceed one of the limits of the service.
+#[derive(Debug)]
+pub struct LimitExceededError(pub String);
+
+impl fmt::Display for LimitExceededError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceededError {}
@@ -1,152 +1,1194 @@
 use crate::errors::Result;
 use crate::service::app::common;
+use crate::service::domain;
+use crate::service::domain::relay_health;
+use crate::service::domain::relay_information;
+use crate::service::domain::subscriptions;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub struct Downloader<T> {
-    _transaction_provider: T,
-    //relay_downloaders: HashMap<domain::RelayAddress, RelayDownloader<T, R1, R2>>,
+// How often the downloader checks if registrations changed unless configured otherwise.
+pub const UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+// How long a relay downloader waits for a message before checking for updates.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long relay information documents are used before they are fetched again.
+pub const INFORMATION_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How far before the newest received event events are requested again after reconnecting so that
+// events which reached the relay late or were created by clocks running behind aren't missed.
+const SINCE_OVERLAP: Duration = Duration::from_secs(5 * 60);
+
+// How far back events are requested after a restart at most. Notifications about older events
+// wouldn't be useful so a longer downtime isn't caught up on.
+const MAX_CATCH_UP: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Keeps one connection open per registered relay and saves events which tag the pub keys
+// registered with that relay. Every relay is handled by its own thread. Registrations are
+// compared with what the threads subscribe to every update so that relays are connected to or
+// disconnected from and pub keys are subscribed to without a restart. Dropping the downloader
+// stops all threads and waits for them to finish.
+pub struct Downloader<T, C> {
+    transaction_provider: Arc<T>,
+    relay_client: Arc<C>,
+    relay_downloaders: HashMap<domain::RelayAddress, RelayDownloader>,
+}
+
+impl<T, C> Downloader<T, C>
+where
+    T: common::TransactionProvider + Send + Sync + 'static,
+    C: common::RelayClient + Send + Sync + 'static,
+{
+    pub fn new(transaction_provider: T, relay_client: C) -> Self {
+        Self {
+            transaction_provider: Arc::new(transaction_provider),
+            relay_client: Arc::new(relay_client),
+            relay_downloaders: HashMap::new(),
+        }
+    }
+
+    // Updates the relay downloaders every interval until a message is received or the sender is
+    // dropped.
+    pub fn run(&mut self, interval: Duration, stop: mpsc::Receiver<()>) {
+        loop {
+            if let Err(err) = self.update() {
+                log::error!("error updating the downloader: {err}");
+            }
+
+            match stop.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                _ => return,
+            }
+        }
+    }
+
+    // Only relay downloaders whose pub keys changed are notified.
+    pub fn update(&mut self) -> Result<()> {
+        let mut registered = self.registered_pub_keys()?;
+
+        let removed: Vec<domain::RelayAddress> = self
+            .relay_downloaders
+            .keys()
+            .filter(|relay| !registered.contains_key(*relay))
+            .cloned()
+            .collect();
+        let mut removed: Vec<RelayDownloader> = removed
+            .iter()
+            .filter_map(|relay| self.relay_downloaders.remove(relay))
+            .collect();
+        stop_all(&mut removed);
+
+        for (relay, pub_keys) in registered.drain() {
+            match self.relay_downloaders.get_mut(&relay) {
+                Some(relay_downloader) => relay_downloader.update(pub_keys),
+                None => {
+                    let relay_downloader = RelayDownloader::new(
+                        relay.clone(),
+                        pub_keys,
+                        self.transaction_provider.clone(),
+                        self.relay_client.clone(),
+                    );
+                    self.relay_downloaders.insert(relay, relay_downloader);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn registered_pub_keys(
+        &self,
+    ) -> Result<HashMap<domain::RelayAddress, HashSet<domain::PubKey>>> {
+        let transaction = self.transaction_provider.start_transaction()?;
+        let adapters = transaction.adapters();
+        let registrations = adapters.registrations.borrow();
+
+        let mut result = HashMap::new();
+        for relay in registrations.get_relays()? {
+            let pub_keys = registrations
+                .get_pub_keys(relay.clone())?
+                .iter()
+                .map(common::PubKeyInfo::pub_key)
+                .collect();
+            result.insert(relay, pub_keys);
+        }
+        Ok(result)
+    }
+}
+
+impl<T, C> Drop for Downloader<T, C> {
+    fn drop(&mut self) {
+        let mut relay_downloaders: Vec<RelayDownloader> =
+            self.relay_downloaders.drain().map(|(_, v)| v).collect();
+        stop_all(&mut relay_downloaders);
+    }
+}
+
+// Threads are signalled first so that they stop at the same time instead of one after another.
+fn stop_all(relay_downloaders: &mut Vec<RelayDownloader>) {
+    for relay_downloader in relay_downloaders.iter_mut() {
+        relay_downloader.updates.take();
+    }
+    relay_downloaders.clear();
+}
+
+struct RelayDownloader {
+    pub_keys: HashSet<domain::PubKey>,
+    updates: Option<mpsc::Sender<HashSet<domain::PubKey>>>,
+    handle: Option<thread::JoinHandle<()>>,
 }
 
-impl<T> Downloader<T> {
-    pub fn new(transaction_provider: T) -> Self {
+impl RelayDownloader {
+    fn new<T, C>(
+        relay: domain::RelayAddress,
+        pub_keys: HashSet<domain::PubKey>,
+        transaction_provider: Arc<T>,
+        relay_client: Arc<C>,
+    ) -> Self
+    where
+        T: common::TransactionProvider + Send + Sync + 'static,
+        C: common::RelayClient + Send + Sync + 'static,
+    {
+        let (updates, received_updates) = mpsc::channel();
+        let handle = {
+            let pub_keys = pub_keys.clone();
+            thread::spawn(move || {
+                run(
+                    &relay,
+                    pub_keys,
+                    transaction_provider.as_ref(),
+                    relay_client.as_ref(),
+                    received_updates,
+                )
+            })
+        };
+
         Self {
-            _transaction_provider: transaction_provider,
+            pub_keys,
+            updates: Some(updates),
+            handle: Some(handle),
+        }
+    }
+
+    fn update(&mut self, pub_keys: HashSet<domain::PubKey>) {
+        if pub_keys == self.pub_keys {
+            return;
+        }
+        if let Some(updates) = &self.updates {
+            // The thread only stops once the sender is dropped.
+            let _ = updates.send(pub_keys.clone());
+        }
+        self.pub_keys = pub_keys;
+    }
+}
+
+impl Drop for RelayDownloader {
+    fn drop(&mut self) {
+        self.updates.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                log::error!("relay downloader panicked");
+            }
+        }
+    }
+}
+
+// Downloads events until the sender of updates is dropped. Failed connections are retried with
+// a backoff which is kept in the relay health so that it also applies after a restart. After
+// reconnecting events are requested since the newest event received over the previous connection
+// so that events which were sent in the meantime aren't missed. That time is stored so that the
+// same applies after a restart.
+fn run<T, C>(
+    relay: &domain::RelayAddress,
+    pub_keys: HashSet<domain::PubKey>,
+    transaction_provider: &T,
+    relay_client: &C,
+    updates: mpsc::Receiver<HashSet<domain::PubKey>>,
+) where
+    T: common::TransactionProvider,
+    C: common::RelayClient,
+{
+    let health = match get_health(relay, transaction_provider) {
+        Ok(health) => health,
+        Err(err) => {
+            log::error!("error getting the health of '{}': {err}", relay.as_ref());
+            None
+        }
+    };
+
+    let now = nostr::Timestamp::now();
+    let since = match get_since(relay, transaction_provider) {
+        Ok(since) => since.map_or(now, |since| since.max(now - MAX_CATCH_UP)),
+        Err(err) => {
+            log::error!("error getting the since of '{}': {err}", relay.as_ref());
+            now
+        }
+    };
+
+    let mut state = RelayState {
+        pub_keys,
+        since: Since::new(since),
+        health: health.unwrap_or_else(|| relay_health::RelayHealth::new(relay.clone())),
+        information: None,
+        information_checked_at: None,
+    };
+
+    loop {
+        let delay = state.health.retry_delay(nostr::Timestamp::now());
+        if !wait(&updates, &mut state, delay) {
+            return;
+        }
+
+        refresh_information(relay, &mut state, transaction_provider, relay_client);
+        // Paying for every relay which users list isn't possible. The relay isn't marked as
+        // failing as nothing is wrong with it.
+        if state
+            .information
+            .as_ref()
+            .is_some_and(relay_information::RelayInformation::payment_required)
+        {
+            log::info!("skipping '{}' as it requires payment", relay.as_ref());
+            if !wait(&updates, &mut state, INFORMATION_MAX_AGE) {
+                return;
+            }
+            continue;
+        }
+
+        match download(
+            relay,
+            &mut state,
+            transaction_provider,
+            relay_client,
+            &updates,
+        ) {
+            Ok(()) => return,
+            Err(err) => {
+                log::warn!("error downloading events from '{}': {err}", relay.as_ref());
+                state
+                    .health
+                    .failed(err.to_string(), nostr::Timestamp::now(), rand::random());
+                save_health(transaction_provider, &state.health);
+            }
+        }
+    }
+}
+
+struct RelayState {
+    pub_keys: HashSet<domain::PubKey>,
+    since: Since,
+    health: relay_health::RelayHealth,
+    information: Option<relay_information::RelayInformation>,
+    information_checked_at: Option<nostr::Timestamp>,
+}
+
+impl RelayState {
+    fn limits(&self) -> subscriptions::SubscriptionLimits {
+        subscriptions::SubscriptionLimits::of_relay(self.information.as_ref())
+    }
+
+    fn max_limit(&self) -> Option<usize> {
+        self.information
+            .as_ref()
+            .and_then(relay_information::RelayInformation::max_limit)
+    }
+}
+
+// The stored document is used while it is fresh. Otherwise a new one is fetched and stored, if
+// that fails the stale one is used. Checks happen at most once per max age so that relays which
+// don't serve the document aren't asked for it before every connection.
+fn refresh_information<T, C>(
+    relay: &domain::RelayAddress,
+    state: &mut RelayState,
+    transaction_provider: &T,
+    relay_client: &C,
+) where
+    T: common::TransactionProvider,
+    C: common::RelayClient,
+{
+    let now = nostr::Timestamp::now();
+    if state
+        .information_checked_at
+        .is_some_and(|checked_at| now < checked_at + INFORMATION_MAX_AGE)
+    {
+        return;
+    }
+    state.information_checked_at = Some(now);
+
+    let stored = match get_information(relay, transaction_provider) {
+        Ok(stored) => stored,
+        Err(err) => {
+            log::error!(
+                "error getting the information of '{}': {err}",
+                relay.as_ref()
+            );
+            None
+        }
+    };
+    if let Some(stored) = stored {
+        let fresh = now < stored.fetched_at() + INFORMATION_MAX_AGE;
+        state.information = Some(stored);
+        if fresh {
+            return;
+        }
+    }
+
+    match relay_client.fetch_information(relay) {
+        Ok(information) => {
+            if let Err(err) = save_information(transaction_provider, relay, &information) {
+                log::error!(
+                    "error saving the information of '{}': {err}",
+                    relay.as_ref()
+                );
+            }
+            state.information = Some(information);
+        }
+        Err(err) => {
+            log::debug!(
+                "error fetching the information of '{}': {err}",
+                relay.as_ref()
+            );
         }
     }
 }
 
-impl<T> Downloader<T>
+// Updates of pub keys are applied while waiting. Returns false if the sender of updates was
+// dropped.
+fn wait(
+    updates: &mpsc::Receiver<HashSet<domain::PubKey>>,
+    state: &mut RelayState,
+    delay: Duration,
+) -> bool {
+    let until = Instant::now() + delay;
+    loop {
+        match updates.recv_timeout(until.saturating_duration_since(Instant::now())) {
+            Ok(pub_keys) => state.pub_keys = pub_keys,
+            Err(mpsc::RecvTimeoutError::Timeout) => return true,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+}
+
+// Returns once the sender of updates is dropped, errors mean that the connection should be
+// opened again.
+fn download<T, C>(
+    relay: &domain::RelayAddress,
+    state: &mut RelayState,
+    transaction_provider: &T,
+    relay_client: &C,
+    updates: &mpsc::Receiver<HashSet<domain::PubKey>>,
+) -> Result<()>
 where
     T: common::TransactionProvider,
+    C: common::RelayClient,
 {
-    fn _run(&mut self) -> Result<()> {
-        let transaction = self._transaction_provider.start_transaction()?;
+    let mut connection = relay_client.connect(relay)?;
+    state.health.connected(nostr::Timestamp::now());
+    save_health(transaction_provider, &state.health);
 
-        let adapters = transaction.adapters();
-        let registrations = adapters.registrations.borrow();
+    let max_limit = state.max_limit();
+    let mut subscriptions = Subscriptions::new(state.limits());
+    let changes = subscriptions.update(&state.pub_keys);
+    state.since.connected(&changes);
+    apply(connection.as_mut(), changes, state.since.get(), max_limit)?;
+
+    let mut saved_since = state.since.get();
+    loop {
+        match updates.try_recv() {
+            Ok(pub_keys) => {
+                state.pub_keys = pub_keys;
+                let changes = subscriptions.update(&state.pub_keys);
+                state.since.subscribed(&changes);
+                apply(connection.as_mut(), changes, state.since.get(), max_limit)?;
+            }
+            Err(mpsc::TryRecvError::Empty) => {}
+            Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+        }
+
+        match connection.next_message(POLL_INTERVAL)? {
+            Some(common::RelayMessage::Event {
+                subscription_id,
+                event,
+            }) if subscriptions.contains(&subscription_id) => {
+                save_event(transaction_provider, &event)?;
+                state.since.received_event(&event, nostr::Timestamp::now());
+            }
+            Some(common::RelayMessage::EndOfStoredEvents(subscription_id))
+                if subscriptions.contains(&subscription_id) =>
+            {
+                state
+                    .since
+                    .received_end_of_stored_events(&subscription_id, nostr::Timestamp::now());
+                if state.health.consecutive_failures() > 0 {
+                    state.health.working();
+                    save_health(transaction_provider, &state.health);
+                }
+            }
+            Some(common::RelayMessage::Closed {
+                subscription_id,
+                message,
+            }) if subscriptions.contains(&subscription_id) => {
+                return Err(format!("relay closed the subscription: {message}").into())
+            }
+            _ => {}
+        }
+
+        if state.since.get() != saved_since {
+            saved_since = state.since.get();
+            save_since(transaction_provider, relay, saved_since);
+        }
+    }
+}
+
+fn apply(
+    connection: &mut dyn common::RelayConnection,
+    changes: Changes,
+    since: nostr::Timestamp,
+    max_limit: Option<usize>,
+) -> Result<()> {
+    if changes.skipped_pub_keys > 0 {
+        log::warn!(
+            "too many pub keys for the relay, {} pub keys weren't subscribed to",
+            changes.skipped_pub_keys
+        );
+    }
+    for subscription_id in changes.unsubscribe {
+        connection.unsubscribe(&subscription_id)?;
+    }
+    for (subscription_id, pub_keys) in changes.subscribe {
+        let filters = pub_keys
+            .iter()
+            .map(|pub_keys| {
+                let filter = nostr::Filter::new()
+                    .pubkeys(pub_keys.iter().map(domain::PubKey::key).collect())
+                    .since(since);
+                match max_limit {
+                    Some(max_limit) => filter.limit(max_limit),
+                    None => filter,
+                }
+            })
+            .collect();
+        connection.subscribe(&subscription_id, filters)?;
+    }
+    Ok(())
+}
+
+fn get_health<T>(
+    relay: &domain::RelayAddress,
+    transaction_provider: &T,
+) -> Result<Option<relay_health::RelayHealth>>
+where
+    T: common::TransactionProvider,
+{
+    let transaction = transaction_provider.start_transaction()?;
+    let health = transaction
+        .adapters()
+        .relay_health
+        .borrow()
+        .get_relay_health(relay)?;
+    Ok(health)
+}
+
+// Errors are only logged as failing to save the health shouldn't interrupt downloading events.
+fn save_health<T>(transaction_provider: &T, health: &relay_health::RelayHealth)
+where
+    T: common::TransactionProvider,
+{
+    let result = transaction_provider
+        .start_transaction()
+        .and_then(|transaction| {
+            transaction
+                .adapters()
+                .relay_health
+                .borrow()
+                .save_relay_health(health)?;
+            transaction.commit()
+        });
+    if let Err(err) = result {
+        log::error!(
+            "error saving the health of '{}': {err}",
+            health.relay().as_ref()
+        );
+    }
+}
+
+fn get_since<T>(
+    relay: &domain::RelayAddress,
+    transaction_provider: &T,
+) -> Result<Option<nostr::Timestamp>>
+where
+    T: common::TransactionProvider,
+{
+    let transaction = transaction_provider.start_transaction()?;
+    let since = transaction
+        .adapters()
+        .relay_health
+        .borrow()
+        .get_since(relay)?;
+    Ok(since)
+}
+
+// Errors are only logged as events will be requested since an older time at worst.
+fn save_since<T>(transaction_provider: &T, relay: &domain::RelayAddress, since: nostr::Timestamp)
+where
+    T: common::TransactionProvider,
+{
+    let result = transaction_provider
+        .start_transaction()
+        .and_then(|transaction| {
+            transaction
+                .adapters()
+                .relay_health
+                .borrow()
+                .save_since(relay, since)?;
+            transaction.commit()
+        });
+    if let Err(err) = result {
+        log::error!("error saving the since of '{}': {err}", relay.as_ref());
+    }
+}
+
+fn get_information<T>(
+    relay: &domain::RelayAddress,
+    transaction_provider: &T,
+) -> Result<Option<relay_information::RelayInformation>>
+where
+    T: common::TransactionProvider,
+{
+    let transaction = transaction_provider.start_transaction()?;
+    let information = transaction
+        .adapters()
+        .relay_information
+        .borrow()
+        .get_relay_information(relay)?;
+    Ok(information)
+}
+
+fn save_information<T>(
+    transaction_provider: &T,
+    relay: &domain::RelayAddress,
+    information: &relay_information::RelayInformation,
+) -> Result<()>
+where
+    T: common::TransactionProvider,
+{
+    let transaction = transaction_provider.start_transaction()?;
+    transaction
+        .adapters()
+        .relay_information
+        .borrow()
+        .save_relay_information(relay, information)?;
+    transaction.commit()
+}
 
-        let initial_relays = registrations.get_relays()?;
-
-        //let initial_relays: Vec<domain::RelayAddress> = self
-        //    .transaction_provider
-        //    .transact(|adapters| adapters.registrations.get_relays())
-        //    .unwrap(); // todo don't unwrap
-
-        for _initial_relay in initial_relays {
-            //let v = RelayDownloader::new(initial_relay.clone(), self.transaction_provider);
-            //self.relay_downloaders.insert(initial_relay.clone(), v);
-        }
-
-        // todo update the list in a loop
-
-        Err("not implemented".into())
-    }
-}
-
-//pub struct RelayDownloader<T, R1, R2> {
-//    transaction_provider: T,
-//    r1: PhantomData<R1>,
-//    r2: PhantomData<R2>,
-//
-//    relay: domain::RelayAddress,
-//
-//    //run_join: thread::JoinHandle<()>,
-//    tx: mpsc::Sender<()>,
-//}
-//
-//impl<T, R1, R2> RelayDownloader<T, R1, R2> {
-//    fn new<R>(relay: domain::RelayAddress, transaction_provider: T) -> RelayDownloader<T, R1, R2>
-//    where
-//        R1: common::RegistrationRepository,
-//        R2: common::EventRepository,
-//        T: common::TransactionProvider,
-//    {
-//        let (tx, rx) = mpsc::channel::<()>();
-//
-//        //let h = thread::spawn(|| {
-//        //    Self::run(relay, transaction_provider, rx);
-//        //});
-//
-//        let v = RelayDownloader {
-//            transaction_provider,
-//            r1: PhantomData {},
-//            r2: PhantomData {},
-//            relay,
-//            //run_join: h,
-//            tx,
-//        };
-//
-//        return v;
-//    }
-//
-//    fn run(relay: domain::RelayAddress, transaction_provider: T, rx: mpsc::Receiver<()>) {
-//        let initial_pub_keys: Vec<domain::RelayAddress> = Vec::new();
-//
-//        //let c = |adapters: Adapters<R1, R2>| -> Result<()> {
-//        //    let relays = adapters.registrations.get_relays()?;
-//
-//        //    for relay in relays {
-//        //        initial_relays.push(relay);
-//        //    }
-//
-//        //    return Ok(());
-//        //};
-//
-//        //for initial_relay in initial_relays {
-//        //}
-//
-//        //self.transaction_provider.transact(&c);
-//    }
-//}
-
-//impl<T, R1, R2> Drop for RelayDownloader<T, R1, R2>
-//where
-//    T: common::TransactionProvider<common::Adapters<R1, R2>, R>,
-//    R1: common::RegistrationRepository,
-//    R2: common::EventRepository,
-//{
-//    fn drop(&mut self) {
-//        self.tx.send(());
-//        self.run_join.join();
-//    }
-//}
+fn save_event<T>(transaction_provider: &T, event: &nostr::Event) -> Result<()>
+where
+    T: common::TransactionProvider,
+{
+    let transaction = transaction_provider.start_transaction()?;
+    if transaction.adapters().events.borrow().save_event(event)? {
+        log::debug!("saved event {}", event.id);
+    }
+    transaction.commit()
+}
+
+// Time since which events are requested when subscribing. Relays send stored events newest first
+// so the time only moves forward once every subscription received all stored events, from then
+// on it follows the newest received event. Events created in the future don't move it past the
+// current time.
+struct Since {
+    since: nostr::Timestamp,
+    receiving_stored_events: BTreeSet<nostr::SubscriptionId>,
+}
+
+impl Since {
+    fn new(since: nostr::Timestamp) -> Self {
+        Self {
+            since,
+            receiving_stored_events: BTreeSet::new(),
+        }
+    }
+
+    fn get(&self) -> nostr::Timestamp {
+        self.since
+    }
+
+    // Subscriptions of previous connections are forgotten.
+    fn connected(&mut self, changes: &Changes) {
+        self.receiving_stored_events.clear();
+        self.subscribed(changes);
+    }
+
+    fn subscribed(&mut self, changes: &Changes) {
+        for subscription_id in &changes.unsubscribe {
+            self.receiving_stored_events.remove(subscription_id);
+        }
+        for (subscription_id, _) in &changes.subscribe {
+            self.receiving_stored_events.insert(subscription_id.clone());
+        }
+    }
+
+    fn received_event(&mut self, event: &nostr::Event, now: nostr::Timestamp) {
+        if self.receiving_stored_events.is_empty() {
+            self.advance(event.created_at.min(now));
+        }
+    }
+
+    fn received_end_of_stored_events(
+        &mut self,
+        subscription_id: &nostr::SubscriptionId,
+        now: nostr::Timestamp,
+    ) {
+        if self.receiving_stored_events.remove(subscription_id)
+            && self.receiving_stored_events.is_empty()
+        {
+            self.advance(now);
+        }
+    }
+
+    fn advance(&mut self, received_at: nostr::Timestamp) {
+        self.since = self.since.max(received_at - SINCE_OVERLAP);
+    }
+}
+
+// Pub keys which subscriptions on a single connection are filtering by. Pub keys are split into
+// chunks so that subscriptions stay within the limits of the relay and only chunks which changed
+// are sent again.
+struct Subscriptions {
+    limits: subscriptions::SubscriptionLimits,
+    subscriptions: BTreeMap<nostr::SubscriptionId, Vec<Vec<domain::PubKey>>>,
+}
+
+// Subscriptions to send to the relay. Sending a REQ with the id of an existing subscription
+// replaces it. Chunks which didn't fit within the limit of subscriptions are skipped.
+#[derive(Debug, Default)]
+struct Changes {
+    unsubscribe: Vec<nostr::SubscriptionId>,
+    subscribe: Vec<(nostr::SubscriptionId, Vec<Vec<domain::PubKey>>)>,
+    skipped_pub_keys: usize,
+}
+
+impl Subscriptions {
+    fn new(limits: subscriptions::SubscriptionLimits) -> Self {
+        Self {
+            limits,
+            subscriptions: BTreeMap::new(),
+        }
+    }
+
+    fn contains(&self, subscription_id: &nostr::SubscriptionId) -> bool {
+        self.subscriptions.contains_key(subscription_id)
+    }
+
+    fn update(&mut self, pub_keys: &HashSet<domain::PubKey>) -> Changes {
+        let mut changes = Changes::default();
+
+        let mut chunks = subscriptions::chunk(pub_keys, &self.limits);
+        if chunks.len() > self.limits.max_subscriptions() {
+            changes.skipped_pub_keys = chunks
+                .drain(self.limits.max_subscriptions()..)
+                .flat_map(|chunk| chunk.filters().to_vec())
+                .map(|filter| filter.len())
+                .sum();
+        }
+
+        let subscriptions: BTreeMap<nostr::SubscriptionId, Vec<Vec<domain::PubKey>>> = chunks
+            .into_iter()
+            .map(|chunk| {
+                (
+                    nostr::SubscriptionId::new(chunk.id()),
+                    chunk.filters().to_vec(),
+                )
+            })
+            .collect();
+
+        for subscription_id in self.subscriptions.keys() {
+            if !subscriptions.contains_key(subscription_id) {
+                changes.unsubscribe.push(subscription_id.clone());
+            }
+        }
+        for (subscription_id, filters) in &subscriptions {
+            if self.subscriptions.get(subscription_id) != Some(filters) {
+                changes
+                    .subscribe
+                    .push((subscription_id.clone(), filters.clone()));
+            }
+        }
+
+        self.subscriptions = subscriptions;
+        changes
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::service::app::common;
+    use crate::fixtures;
+    use crate::service::adapters::{relays, sqlite};
+    use crate::service::app::common::TransactionProvider as _;
+    use crate::service::domain::notifications;
+
+    #[test]
+    fn events_tagging_registered_pub_keys_are_saved() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let tagged = mention(&keys)?;
+        let other = mention(&fixtures::some_keys())?;
+        let relay = fixtures::FakeRelay::new(vec![tagged.clone(), other.clone()])?;
+
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &registration(&keys, vec![relay.address()])?)?;
+
+        let mut downloader = Downloader::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        downloader.update()?;
+
+        wait_until(|| Ok(stored_event(&conn, &tagged)?.is_some()))?;
+        drop(downloader);
+
+        assert_eq!(stored_event(&conn, &other)?, None);
+        assert_eq!(relay.requests().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn dropping_the_downloader_stops_it() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let relay = fixtures::FakeRelay::new(vec![])?;
+
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &registration(&keys, vec![relay.address()])?)?;
+
+        let mut downloader = Downloader::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        downloader.update()?;
+
+        let start = Instant::now();
+        drop(downloader);
+        assert!(start.elapsed() < 2 * POLL_INTERVAL);
+        Ok(())
+    }
+
+    #[test]
+    fn registration_changes_are_picked_up_by_updates() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let new_keys = fixtures::some_keys();
+        let relay = fixtures::FakeRelay::new(vec![])?;
+        let new_relay = fixtures::FakeRelay::new(vec![mention(&new_keys)?])?;
+
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &registration(&keys, vec![relay.address()])?)?;
+
+        let mut downloader = Downloader::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        downloader.update()?;
+        wait_until(|| Ok(relay.requests().len() == 1))?;
+        // Makes sure that resubscribing at the current time would be noticed.
+        thread::sleep(Duration::from_millis(1100));
+
+        save(
+            &conn,
+            &registration(&new_keys, vec![relay.address(), new_relay.address()])?,
+        )?;
+        downloader.update()?;
+        downloader.update()?;
+        wait_until(|| Ok(relay.requests().len() == 2 && new_relay.requests().len() == 1))?;
+        drop(downloader);
+
+        let requests = relay.requests();
+        assert_eq!(requests[0][0].pubkeys, Some(vec![keys.public_key()]));
+        let mut pub_keys = requests[1][0].pubkeys.clone().unwrap_or_default();
+        pub_keys.sort();
+        let mut expected = vec![keys.public_key(), new_keys.public_key()];
+        expected.sort();
+        assert_eq!(pub_keys, expected);
+        assert_eq!(requests[1][0].since, requests[0][0].since);
+        assert_eq!(
+            new_relay.requests()[0][0].pubkeys,
+            Some(vec![new_keys.public_key()])
+        );
+        Ok(())
+    }
 
     #[test]
-    fn it_works() -> Result<()> {
-        let transaction_provider = TransactionProviderMock::new();
-        let mut downloader = Downloader::new(transaction_provider);
-        match downloader._run() {
-            Ok(_) => return Err("should have failed".into()),
-            Err(_) => return Ok(()),
+    fn since_is_kept_across_restarts() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let relay = fixtures::FakeRelay::new(vec![])?;
+        let stale_relay = fixtures::FakeRelay::new(vec![])?;
+
+        let conn = fixtures::new_sqlite()?;
+        save(
+            &conn,
+            &registration(&keys, vec![relay.address(), stale_relay.address()])?,
+        )?;
+        let now = nostr::Timestamp::now();
+        let since = now - Duration::from_secs(60 * 60);
+        save_since(
+            &sqlite::TransactionProvider::new(conn.clone()),
+            &relay.address(),
+            since,
+        );
+        save_since(
+            &sqlite::TransactionProvider::new(conn.clone()),
+            &stale_relay.address(),
+            now - 2 * MAX_CATCH_UP,
+        );
+
+        let mut downloader = Downloader::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        downloader.update()?;
+        wait_until(|| {
+            Ok(stored_since(&conn, &relay.address())?.is_some_and(|stored| stored > since))
+        })?;
+        wait_until(|| Ok(!stale_relay.requests().is_empty()))?;
+        drop(downloader);
+
+        assert_eq!(relay.requests()[0][0].since, Some(since));
+        assert!(stale_relay.requests()[0][0]
+            .since
+            .is_some_and(|since| since >= now - MAX_CATCH_UP));
+        Ok(())
+    }
+
+    #[test]
+    fn relay_health_is_saved() -> Result<()> {
+        let relay = fixtures::FakeRelay::new(vec![])?;
+        let unreachable = {
+            let relay = fixtures::FakeRelay::new(vec![])?;
+            relay.address()
         };
 
-        //match APNSToken::new(String::from("")) {
-        //    Ok(_) => panic!("constructor should have returned an error"),
-        //    Err(error) => assert_eq!(error.to_string(), String::from("empty token")),
-        //}
+        let conn = fixtures::new_sqlite()?;
+        save(
+            &conn,
+            &registration(
+                &fixtures::some_keys(),
+                vec![relay.address(), unreachable.clone()],
+            )?,
+        )?;
+
+        let mut downloader = Downloader::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        downloader.update()?;
+
+        wait_until(|| {
+            Ok(stored_health(&conn, &relay.address())?
+                .is_some_and(|health| health.state() == relay_health::State::Connected))
+        })?;
+        wait_until(|| {
+            Ok(stored_health(&conn, &unreachable)?
+                .is_some_and(|health| health.state() == relay_health::State::BackingOff))
+        })?;
+        drop(downloader);
+
+        let health = stored_health(&conn, &unreachable)?.ok_or("missing health")?;
+        assert_eq!(health.consecutive_failures(), 1);
+        assert!(health.last_error().is_some());
+        assert!(health.retry_at().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn failures_are_forgotten_once_relays_send_stored_events() -> Result<()> {
+        let relay = fixtures::FakeRelay::new(vec![])?;
+        let conn = fixtures::new_sqlite()?;
+        save(
+            &conn,
+            &registration(&fixtures::some_keys(), vec![relay.address()])?,
+        )?;
+        let now = nostr::Timestamp::now();
+        save_health(
+            &sqlite::TransactionProvider::new(conn.clone()),
+            &relay_health::RelayHealth::restore(
+                relay.address(),
+                relay_health::State::BackingOff,
+                Some(String::from("connection reset")),
+                3,
+                Some(now),
+                Some(now),
+                Some(now),
+            ),
+        );
+
+        let mut downloader = Downloader::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        downloader.update()?;
+
+        wait_until(|| {
+            Ok(stored_health(&conn, &relay.address())?
+                .is_some_and(|health| health.consecutive_failures() == 0))
+        })?;
+        drop(downloader);
+
+        let health = stored_health(&conn, &relay.address())?.ok_or("missing health")?;
+        assert_eq!(health.state(), relay_health::State::Connected);
+        assert_eq!(health.failing_since(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn subscriptions_follow_relay_information() -> Result<()> {
+        let relay = fixtures::FakeRelay::new_with_information(
+            vec![],
+            r#"{"limitation": {"max_message_length": 800, "max_filters": 1, "max_subscriptions": 2, "max_limit": 50}}"#,
+        )?;
+
+        let conn = fixtures::new_sqlite()?;
+        for _ in 0..30 {
+            save(
+                &conn,
+                &registration(&fixtures::some_keys(), vec![relay.address()])?,
+            )?;
+        }
+
+        let mut downloader = Downloader::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        downloader.update()?;
+        wait_until(|| Ok(relay.requests().len() == 2))?;
+        drop(downloader);
+
+        // Messages of at most 800 bytes fit 10 pub keys in a single filter.
+        for filters in relay.requests() {
+            assert_eq!(filters.len(), 1);
+            assert!(filters[0].pubkeys.as_ref().is_some_and(|v| v.len() <= 10));
+            assert_eq!(filters[0].limit, Some(50));
+        }
+        assert_eq!(
+            relay.information_requests(),
+            vec![String::from("application/nostr+json")]
+        );
+
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        let information = transaction
+            .adapters()
+            .relay_information
+            .borrow()
+            .get_relay_information(&relay.address())?;
+        assert_eq!(information.and_then(|v| v.max_limit()), Some(50));
+        Ok(())
+    }
+
+    #[test]
+    fn relays_which_require_payment_are_skipped() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let relay = fixtures::FakeRelay::new_with_information(
+            vec![mention(&keys)?],
+            r#"{"limitation": {"payment_required": true}}"#,
+        )?;
+
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &registration(&keys, vec![relay.address()])?)?;
+
+        let mut downloader = Downloader::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        downloader.update()?;
+        wait_until(|| Ok(!relay.information_requests().is_empty()))?;
+        thread::sleep(2 * POLL_INTERVAL);
+        drop(downloader);
+
+        assert!(relay.requests().is_empty());
+        assert_eq!(stored_health(&conn, &relay.address())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn only_changed_chunks_are_resubscribed() -> Result<()> {
+        let limits = subscriptions::SubscriptionLimits::new(2, 2, 100)?;
+        let mut pub_keys: HashSet<domain::PubKey> =
+            (0..50).map(|_| fixtures::some_pub_key()).collect();
+        let mut subscriptions = Subscriptions::new(limits.clone());
+
+        let changes = subscriptions.update(&pub_keys);
+        assert!(changes.unsubscribe.is_empty());
+        assert_eq!(
+            changes.subscribe.len(),
+            subscriptions::chunk(&pub_keys, &limits).len()
+        );
+
+        let changes = subscriptions.update(&pub_keys);
+        assert!(changes.unsubscribe.is_empty());
+        assert!(changes.subscribe.is_empty());
+
+        let added = fixtures::some_pub_key();
+        pub_keys.insert(added.clone());
+        let changes = subscriptions.update(&pub_keys);
+        assert!(changes.unsubscribe.len() <= 1);
+        assert!(!changes.subscribe.is_empty() && changes.subscribe.len() <= 2);
+        assert!(changes
+            .subscribe
+            .iter()
+            .any(|(_, filters)| filters.iter().flatten().any(|v| v == &added)));
+
+        let changes = subscriptions.update(&HashSet::new());
+        assert_eq!(
+            changes.unsubscribe.len(),
+            subscriptions_count(&pub_keys, &limits)
+        );
+        assert!(changes.subscribe.is_empty());
+        Ok(())
     }
 
-    //fn new_sqlite() -> Result<SqliteConnectionAdapter> {
-    //    //let conn = SqliteConnectionAdapter::new(sqlite::open(":memory:")?);
-    //    //RegistrationRepositoryMigration0001::new(conn.clone()).run()?;
-    //    Ok(conn)
-    //}
+    #[test]
+    fn since_only_moves_once_all_stored_events_were_received() -> Result<()> {
+        let first = nostr::SubscriptionId::new("first");
+        let second = nostr::SubscriptionId::new("second");
+        let start = nostr::Timestamp::from(10_000);
+        let mut since = Since::new(start);
+
+        since.connected(&subscribed(&[&first, &second]));
+        // Stored events are sent newest first so older ones may still be missing.
+        since.received_event(&event_created_at(start + 600_i64)?, start + 700_i64);
+        since.received_end_of_stored_events(&first, start + 700_i64);
+        assert_eq!(since.get(), start);
+
+        since.received_end_of_stored_events(&second, start + 800_i64);
+        assert_eq!(since.get(), start + 800_i64 - SINCE_OVERLAP);
 
-    struct TransactionProviderMock {}
+        since.received_event(&event_created_at(start + 2000_i64)?, start + 2000_i64);
+        assert_eq!(since.get(), start + 2000_i64 - SINCE_OVERLAP);
+
+        // Events created in the future or long ago don't move it past now or back.
+        since.received_event(&event_created_at(start + 9000_i64)?, start + 3000_i64);
+        assert_eq!(since.get(), start + 3000_i64 - SINCE_OVERLAP);
+        since.received_event(&event_created_at(start)?, start + 3100_i64);
+        assert_eq!(since.get(), start + 3000_i64 - SINCE_OVERLAP);
+
+        // Resubscribing waits for the stored events of the new subscription.
+        since.subscribed(&subscribed(&[&first]));
+        since.received_event(&event_created_at(start + 4000_i64)?, start + 4000_i64);
+        assert_eq!(since.get(), start + 3000_i64 - SINCE_OVERLAP);
+
+        // Reconnecting forgets subscriptions of the previous connection.
+        since.connected(&subscribed(&[&second]));
+        since.received_end_of_stored_events(&second, start + 5000_i64);
+        assert_eq!(since.get(), start + 5000_i64 - SINCE_OVERLAP);
+        Ok(())
+    }
+
+    #[test]
+    fn chunks_above_the_limit_of_subscriptions_are_skipped() -> Result<()> {
+        let limits = subscriptions::SubscriptionLimits::new(1, 1, 3)?;
+        let pub_keys: HashSet<domain::PubKey> = (0..10).map(|_| fixtures::some_pub_key()).collect();
+        let mut subscriptions = Subscriptions::new(limits);
+
+        let changes = subscriptions.update(&pub_keys);
+        assert_eq!(changes.subscribe.len(), 3);
+        assert_eq!(changes.skipped_pub_keys, 7);
+        Ok(())
+    }
 
-    impl TransactionProviderMock {
-        pub fn new() -> Self {
-            TransactionProviderMock {}
+    fn subscribed(subscription_ids: &[&nostr::SubscriptionId]) -> Changes {
+        Changes {
+            subscribe: subscription_ids
+                .iter()
+                .map(|subscription_id| ((*subscription_id).clone(), vec![]))
+                .collect(),
+            ..Changes::default()
         }
     }
 
-    impl common::TransactionProvider for TransactionProviderMock {
-        fn start_transaction(&self) -> Result<Box<dyn common::Transaction>> {
-            Err("not implemented".into())
+    fn event_created_at(created_at: nostr::Timestamp) -> Result<nostr::Event> {
+        let keys = fixtures::some_keys();
+        let event = nostr::EventBuilder::new_text_note("hello", &[]).to_event(&keys)?;
+        Ok(fixtures::with_created_at(&keys, event, created_at))
+    }
+
+    fn subscriptions_count(
+        pub_keys: &HashSet<domain::PubKey>,
+        limits: &subscriptions::SubscriptionLimits,
+    ) -> usize {
+        subscriptions::chunk(pub_keys, limits).len()
+    }
+
+    fn wait_until(f: impl Fn() -> Result<bool>) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f()? {
+            if Instant::now() > deadline {
+                return Err("timed out".into());
+            }
+            thread::sleep(Duration::from_millis(10));
         }
+        Ok(())
+    }
+
+    fn mention(keys: &nostr::Keys) -> Result<nostr::Event> {
+        let event = nostr::EventBuilder::new_text_note(
+            "hello",
+            &[nostr::Tag::PubKey(keys.public_key(), None)],
+        )
+        .to_event(&fixtures::some_keys())?;
+        Ok(event)
+    }
+
+    fn registration(
+        keys: &nostr::Keys,
+        relays: Vec<domain::RelayAddress>,
+    ) -> Result<domain::Registration> {
+        domain::Registration::new(
+            domain::PubKey::new(keys.public_key()),
+            fixtures::some_push_token(),
+            relays,
+            fixtures::some_locale(),
+            notifications::Preferences::default(),
+            None,
+            fixtures::some_event_info(),
+        )
+    }
+
+    fn save(
+        conn: &sqlite::SqliteConnectionAdapter,
+        registration: &domain::Registration,
+    ) -> Result<()> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        transaction
+            .adapters()
+            .registrations
+            .borrow()
+            .save(registration)?;
+        transaction.commit()
+    }
+
+    fn stored_event(
+        conn: &sqlite::SqliteConnectionAdapter,
+        event: &nostr::Event,
+    ) -> Result<Option<nostr::Event>> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        let events = transaction
+            .adapters()
+            .events
+            .borrow()
+            .get_unprocessed_events(100)?;
+        Ok(events.into_iter().find(|stored| stored.id == event.id))
+    }
+
+    fn stored_since(
+        conn: &sqlite::SqliteConnectionAdapter,
+        relay: &domain::RelayAddress,
+    ) -> Result<Option<nostr::Timestamp>> {
+        get_since(relay, &sqlite::TransactionProvider::new(conn.clone()))
+    }
+
+    fn stored_health(
+        conn: &sqlite::SqliteConnectionAdapter,
+        relay: &domain::RelayAddress,
+    ) -> Result<Option<relay_health::RelayHealth>> {
+        get_health(relay, &sqlite::TransactionProvider::new(conn.clone()))
     }
 }
@@ -1,17 +1,430 @@
 use crate::app::commands;
 use crate::errors::Result;
+use crate::service::app::common;
+use crate::service::domain;
+use crate::service::domain::limits;
+use std::collections::HashSet;
 
-pub struct RegisterHandler {}
+pub struct RegisterHandler<T> {
+    transaction_provider: T,
+    limits: limits::RegistrationLimits,
+}
+
+impl<T> RegisterHandler<T> {
+    pub fn new(transaction_provider: T, limits: limits::RegistrationLimits) -> RegisterHandler<T> {
+        RegisterHandler {
+            transaction_provider,
+            limits,
+        }
+    }
 
-impl RegisterHandler {
-    pub fn new() -> RegisterHandler {
-        RegisterHandler {}
+    fn check_size(&self, cmd: &commands::Register) -> Result<()> {
+        if cmd.content_size > self.limits.max_content_size() {
+            return Err(commands::LimitExceededError(format!(
+                "content is too large: {} bytes, at most {} are allowed",
+                cmd.content_size,
+                self.limits.max_content_size()
+            ))
+            .into());
+        }
+
+        let relays = cmd.registration.relays().len();
+        if relays > self.limits.max_relays_per_registration() {
+            return Err(commands::LimitExceededError(format!(
+                "too many relays: {relays}, at most {} are allowed",
+                self.limits.max_relays_per_registration()
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+
+    // Stored registrations can always be updated and relays which are already known don't count
+    // towards the limit so that existing users aren't locked out once the service is full.
+    fn check_capacity(
+        &self,
+        registrations: &dyn common::RegistrationRepository,
+        registration: &domain::Registration,
+        is_new: bool,
+    ) -> Result<()> {
+        if is_new && registrations.count_registrations()? >= self.limits.max_registrations() {
+            return Err(commands::LimitExceededError(
+                "the service doesn't accept new registrations".into(),
+            )
+            .into());
+        }
+
+        let known_relays: HashSet<domain::RelayAddress> =
+            registrations.get_relays()?.into_iter().collect();
+        let new_relays = registration
+            .relays()
+            .into_iter()
+            .filter(|relay| !known_relays.contains(relay))
+            .count();
+        if new_relays > 0 && known_relays.len() + new_relays > self.limits.max_relays() {
+            return Err(commands::LimitExceededError(
+                "the service doesn't accept new relays".into(),
+            )
+            .into());
+        }
+
+        Ok(())
     }
 }
 
-impl commands::RegisterHandler for RegisterHandler {
+impl<T> commands::RegisterHandler for RegisterHandler<T>
+where
+    T: common::TransactionProvider,
+{
     fn handle(&self, cmd: &commands::Register) -> Result<()> {
-        println!("apns_token {}", cmd.registration.apns_token().as_ref());
+        self.check_size(cmd)?;
+
+        let transaction = self.transaction_provider.start_transaction()?;
+
+        let adapters = transaction.adapters();
+        let registrations = adapters.registrations.borrow();
+
+        let event = cmd.registration.event();
+        let stored_event =
+            registrations.get_event(&cmd.registration.pub_key(), &cmd.registration.push_token())?;
+        match &stored_event {
+            Some(stored_event) if *stored_event == event => return Ok(()),
+            Some(stored_event) if !event.supersedes(stored_event) => {
+                return Err(commands::OutdatedEventError.into())
+            }
+            _ => {}
+        }
+
+        // Unregistrations are kept so that registrations created before them can't be replayed to
+        // bring the device back.
+        if let Some(unregistration) = registrations
+            .get_unregistration_event(&cmd.registration.pub_key(), &cmd.registration.push_token())?
+        {
+            if !event.supersedes(&unregistration) {
+                return Err(commands::OutdatedEventError.into());
+            }
+        }
+
+        self.check_capacity(
+            registrations.as_ref(),
+            &cmd.registration,
+            stored_event.is_none(),
+        )?;
+
+        registrations.save(&cmd.registration)?;
+
+        transaction.commit()
+    }
+}
+
+pub struct UnregisterHandler<T> {
+    transaction_provider: T,
+}
+
+impl<T> UnregisterHandler<T> {
+    pub fn new(transaction_provider: T) -> UnregisterHandler<T> {
+        UnregisterHandler {
+            transaction_provider,
+        }
+    }
+}
+
+impl<T> commands::UnregisterHandler for UnregisterHandler<T>
+where
+    T: common::TransactionProvider,
+{
+    fn handle(&self, cmd: &commands::Unregister) -> Result<()> {
+        let transaction = self.transaction_provider.start_transaction()?;
+
+        let adapters = transaction.adapters();
+        let registrations = adapters.registrations.borrow();
+
+        let stored_events = [
+            registrations.get_event(&cmd.pub_key, &cmd.push_token)?,
+            registrations.get_unregistration_event(&cmd.pub_key, &cmd.push_token)?,
+        ];
+        if stored_events
+            .iter()
+            .flatten()
+            .any(|stored_event| stored_event.supersedes(&cmd.event))
+        {
+            return Err(commands::OutdatedEventError.into());
+        }
+
+        registrations.delete(&cmd.pub_key, &cmd.push_token)?;
+        registrations.save_unregistration_event(&cmd.pub_key, &cmd.push_token, &cmd.event)?;
+
+        transaction.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::service::adapters::sqlite;
+    use crate::service::app::commands::{RegisterHandler as _, UnregisterHandler as _};
+    use crate::service::app::common::TransactionProvider as _;
+
+    #[test]
+    fn newer_registrations_replace_older_registrations() -> Result<()> {
+        let conn = fixtures::new_sqlite()?;
+        let handler = RegisterHandler::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            limits::RegistrationLimits::default(),
+        );
+
+        handler.handle(&register(event_info(1, 100)))?;
+        handler.handle(&register(event_info(2, 101)))?;
+
+        assert_eq!(stored_event(&conn)?, Some(event_info(2, 101)));
+        Ok(())
+    }
+
+    #[test]
+    fn older_registrations_are_refused() -> Result<()> {
+        let conn = fixtures::new_sqlite()?;
+        let handler = RegisterHandler::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            limits::RegistrationLimits::default(),
+        );
+
+        handler.handle(&register(event_info(2, 101)))?;
+        match handler.handle(&register(event_info(1, 100))) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => assert!(err.is::<commands::OutdatedEventError>()),
+        }
+
+        assert_eq!(stored_event(&conn)?, Some(event_info(2, 101)));
         Ok(())
     }
+
+    #[test]
+    fn replayed_registrations_are_ignored() -> Result<()> {
+        let conn = fixtures::new_sqlite()?;
+        let handler = RegisterHandler::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            limits::RegistrationLimits::default(),
+        );
+
+        handler.handle(&register(event_info(1, 100)))?;
+        handler.handle(&register(event_info(1, 100)))?;
+
+        assert_eq!(stored_event(&conn)?, Some(event_info(1, 100)));
+        Ok(())
+    }
+
+    #[test]
+    fn unregistrations_older_than_the_registration_are_refused() -> Result<()> {
+        let conn = fixtures::new_sqlite()?;
+        let register_handler = RegisterHandler::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            limits::RegistrationLimits::default(),
+        );
+        let unregister_handler =
+            UnregisterHandler::new(sqlite::TransactionProvider::new(conn.clone()));
+
+        register_handler.handle(&register(event_info(2, 101)))?;
+        match unregister_handler.handle(&unregister(event_info(1, 100))) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => assert!(err.is::<commands::OutdatedEventError>()),
+        }
+        assert!(stored_event(&conn)?.is_some());
+
+        unregister_handler.handle(&unregister(event_info(3, 102)))?;
+        assert!(stored_event(&conn)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn registrations_older_than_the_unregistration_are_refused() -> Result<()> {
+        let conn = fixtures::new_sqlite()?;
+        let register_handler = RegisterHandler::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            limits::RegistrationLimits::default(),
+        );
+        let unregister_handler =
+            UnregisterHandler::new(sqlite::TransactionProvider::new(conn.clone()));
+
+        register_handler.handle(&register(event_info(1, 100)))?;
+        unregister_handler.handle(&unregister(event_info(2, 101)))?;
+
+        match register_handler.handle(&register(event_info(1, 100))) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => assert!(err.is::<commands::OutdatedEventError>()),
+        }
+        match unregister_handler.handle(&unregister(event_info(3, 100))) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => assert!(err.is::<commands::OutdatedEventError>()),
+        }
+        assert!(stored_event(&conn)?.is_none());
+
+        register_handler.handle(&register(event_info(4, 102)))?;
+        assert_eq!(stored_event(&conn)?, Some(event_info(4, 102)));
+        Ok(())
+    }
+
+    #[test]
+    fn registrations_exceeding_size_limits_are_refused() -> Result<()> {
+        let conn = fixtures::new_sqlite()?;
+        let handler = RegisterHandler::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            limits::RegistrationLimits::new(1, 10, 10, 50)?,
+        );
+
+        let cases = vec![
+            (
+                register(event_info(1, 100)),
+                "content is too large: 100 bytes, at most 50 are allowed",
+            ),
+            (
+                commands::Register {
+                    registration: registration(
+                        pub_key(),
+                        &["wss://a.example", "wss://b.example"],
+                        event_info(1, 100),
+                    ),
+                    content_size: 10,
+                },
+                "too many relays: 2, at most 1 are allowed",
+            ),
+        ];
+
+        for (cmd, expected_error) in cases {
+            match handler.handle(&cmd) {
+                Ok(_) => panic!("expected an error: {expected_error}"),
+                Err(err) => {
+                    assert!(err.is::<commands::LimitExceededError>());
+                    assert_eq!(err.to_string(), expected_error);
+                }
+            }
+        }
+
+        assert!(stored_event(&conn)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn full_service_refuses_new_registrations_and_relays() -> Result<()> {
+        let conn = fixtures::new_sqlite()?;
+        let handler = RegisterHandler::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            limits::RegistrationLimits::new(2, 2, 1, 1024)?,
+        );
+
+        handler.handle(&commands::Register {
+            registration: registration(
+                pub_key(),
+                &["wss://a.example", "wss://b.example"],
+                event_info(1, 100),
+            ),
+            content_size: 10,
+        })?;
+
+        let other_pub_key = domain::PubKey::new(fixtures::some_keys().public_key());
+        let cases = vec![
+            (
+                registration(other_pub_key, &["wss://a.example"], event_info(2, 101)),
+                "the service doesn't accept new registrations",
+            ),
+            (
+                registration(
+                    pub_key(),
+                    &["wss://a.example", "wss://c.example"],
+                    event_info(3, 101),
+                ),
+                "the service doesn't accept new relays",
+            ),
+        ];
+        for (registration, expected_error) in cases {
+            match handler.handle(&commands::Register {
+                registration,
+                content_size: 10,
+            }) {
+                Ok(_) => panic!("expected an error: {expected_error}"),
+                Err(err) => assert_eq!(err.to_string(), expected_error),
+            }
+        }
+
+        // Stored registrations can still be updated using known relays.
+        handler.handle(&commands::Register {
+            registration: registration(pub_key(), &["wss://b.example"], event_info(4, 102)),
+            content_size: 10,
+        })?;
+        Ok(())
+    }
+
+    fn registration(
+        pub_key: domain::PubKey,
+        relays: &[&str],
+        event: domain::EventInfo,
+    ) -> domain::Registration {
+        domain::Registration::new(
+            pub_key,
+            push_token(),
+            relays
+                .iter()
+                .map(|relay| domain::RelayAddress::new(relay.to_string()).unwrap())
+                .collect(),
+            fixtures::some_locale(),
+            domain::notifications::Preferences::default(),
+            None,
+            event,
+        )
+        .unwrap()
+    }
+
+    fn register(event: domain::EventInfo) -> commands::Register {
+        let registration = domain::Registration::new(
+            pub_key(),
+            push_token(),
+            vec![fixtures::some_relay_address()],
+            fixtures::some_locale(),
+            domain::notifications::Preferences::default(),
+            None,
+            event,
+        )
+        .unwrap();
+        commands::Register {
+            registration,
+            content_size: 100,
+        }
+    }
+
+    fn unregister(event: domain::EventInfo) -> commands::Unregister {
+        commands::Unregister {
+            pub_key: pub_key(),
+            push_token: push_token(),
+            event,
+        }
+    }
+
+    fn stored_event(conn: &sqlite::SqliteConnectionAdapter) -> Result<Option<domain::EventInfo>> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        let event = transaction
+            .adapters()
+            .registrations
+            .borrow()
+            .get_event(&pub_key(), &push_token())?;
+        Ok(event)
+    }
+
+    fn event_info(id: u8, created_at: u64) -> domain::EventInfo {
+        domain::EventInfo::new(
+            nostr::EventId::from_slice(&[id; 32]).unwrap(),
+            nostr::Timestamp::from(created_at),
+        )
+    }
+
+    fn pub_key() -> domain::PubKey {
+        domain::PubKey::new_from_hex(
+            "78c5260d54a9c09065c698950c0bb97b9b03d3c103aa0d5507c902f70e7088af",
+        )
+        .unwrap()
+    }
+
+    fn push_token() -> domain::PushToken {
+        fixtures::some_push_token()
+    }
 }
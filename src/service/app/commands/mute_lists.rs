@@ -0,0 +1,411 @@
+use crate::errors::Result;
+use crate::service::app::common;
+use crate::service::domain;
+use crate::service::domain::mutes;
+use crate::service::domain::relay_health;
+use crate::service::domain::relay_information;
+use crate::service::domain::subscriptions;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc;
+use std::time::Duration;
+
+// How often mute lists are fetched from relays unless configured otherwise.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// Keeps the NIP-51 mute lists of registered pub keys up to date by fetching them from the relays
+// which the pub keys were registered with. Relays are queried without holding a transaction as
+// they can take a long time to reply.
+pub struct MuteListUpdater<T, C> {
+    transaction_provider: T,
+    relay_client: C,
+}
+
+impl<T, C> MuteListUpdater<T, C>
+where
+    T: common::TransactionProvider,
+    C: common::RelayClient,
+{
+    pub fn new(transaction_provider: T, relay_client: C) -> Self {
+        Self {
+            transaction_provider,
+            relay_client,
+        }
+    }
+
+    // Updates the mute lists every interval until a message is received or the sender is dropped.
+    pub fn run(&self, interval: Duration, stop: mpsc::Receiver<()>) {
+        loop {
+            if let Err(err) = self.update() {
+                log::error!("error updating mute lists: {err}");
+            }
+
+            match stop.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                _ => return,
+            }
+        }
+    }
+
+    // Relays which can't be reached are skipped, the lists will be fetched from them next time.
+    pub fn update(&self) -> Result<()> {
+        let mut newest: HashMap<domain::PubKey, mutes::MuteList> = HashMap::new();
+
+        for relay in self.relays()? {
+            let events = match self.fetch_mute_lists(&relay) {
+                Ok(events) => events,
+                Err(err) => {
+                    log::warn!(
+                        "error fetching mute lists from '{}': {err}",
+                        relay.address.as_ref()
+                    );
+                    continue;
+                }
+            };
+
+            for event in events {
+                let mute_list = match mutes::MuteList::from_event(&event) {
+                    Ok(mute_list) => mute_list,
+                    Err(err) => {
+                        log::debug!("skipping a mute list: {err}");
+                        continue;
+                    }
+                };
+
+                if !relay.pub_keys.contains(&mute_list.pub_key()) {
+                    continue;
+                }
+
+                match newest.get(&mute_list.pub_key()) {
+                    Some(other) if !mute_list.event().supersedes(&other.event()) => {}
+                    _ => {
+                        newest.insert(mute_list.pub_key(), mute_list);
+                    }
+                }
+            }
+        }
+
+        self.save(newest.into_values().collect())
+    }
+
+    // Relays are skipped the same way the downloader skips them: dead relays aren't asked until
+    // the downloader manages to connect to them again and relays which require payment aren't
+    // asked at all.
+    fn relays(&self) -> Result<Vec<RegisteredRelay>> {
+        let transaction = self.transaction_provider.start_transaction()?;
+        let adapters = transaction.adapters();
+        let registrations = adapters.registrations.borrow();
+
+        let mut result = vec![];
+        for relay in registrations.get_relays()? {
+            let health = adapters.relay_health.borrow().get_relay_health(&relay)?;
+            if health.is_some_and(|health| health.state() == relay_health::State::Dead) {
+                log::debug!("skipping '{}' as it is dead", relay.as_ref());
+                continue;
+            }
+
+            let information = adapters
+                .relay_information
+                .borrow()
+                .get_relay_information(&relay)?;
+            if information
+                .as_ref()
+                .is_some_and(relay_information::RelayInformation::payment_required)
+            {
+                log::debug!("skipping '{}' as it requires payment", relay.as_ref());
+                continue;
+            }
+
+            let pub_keys = registrations
+                .get_pub_keys(relay.clone())?
+                .iter()
+                .map(common::PubKeyInfo::pub_key)
+                .collect();
+            let limits = subscriptions::SubscriptionLimits::of_relay(information.as_ref());
+            result.push(RegisteredRelay {
+                address: relay,
+                pub_keys,
+                limits,
+            });
+        }
+        Ok(result)
+    }
+
+    // Pub keys are split into chunks the same way the downloader splits them so that requests
+    // stay within the limits of the relay.
+    fn fetch_mute_lists(&self, relay: &RegisteredRelay) -> Result<Vec<nostr::Event>> {
+        let mut events = vec![];
+        for chunk in subscriptions::chunk(&relay.pub_keys, &relay.limits) {
+            let filters = chunk
+                .filters()
+                .iter()
+                .map(|pub_keys| {
+                    nostr::Filter::new()
+                        .kind(nostr::Kind::MuteList)
+                        .authors(pub_keys.iter().map(domain::PubKey::hex).collect())
+                })
+                .collect();
+            events.extend(self.relay_client.fetch_events(&relay.address, filters)?);
+        }
+        Ok(events)
+    }
+
+    // Lists are saved only if they are newer than the stored ones as relays can serve outdated
+    // lists.
+    fn save(&self, mute_lists: Vec<mutes::MuteList>) -> Result<()> {
+        let transaction = self.transaction_provider.start_transaction()?;
+
+        let adapters = transaction.adapters();
+        let registrations = adapters.registrations.borrow();
+
+        for mute_list in mute_lists {
+            match registrations.get_mute_list(&mute_list.pub_key())? {
+                Some(stored) if !mute_list.event().supersedes(&stored.event()) => {}
+                _ => registrations.save_mute_list(&mute_list)?,
+            }
+        }
+
+        transaction.commit()
+    }
+}
+
+struct RegisteredRelay {
+    address: domain::RelayAddress,
+    pub_keys: HashSet<domain::PubKey>,
+    limits: subscriptions::SubscriptionLimits,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::service::adapters::{relays, sqlite};
+    use crate::service::app::common::TransactionProvider as _;
+    use crate::service::domain::notifications;
+
+    #[test]
+    fn mute_lists_are_fetched_from_registered_relays() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let muted = fixtures::some_keys();
+        let unregistered = fixtures::some_keys();
+        let relay = fixtures::FakeRelay::new(vec![
+            fixtures::mute_list_event(&keys, vec![nostr::Tag::PubKey(muted.public_key(), None)]),
+            fixtures::mute_list_event(&unregistered, vec![]),
+        ])?;
+
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &registration(&keys, relay.address())?)?;
+
+        let updater = MuteListUpdater::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        updater.update()?;
+
+        assert_eq!(relay.requests().len(), 1);
+        let mute_list = stored_mute_list(&conn, &keys)?.ok_or("missing mute list")?;
+        assert!(stored_mute_list(&conn, &unregistered)?.is_none());
+
+        let reply = nostr::EventBuilder::new_text_note(
+            "hello",
+            &[nostr::Tag::PubKey(keys.public_key(), None)],
+        )
+        .to_event(&muted)?;
+        assert_eq!(
+            notifications::category_to_notify(
+                &reply,
+                &notifications::Preferences::default(),
+                Some(&mute_list)
+            ),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn older_mute_lists_do_not_replace_newer_ones() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let muted = fixtures::some_keys();
+        let newer = fixtures::with_created_at(
+            &keys,
+            fixtures::mute_list_event(&keys, vec![nostr::Tag::PubKey(muted.public_key(), None)]),
+            nostr::Timestamp::from(200),
+        );
+        let older = fixtures::with_created_at(
+            &keys,
+            fixtures::mute_list_event(&keys, vec![]),
+            nostr::Timestamp::from(100),
+        );
+        let newer_relay = fixtures::FakeRelay::new(vec![newer.clone()])?;
+        let older_relay = fixtures::FakeRelay::new(vec![older])?;
+
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &registration(&keys, newer_relay.address())?)?;
+
+        let updater = MuteListUpdater::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        updater.update()?;
+
+        save(&conn, &registration(&keys, older_relay.address())?)?;
+        updater.update()?;
+
+        let mute_list = stored_mute_list(&conn, &keys)?.ok_or("missing mute list")?;
+        assert_eq!(mute_list.event(), domain::events::event_info(&newer));
+        Ok(())
+    }
+
+    #[test]
+    fn requests_are_split_by_the_limits_of_relays() -> Result<()> {
+        let keys: Vec<nostr::Keys> = (0..5).map(|_| fixtures::some_keys()).collect();
+        let relay = fixtures::FakeRelay::new(
+            keys.iter()
+                .map(|keys| fixtures::mute_list_event(keys, vec![]))
+                .collect(),
+        )?;
+
+        let conn = fixtures::new_sqlite()?;
+        for keys in &keys {
+            save(&conn, &registration(keys, relay.address())?)?;
+        }
+        // Two pub keys fit in a single filter and a single filter in a subscription.
+        save_information(
+            &conn,
+            &relay.address(),
+            r#"{"limitation": {"max_filters": 1, "max_message_length": 262}}"#,
+        )?;
+
+        let updater = MuteListUpdater::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        updater.update()?;
+
+        let requests = relay.requests();
+        assert!(requests.len() >= 3);
+        for filters in requests {
+            assert_eq!(filters.len(), 1);
+            assert!(filters[0]
+                .authors
+                .as_ref()
+                .is_some_and(|authors| authors.len() <= 2));
+        }
+        for keys in &keys {
+            assert!(stored_mute_list(&conn, keys)?.is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dead_relays_and_relays_which_require_payment_are_skipped() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let event = fixtures::mute_list_event(&keys, vec![]);
+        let dead_relay = fixtures::FakeRelay::new(vec![event.clone()])?;
+        let paid_relay = fixtures::FakeRelay::new(vec![event])?;
+
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &registration(&keys, dead_relay.address())?)?;
+        save(&conn, &registration(&keys, paid_relay.address())?)?;
+        let now = nostr::Timestamp::now();
+        save_health(
+            &conn,
+            &relay_health::RelayHealth::restore(
+                dead_relay.address(),
+                relay_health::State::Dead,
+                Some(String::from("connection refused")),
+                100,
+                None,
+                Some(now),
+                Some(now),
+            ),
+        )?;
+        save_information(
+            &conn,
+            &paid_relay.address(),
+            r#"{"limitation": {"payment_required": true}}"#,
+        )?;
+
+        let updater = MuteListUpdater::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        updater.update()?;
+
+        assert!(dead_relay.requests().is_empty());
+        assert!(paid_relay.requests().is_empty());
+        assert!(stored_mute_list(&conn, &keys)?.is_none());
+        Ok(())
+    }
+
+    fn registration(
+        keys: &nostr::Keys,
+        relay: domain::RelayAddress,
+    ) -> Result<domain::Registration> {
+        domain::Registration::new(
+            domain::PubKey::new(keys.public_key()),
+            fixtures::some_push_token(),
+            vec![relay],
+            fixtures::some_locale(),
+            notifications::Preferences::default(),
+            None,
+            fixtures::some_event_info(),
+        )
+    }
+
+    fn save(
+        conn: &sqlite::SqliteConnectionAdapter,
+        registration: &domain::Registration,
+    ) -> Result<()> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        transaction
+            .adapters()
+            .registrations
+            .borrow()
+            .save(registration)?;
+        transaction.commit()
+    }
+
+    fn save_health(
+        conn: &sqlite::SqliteConnectionAdapter,
+        health: &relay_health::RelayHealth,
+    ) -> Result<()> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        transaction
+            .adapters()
+            .relay_health
+            .borrow()
+            .save_relay_health(health)?;
+        transaction.commit()
+    }
+
+    fn save_information(
+        conn: &sqlite::SqliteConnectionAdapter,
+        relay: &domain::RelayAddress,
+        document: &str,
+    ) -> Result<()> {
+        let information = relay_information::RelayInformation::new(
+            document.to_string(),
+            nostr::Timestamp::now(),
+        )?;
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        transaction
+            .adapters()
+            .relay_information
+            .borrow()
+            .save_relay_information(relay, &information)?;
+        transaction.commit()
+    }
+
+    fn stored_mute_list(
+        conn: &sqlite::SqliteConnectionAdapter,
+        keys: &nostr::Keys,
+    ) -> Result<Option<mutes::MuteList>> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        let mute_list = transaction
+            .adapters()
+            .registrations
+            .borrow()
+            .get_mute_list(&domain::PubKey::new(keys.public_key()))?;
+        Ok(mute_list)
+    }
+}
@@ -0,0 +1,638 @@
+use crate::errors::Result;
+use crate::service::app::common;
+use crate::service::domain;
+use crate::service::domain::contacts;
+use crate::service::domain::notifications;
+use crate::service::domain::quiet_hours;
+use std::sync::mpsc;
+use std::time::Duration;
+
+// How often the notifier checks for downloaded events unless configured otherwise.
+pub const PROCESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// How many events are processed in a single transaction.
+const BATCH_SIZE: usize = 100;
+
+// Turns events saved by the downloader into notifications for the devices of the pub keys which
+// the events tag, following the preferences, mute lists and quiet hours of those pub keys. Events
+// are marked as processed in the same transaction in which notifications are created and
+// notifications are sent once the transaction is committed as push providers can take a long time
+// to reply. A failure can therefore lose notifications but never sends them twice. Digests of
+// notifications held during quiet hours are sent the same way.
+pub struct Notifier<T, S> {
+    transaction_provider: T,
+    sender: S,
+}
+
+impl<T, S> Notifier<T, S>
+where
+    T: common::TransactionProvider,
+    S: common::NotificationSender,
+{
+    pub fn new(transaction_provider: T, sender: S) -> Self {
+        Self {
+            transaction_provider,
+            sender,
+        }
+    }
+
+    // Processes events every interval until a message is received or the sender is dropped.
+    pub fn run(&self, interval: Duration, stop: mpsc::Receiver<()>) {
+        loop {
+            if let Err(err) = self.process(nostr::Timestamp::now()) {
+                log::error!("error sending notifications: {err}");
+            }
+
+            match stop.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                _ => return,
+            }
+        }
+    }
+
+    // Sends digests of quiet hours which ended by now and processes all events which were saved
+    // since the last call.
+    pub fn process(&self, now: nostr::Timestamp) -> Result<()> {
+        for digest in &self.take_digests(now)? {
+            if let Err(err) = self.sender.send_digest(digest) {
+                log::warn!(
+                    "error sending a digest of {} notifications: {err}",
+                    digest.total()
+                );
+            }
+        }
+
+        loop {
+            let (notifications, processed) = self.prepare(now)?;
+
+            for notification in &notifications {
+                if let Err(err) = self.sender.send(notification) {
+                    log::warn!(
+                        "error sending a notification about event {}: {err}",
+                        notification.event_id()
+                    );
+                }
+            }
+
+            if processed < BATCH_SIZE {
+                return Ok(());
+            }
+        }
+    }
+
+    fn take_digests(&self, now: nostr::Timestamp) -> Result<Vec<quiet_hours::Digest>> {
+        let transaction = self.transaction_provider.start_transaction()?;
+        let digests = transaction
+            .adapters()
+            .registrations
+            .borrow()
+            .take_digests(now)?;
+        transaction.commit()?;
+        Ok(digests)
+    }
+
+    // Returns the notifications for a batch of events and the number of processed events.
+    fn prepare(&self, now: nostr::Timestamp) -> Result<(Vec<notifications::Notification>, usize)> {
+        let transaction = self.transaction_provider.start_transaction()?;
+
+        let adapters = transaction.adapters();
+        let events = adapters.events.borrow();
+        let registrations = adapters.registrations.borrow();
+
+        let unprocessed = events.get_unprocessed_events(BATCH_SIZE)?;
+        let mut notifications = vec![];
+        for event in &unprocessed {
+            let recipients = self.recipients(registrations.as_ref(), event)?;
+            notifications.extend(self.notifications(
+                registrations.as_ref(),
+                event,
+                recipients,
+                now,
+            )?);
+            events.mark_event_processed(&event.id)?;
+        }
+
+        transaction.commit()?;
+        Ok((notifications, unprocessed.len()))
+    }
+
+    // Contact lists tag every pub key which their author follows so only pub keys which the
+    // previous list of the author didn't follow are notified about them.
+    fn recipients(
+        &self,
+        registrations: &dyn common::RegistrationRepository,
+        event: &nostr::Event,
+    ) -> Result<Vec<domain::PubKey>> {
+        let recipients = notifications::recipients(event);
+        if event.kind.as_u64() != contacts::CONTACT_LIST_KIND {
+            return Ok(recipients);
+        }
+
+        let contact_list = contacts::ContactList::from_event(event)?;
+        let previous = registrations.get_contact_list(&contact_list.pub_key())?;
+        let new_follows = contact_list.new_follows(previous.as_ref());
+        if previous
+            .as_ref()
+            .is_none_or(|previous| contact_list.event().supersedes(&previous.event()))
+        {
+            registrations.save_contact_list(&contact_list)?;
+        }
+
+        Ok(recipients
+            .into_iter()
+            .filter(|pub_key| new_follows.contains(pub_key))
+            .collect())
+    }
+
+    // Every device is notified according to its own preferences and quiet hours, mute lists are
+    // shared by all devices of a pub key. Notifications held during quiet hours are saved and not
+    // returned.
+    fn notifications(
+        &self,
+        registrations: &dyn common::RegistrationRepository,
+        event: &nostr::Event,
+        recipients: Vec<domain::PubKey>,
+        now: nostr::Timestamp,
+    ) -> Result<Vec<notifications::Notification>> {
+        let mut result = vec![];
+        for pub_key in recipients {
+            let devices = registrations.get_registrations(&pub_key)?;
+            if devices.is_empty() {
+                continue;
+            }
+
+            let mute_list = registrations.get_mute_list(&pub_key)?;
+            for device in devices {
+                let category = notifications::category_to_notify(
+                    event,
+                    &device.preferences(),
+                    mute_list.as_ref(),
+                );
+                let Some(category) = category else {
+                    log::debug!("not notifying {} about event {}", pub_key.hex(), event.id);
+                    continue;
+                };
+
+                let delivery = device
+                    .quiet_hours()
+                    .map_or(quiet_hours::Delivery::Now, |quiet_hours| {
+                        quiet_hours.delivery(now)
+                    });
+                match delivery {
+                    quiet_hours::Delivery::Now => result.push(notifications::Notification::new(
+                        pub_key.clone(),
+                        device.push_token(),
+                        device.locale(),
+                        event.id,
+                        category,
+                    )),
+                    quiet_hours::Delivery::Drop => log::debug!(
+                        "not notifying {} about event {} during quiet hours",
+                        pub_key.hex(),
+                        event.id
+                    ),
+                    quiet_hours::Delivery::HoldUntil(release_at) => registrations
+                        .hold_notification(&quiet_hours::HeldNotification::new(
+                            pub_key.clone(),
+                            device.push_token(),
+                            event.id,
+                            category,
+                            release_at,
+                        ))?,
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::service::adapters::{relays, sqlite};
+    use crate::service::app::commands::downloader::Downloader;
+    use crate::service::app::common::TransactionProvider as _;
+    use crate::service::domain::mutes;
+    use std::collections::BTreeMap;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn events_downloaded_from_relays_are_notified() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let author = fixtures::some_keys();
+        let mention = nostr::EventBuilder::new_text_note(
+            "hello",
+            &[nostr::Tag::PubKey(keys.public_key(), None)],
+        )
+        .to_event(&author)?;
+        let reaction = nostr::EventBuilder::new(
+            nostr::Kind::Reaction,
+            "+",
+            &[nostr::Tag::PubKey(keys.public_key(), None)],
+        )
+        .to_event(&author)?;
+        let relay = fixtures::FakeRelay::new(vec![mention.clone(), reaction])?;
+
+        let conn = fixtures::new_sqlite()?;
+        let registration = registration(&keys, relay.address())?;
+        save(&conn, &registration)?;
+
+        let mut downloader = Downloader::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            relays::RelayClient::new(Duration::from_secs(5)),
+        );
+        downloader.update()?;
+        wait_until(|| Ok(unprocessed_events(&conn)? == 2))?;
+        drop(downloader);
+
+        let notifier = Notifier::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            fixtures::NotificationSenderMock::new(),
+        );
+        notifier.process(nostr::Timestamp::now())?;
+
+        // Reactions are disabled by default.
+        assert_eq!(
+            notifier.sender.notifications(),
+            vec![notifications::Notification::new(
+                registration.pub_key(),
+                registration.push_token(),
+                registration.locale(),
+                mention.id,
+                notifications::Category::Mention,
+            )]
+        );
+        assert_eq!(unprocessed_events(&conn)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn events_are_only_notified_once() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &registration(&keys, fixtures::some_relay_address())?)?;
+        save_event(&conn, &mention(&keys, &fixtures::some_keys())?)?;
+
+        let notifier = Notifier::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            fixtures::NotificationSenderMock::new(),
+        );
+        notifier.process(nostr::Timestamp::now())?;
+        notifier.process(nostr::Timestamp::now())?;
+
+        assert_eq!(notifier.sender.notifications().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn unregistered_pub_keys_and_authors_are_not_notified() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &registration(&keys, fixtures::some_relay_address())?)?;
+        save_event(&conn, &mention(&fixtures::some_keys(), &keys)?)?;
+        save_event(&conn, &mention(&keys, &keys)?)?;
+
+        let notifier = Notifier::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            fixtures::NotificationSenderMock::new(),
+        );
+        notifier.process(nostr::Timestamp::now())?;
+
+        assert_eq!(notifier.sender.notifications(), vec![]);
+        assert_eq!(unprocessed_events(&conn)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn all_devices_of_a_pub_key_are_notified() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let conn = fixtures::new_sqlite()?;
+        let phone = registration(&keys, fixtures::some_relay_address())?;
+        let tablet = domain::Registration::new(
+            domain::PubKey::new(keys.public_key()),
+            fixtures::fcm_token("tablet"),
+            vec![fixtures::some_relay_address()],
+            fixtures::some_locale(),
+            notifications::Preferences::default(),
+            None,
+            fixtures::some_event_info(),
+        )?;
+        save(&conn, &phone)?;
+        save(&conn, &tablet)?;
+        save_event(&conn, &mention(&keys, &fixtures::some_keys())?)?;
+
+        let notifier = Notifier::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            fixtures::NotificationSenderMock::new(),
+        );
+        notifier.process(nostr::Timestamp::now())?;
+
+        let mut push_tokens: Vec<domain::PushToken> = notifier
+            .sender
+            .notifications()
+            .iter()
+            .map(notifications::Notification::push_token)
+            .collect();
+        push_tokens.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        let mut expected = vec![phone.push_token(), tablet.push_token()];
+        expected.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        assert_eq!(push_tokens, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn contact_lists_only_notify_new_followers() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let other = fixtures::some_keys();
+        let follower = fixtures::some_keys();
+        let conn = fixtures::new_sqlite()?;
+        let registration = registration(&keys, fixtures::some_relay_address())?;
+        save(&conn, &registration)?;
+
+        let notifier = Notifier::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            fixtures::NotificationSenderMock::new(),
+        );
+
+        let contact_list = |pub_keys: &[&nostr::Keys], created_at: u64| {
+            let tags = pub_keys
+                .iter()
+                .map(|keys| nostr::Tag::PubKey(keys.public_key(), None))
+                .collect();
+            fixtures::with_created_at(
+                &follower,
+                fixtures::contact_list_event(&follower, tags),
+                nostr::Timestamp::from(created_at),
+            )
+        };
+
+        // The first list which the service sees can't tell new follows from old ones.
+        save_event(&conn, &contact_list(&[&keys], 1000))?;
+        notifier.process(nostr::Timestamp::now())?;
+        assert_eq!(notifier.sender.notifications(), vec![]);
+
+        save_event(&conn, &contact_list(&[&keys, &other], 2000))?;
+        notifier.process(nostr::Timestamp::now())?;
+        assert_eq!(notifier.sender.notifications(), vec![]);
+
+        save_event(&conn, &contact_list(&[&other], 3000))?;
+        save_event(&conn, &contact_list(&[], 2500))?;
+        notifier.process(nostr::Timestamp::now())?;
+        assert_eq!(notifier.sender.notifications(), vec![]);
+
+        let followed = contact_list(&[&keys, &other], 4000);
+        save_event(&conn, &followed)?;
+        notifier.process(nostr::Timestamp::now())?;
+        assert_eq!(
+            notifier.sender.notifications(),
+            vec![notifications::Notification::new(
+                registration.pub_key(),
+                registration.push_token(),
+                registration.locale(),
+                followed.id,
+                notifications::Category::NewFollower,
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn disabled_categories_are_not_notified() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let conn = fixtures::new_sqlite()?;
+        let registration = domain::Registration::new(
+            domain::PubKey::new(keys.public_key()),
+            fixtures::some_push_token(),
+            vec![fixtures::some_relay_address()],
+            fixtures::some_locale(),
+            notifications::Preferences::new([notifications::Category::Reply]),
+            None,
+            fixtures::some_event_info(),
+        )?;
+        save(&conn, &registration)?;
+        save_event(&conn, &mention(&keys, &fixtures::some_keys())?)?;
+
+        let notifier = Notifier::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            fixtures::NotificationSenderMock::new(),
+        );
+        notifier.process(nostr::Timestamp::now())?;
+
+        assert_eq!(notifier.sender.notifications(), vec![]);
+        assert_eq!(unprocessed_events(&conn)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn events_muted_by_the_recipient_are_not_notified() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let muted = fixtures::some_keys();
+        let other = fixtures::some_keys();
+        let conn = fixtures::new_sqlite()?;
+        let registration = registration(&keys, fixtures::some_relay_address())?;
+        save(&conn, &registration)?;
+        save_mute_list(
+            &conn,
+            &mutes::MuteList::from_event(&fixtures::mute_list_event(
+                &keys,
+                vec![nostr::Tag::PubKey(muted.public_key(), None)],
+            ))?,
+        )?;
+        save_event(&conn, &mention(&keys, &muted)?)?;
+        let not_muted = mention(&keys, &other)?;
+        save_event(&conn, &not_muted)?;
+
+        let notifier = Notifier::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            fixtures::NotificationSenderMock::new(),
+        );
+        notifier.process(nostr::Timestamp::now())?;
+
+        assert_eq!(
+            notifier.sender.notifications(),
+            vec![notifications::Notification::new(
+                registration.pub_key(),
+                registration.push_token(),
+                registration.locale(),
+                not_muted.id,
+                notifications::Category::Mention,
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn notifications_held_during_quiet_hours_are_sent_as_a_digest_once_they_end() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let conn = fixtures::new_sqlite()?;
+        let registration = quiet_registration(&keys, quiet_hours::Mode::Digest)?;
+        save(&conn, &registration)?;
+
+        let notifier = Notifier::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            fixtures::NotificationSenderMock::new(),
+        );
+
+        save_event(&conn, &mention(&keys, &fixtures::some_keys())?)?;
+        notifier.process(timestamp("2023-06-01T23:00:00Z"))?;
+        save_event(&conn, &mention(&keys, &fixtures::some_keys())?)?;
+        notifier.process(timestamp("2023-06-02T06:59:59Z"))?;
+
+        assert_eq!(notifier.sender.notifications(), vec![]);
+        assert_eq!(notifier.sender.digests(), vec![]);
+
+        notifier.process(timestamp("2023-06-02T07:00:00Z"))?;
+        notifier.process(timestamp("2023-06-02T07:00:01Z"))?;
+
+        assert_eq!(notifier.sender.notifications(), vec![]);
+        assert_eq!(
+            notifier.sender.digests(),
+            vec![quiet_hours::Digest::new(
+                registration.pub_key(),
+                registration.push_token(),
+                BTreeMap::from([(notifications::Category::Mention, 2)]),
+            )]
+        );
+
+        let mention = mention(&keys, &fixtures::some_keys())?;
+        save_event(&conn, &mention)?;
+        notifier.process(timestamp("2023-06-02T08:00:00Z"))?;
+
+        assert_eq!(
+            notifier.sender.notifications(),
+            vec![notifications::Notification::new(
+                registration.pub_key(),
+                registration.push_token(),
+                registration.locale(),
+                mention.id,
+                notifications::Category::Mention,
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn notifications_are_dropped_during_quiet_hours() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let conn = fixtures::new_sqlite()?;
+        save(&conn, &quiet_registration(&keys, quiet_hours::Mode::Drop)?)?;
+
+        let notifier = Notifier::new(
+            sqlite::TransactionProvider::new(conn.clone()),
+            fixtures::NotificationSenderMock::new(),
+        );
+
+        save_event(&conn, &mention(&keys, &fixtures::some_keys())?)?;
+        notifier.process(timestamp("2023-06-01T23:00:00Z"))?;
+        notifier.process(timestamp("2023-06-02T07:00:00Z"))?;
+
+        assert_eq!(notifier.sender.notifications(), vec![]);
+        assert_eq!(notifier.sender.digests(), vec![]);
+        assert_eq!(unprocessed_events(&conn)?, 0);
+        Ok(())
+    }
+
+    fn mention(tagged: &nostr::Keys, author: &nostr::Keys) -> Result<nostr::Event> {
+        let event = nostr::EventBuilder::new_text_note(
+            "hello",
+            &[nostr::Tag::PubKey(tagged.public_key(), None)],
+        )
+        .to_event(author)?;
+        Ok(event)
+    }
+
+    fn registration(
+        keys: &nostr::Keys,
+        relay: domain::RelayAddress,
+    ) -> Result<domain::Registration> {
+        domain::Registration::new(
+            domain::PubKey::new(keys.public_key()),
+            fixtures::some_push_token(),
+            vec![relay],
+            fixtures::some_locale(),
+            notifications::Preferences::default(),
+            None,
+            fixtures::some_event_info(),
+        )
+    }
+
+    // Quiet from 22:00 to 07:00 UTC.
+    fn quiet_registration(
+        keys: &nostr::Keys,
+        mode: quiet_hours::Mode,
+    ) -> Result<domain::Registration> {
+        domain::Registration::new(
+            domain::PubKey::new(keys.public_key()),
+            fixtures::some_push_token(),
+            vec![fixtures::some_relay_address()],
+            fixtures::some_locale(),
+            notifications::Preferences::default(),
+            Some(quiet_hours::QuietHours::new(
+                "UTC",
+                vec![quiet_hours::Window::parse("22:00", "07:00")?],
+                mode,
+            )?),
+            fixtures::some_event_info(),
+        )
+    }
+
+    fn timestamp(s: &str) -> nostr::Timestamp {
+        let date_time = chrono::DateTime::parse_from_rfc3339(s).unwrap();
+        nostr::Timestamp::from(date_time.timestamp() as u64)
+    }
+
+    fn save(
+        conn: &sqlite::SqliteConnectionAdapter,
+        registration: &domain::Registration,
+    ) -> Result<()> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        transaction
+            .adapters()
+            .registrations
+            .borrow()
+            .save(registration)?;
+        transaction.commit()
+    }
+
+    fn save_mute_list(
+        conn: &sqlite::SqliteConnectionAdapter,
+        mute_list: &mutes::MuteList,
+    ) -> Result<()> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        transaction
+            .adapters()
+            .registrations
+            .borrow()
+            .save_mute_list(mute_list)?;
+        transaction.commit()
+    }
+
+    fn save_event(conn: &sqlite::SqliteConnectionAdapter, event: &nostr::Event) -> Result<()> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        transaction.adapters().events.borrow().save_event(event)?;
+        transaction.commit()
+    }
+
+    fn unprocessed_events(conn: &sqlite::SqliteConnectionAdapter) -> Result<usize> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        let events = transaction
+            .adapters()
+            .events
+            .borrow()
+            .get_unprocessed_events(BATCH_SIZE)?;
+        Ok(events.len())
+    }
+
+    fn wait_until(f: impl Fn() -> Result<bool>) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f()? {
+            if Instant::now() > deadline {
+                return Err("timed out".into());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ use crate::errors::Result;
 use crate::service::domain;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::Duration;
 
 pub trait Transaction {
     fn adapters(&self) -> Adapters;
@@ -16,28 +17,211 @@ pub trait TransactionProvider {
 pub struct Adapters {
     pub registrations: Rc<RefCell<Box<dyn RegistrationRepository>>>,
     pub events: Rc<RefCell<Box<dyn EventRepository>>>,
+    pub relay_health: Rc<RefCell<Box<dyn RelayHealthRepository>>>,
+    pub relay_information: Rc<RefCell<Box<dyn RelayInformationRepository>>>,
 }
 
 impl Adapters {
     pub fn new(
         registrations: Box<dyn RegistrationRepository>,
         events: Box<dyn EventRepository>,
+        relay_health: Box<dyn RelayHealthRepository>,
+        relay_information: Box<dyn RelayInformationRepository>,
     ) -> Adapters {
         Adapters {
             registrations: Rc::new(RefCell::new(registrations)),
             events: Rc::new(RefCell::new(events)),
+            relay_health: Rc::new(RefCell::new(relay_health)),
+            relay_information: Rc::new(RefCell::new(relay_information)),
         }
     }
 }
 
 pub trait RegistrationRepository {
     fn save(&self, registration: &domain::Registration) -> Result<()>;
+    fn delete(&self, pub_key: &domain::PubKey, push_token: &domain::PushToken) -> Result<()>;
+    fn get_event(
+        &self,
+        pub_key: &domain::PubKey,
+        push_token: &domain::PushToken,
+    ) -> Result<Option<domain::EventInfo>>;
+    fn save_unregistration_event(
+        &self,
+        pub_key: &domain::PubKey,
+        push_token: &domain::PushToken,
+        event: &domain::EventInfo,
+    ) -> Result<()>;
+    fn get_unregistration_event(
+        &self,
+        pub_key: &domain::PubKey,
+        push_token: &domain::PushToken,
+    ) -> Result<Option<domain::EventInfo>>;
+    fn get_preferences(
+        &self,
+        pub_key: &domain::PubKey,
+        push_token: &domain::PushToken,
+    ) -> Result<domain::notifications::Preferences>;
+    fn get_quiet_hours(
+        &self,
+        pub_key: &domain::PubKey,
+        push_token: &domain::PushToken,
+    ) -> Result<Option<domain::quiet_hours::QuietHours>>;
+    fn hold_notification(&self, notification: &domain::quiet_hours::HeldNotification)
+        -> Result<()>;
+    // Removes notifications which were held until now or earlier and returns them as one digest
+    // per device.
+    fn take_digests(&self, now: nostr::Timestamp) -> Result<Vec<domain::quiet_hours::Digest>>;
+    fn save_contact_list(&self, contact_list: &domain::contacts::ContactList) -> Result<()>;
+    fn get_contact_list(
+        &self,
+        pub_key: &domain::PubKey,
+    ) -> Result<Option<domain::contacts::ContactList>>;
+    fn save_mute_list(&self, mute_list: &domain::mutes::MuteList) -> Result<()>;
+    fn get_mute_list(&self, pub_key: &domain::PubKey) -> Result<Option<domain::mutes::MuteList>>;
+    fn get_registrations(&self, pub_key: &domain::PubKey) -> Result<Vec<RegistrationInfo>>;
     fn get_relays(&self) -> Result<Vec<domain::RelayAddress>>;
+    fn count_registrations(&self) -> Result<usize>;
     fn get_pub_keys(&self, relay: domain::RelayAddress) -> Result<Vec<PubKeyInfo>>;
 }
 
+// Fetches stored events from relays or keeps connections open to receive new events. Events are
+// returned only if their signatures are valid.
+pub trait RelayClient {
+    fn fetch_events(
+        &self,
+        relay: &domain::RelayAddress,
+        filters: Vec<nostr::Filter>,
+    ) -> Result<Vec<nostr::Event>>;
+    fn connect(&self, relay: &domain::RelayAddress) -> Result<Box<dyn RelayConnection>>;
+    // Fetches the NIP-11 relay information document.
+    fn fetch_information(
+        &self,
+        relay: &domain::RelayAddress,
+    ) -> Result<domain::relay_information::RelayInformation>;
+}
+
+// Connection to a relay which can carry many subscriptions. The connection is closed once
+// dropped.
+pub trait RelayConnection {
+    fn subscribe(
+        &mut self,
+        subscription_id: &nostr::SubscriptionId,
+        filters: Vec<nostr::Filter>,
+    ) -> Result<()>;
+    fn unsubscribe(&mut self, subscription_id: &nostr::SubscriptionId) -> Result<()>;
+    // Returns None if nothing was received before the timeout or if the relay sent a message
+    // which isn't relevant to subscriptions.
+    fn next_message(&mut self, timeout: Duration) -> Result<Option<RelayMessage>>;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RelayMessage {
+    Event {
+        subscription_id: nostr::SubscriptionId,
+        event: Box<nostr::Event>,
+    },
+    EndOfStoredEvents(nostr::SubscriptionId),
+    Closed {
+        subscription_id: nostr::SubscriptionId,
+        message: String,
+    },
+}
+
+// Events downloaded from relays wait here until notifications are sent for them.
 pub trait EventRepository {
-    fn save_event(&self);
+    // Returns false if the event was already saved, relays send the same events many times.
+    fn save_event(&self, event: &nostr::Event) -> Result<bool>;
+    fn get_unprocessed_events(&self, limit: usize) -> Result<Vec<nostr::Event>>;
+    fn mark_event_processed(&self, id: &nostr::EventId) -> Result<()>;
+}
+
+// Sends push notifications to devices through their push providers.
+pub trait NotificationSender {
+    fn send(&self, notification: &domain::notifications::Notification) -> Result<()>;
+    fn send_digest(&self, digest: &domain::quiet_hours::Digest) -> Result<()>;
+}
+
+// Health of relay connections is kept across restarts so that relays which are down aren't
+// hammered with connection attempts after every restart.
+pub trait RelayHealthRepository {
+    fn save_relay_health(&self, health: &domain::relay_health::RelayHealth) -> Result<()>;
+    fn get_relay_health(
+        &self,
+        relay: &domain::RelayAddress,
+    ) -> Result<Option<domain::relay_health::RelayHealth>>;
+    // Time since which events are requested from the relay, kept so that events sent while the
+    // service wasn't running aren't missed.
+    fn save_since(&self, relay: &domain::RelayAddress, since: nostr::Timestamp) -> Result<()>;
+    fn get_since(&self, relay: &domain::RelayAddress) -> Result<Option<nostr::Timestamp>>;
+}
+
+// Relay information documents are cached as relays rarely change them.
+pub trait RelayInformationRepository {
+    fn save_relay_information(
+        &self,
+        relay: &domain::RelayAddress,
+        information: &domain::relay_information::RelayInformation,
+    ) -> Result<()>;
+    fn get_relay_information(
+        &self,
+        relay: &domain::RelayAddress,
+    ) -> Result<Option<domain::relay_information::RelayInformation>>;
+}
+
+// What is stored about a single device of a pub key. Registrations saved before events were
+// stored don't have the time of the last update.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegistrationInfo {
+    push_token: domain::PushToken,
+    relays: Vec<domain::RelayAddress>,
+    locale: domain::Locale,
+    preferences: domain::notifications::Preferences,
+    quiet_hours: Option<domain::quiet_hours::QuietHours>,
+    updated_at: Option<nostr::Timestamp>,
+}
+
+impl RegistrationInfo {
+    pub fn new(
+        push_token: domain::PushToken,
+        relays: Vec<domain::RelayAddress>,
+        locale: domain::Locale,
+        preferences: domain::notifications::Preferences,
+        quiet_hours: Option<domain::quiet_hours::QuietHours>,
+        updated_at: Option<nostr::Timestamp>,
+    ) -> Self {
+        Self {
+            push_token,
+            relays,
+            locale,
+            preferences,
+            quiet_hours,
+            updated_at,
+        }
+    }
+
+    pub fn push_token(&self) -> domain::PushToken {
+        self.push_token.clone()
+    }
+
+    pub fn relays(&self) -> Vec<domain::RelayAddress> {
+        self.relays.clone()
+    }
+
+    pub fn locale(&self) -> domain::Locale {
+        self.locale.clone()
+    }
+
+    pub fn preferences(&self) -> domain::notifications::Preferences {
+        self.preferences.clone()
+    }
+
+    pub fn quiet_hours(&self) -> Option<domain::quiet_hours::QuietHours> {
+        self.quiet_hours.clone()
+    }
+
+    pub fn updated_at(&self) -> Option<nostr::Timestamp> {
+        self.updated_at
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -50,4 +234,8 @@ impl PubKeyInfo {
     pub fn new(pub_key: domain::PubKey) -> Self {
         Self { pub_key }
     }
+
+    pub fn pub_key(&self) -> domain::PubKey {
+        self.pub_key.clone()
+    }
 }
@@ -0,0 +1,14 @@
+pub mod implementation;
+
+use crate::errors::Result;
+use crate::service::app::common::RegistrationInfo;
+use crate::service::domain::PubKey;
+
+pub struct GetRegistration {
+    pub pub_key: PubKey,
+}
+
+// Returns the registrations of all devices of the pub key.
+pub trait GetRegistrationHandler {
+    fn handle(&self, query: &GetRegistration) -> Result<Vec<RegistrationInfo>>;
+}
@@ -0,0 +1,117 @@
+use crate::app::queries;
+use crate::errors::Result;
+use crate::service::app::common;
+
+pub struct GetRegistrationHandler<T> {
+    transaction_provider: T,
+}
+
+impl<T> GetRegistrationHandler<T> {
+    pub fn new(transaction_provider: T) -> GetRegistrationHandler<T> {
+        GetRegistrationHandler {
+            transaction_provider,
+        }
+    }
+}
+
+impl<T> queries::GetRegistrationHandler for GetRegistrationHandler<T>
+where
+    T: common::TransactionProvider,
+{
+    fn handle(&self, query: &queries::GetRegistration) -> Result<Vec<common::RegistrationInfo>> {
+        let transaction = self.transaction_provider.start_transaction()?;
+
+        let adapters = transaction.adapters();
+        let registrations = adapters.registrations.borrow();
+
+        registrations.get_registrations(&query.pub_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::service::adapters::sqlite;
+    use crate::service::app::common::TransactionProvider as _;
+    use crate::service::app::queries::GetRegistrationHandler as _;
+    use crate::service::domain;
+
+    #[test]
+    fn registrations_of_all_devices_are_returned() -> Result<()> {
+        let conn = fixtures::new_sqlite()?;
+        let keys = fixtures::some_keys();
+        let phone = registration(&keys, fixtures::some_push_token())?;
+        let tablet = registration(&keys, fixtures::fcm_token("other_token"))?;
+        save(&conn, &phone)?;
+        save(&conn, &tablet)?;
+        save(&conn, &fixtures::some_registration())?;
+
+        let handler = GetRegistrationHandler::new(sqlite::TransactionProvider::new(conn));
+        let mut registrations = handler.handle(&queries::GetRegistration {
+            pub_key: domain::PubKey::new(keys.public_key()),
+        })?;
+        registrations.sort_by_key(|v| v.push_token().as_ref().to_string());
+
+        let mut expected: Vec<common::RegistrationInfo> = [phone, tablet]
+            .iter()
+            .map(|registration| {
+                common::RegistrationInfo::new(
+                    registration.push_token(),
+                    registration.relays(),
+                    registration.locale(),
+                    registration.preferences(),
+                    registration.quiet_hours(),
+                    Some(registration.event().created_at()),
+                )
+            })
+            .collect();
+        expected.sort_by_key(|v| v.push_token().as_ref().to_string());
+
+        assert_eq!(registrations, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_pub_keys_have_no_registrations() -> Result<()> {
+        let handler =
+            GetRegistrationHandler::new(sqlite::TransactionProvider::new(fixtures::new_sqlite()?));
+        let registrations = handler.handle(&queries::GetRegistration {
+            pub_key: fixtures::some_pub_key(),
+        })?;
+        assert!(registrations.is_empty());
+        Ok(())
+    }
+
+    fn registration(
+        keys: &nostr::Keys,
+        push_token: domain::PushToken,
+    ) -> Result<domain::Registration> {
+        domain::Registration::new(
+            domain::PubKey::new(keys.public_key()),
+            push_token,
+            vec![fixtures::some_relay_address()],
+            fixtures::some_locale(),
+            domain::notifications::Preferences::default(),
+            Some(domain::quiet_hours::QuietHours::new(
+                "Europe/Warsaw",
+                vec![domain::quiet_hours::Window::parse("22:00", "07:00")?],
+                domain::quiet_hours::Mode::Digest,
+            )?),
+            fixtures::some_event_info(),
+        )
+    }
+
+    fn save(
+        conn: &sqlite::SqliteConnectionAdapter,
+        registration: &domain::Registration,
+    ) -> Result<()> {
+        let transaction = sqlite::TransactionProvider::new(conn.clone()).start_transaction()?;
+        transaction
+            .adapters()
+            .registrations
+            .borrow()
+            .save(registration)?;
+        transaction.commit()
+    }
+}
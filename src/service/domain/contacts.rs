@@ -0,0 +1,161 @@
+use crate::errors::Result;
+use crate::service::domain;
+use std::collections::HashSet;
+
+// Kind of NIP-02 contact lists.
+pub const CONTACT_LIST_KIND: u64 = 3;
+
+// Pub keys followed by the author of a NIP-02 contact list. Clients republish the whole list every
+// time a single contact changes so the list is kept to tell new follows from existing ones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContactList {
+    pub_key: domain::PubKey,
+    pub_keys: HashSet<domain::PubKey>,
+    event: domain::EventInfo,
+}
+
+impl ContactList {
+    pub fn new(
+        pub_key: domain::PubKey,
+        pub_keys: HashSet<domain::PubKey>,
+        event: domain::EventInfo,
+    ) -> ContactList {
+        ContactList {
+            pub_key,
+            pub_keys,
+            event,
+        }
+    }
+
+    // Tags which can't be parsed are skipped so that a single malformed tag doesn't hide the whole
+    // list.
+    pub fn from_event(event: &nostr::Event) -> Result<ContactList> {
+        if event.kind.as_u64() != CONTACT_LIST_KIND {
+            return Err(format!("unexpected event kind: {}", event.kind.as_u64()).into());
+        }
+
+        let mut pub_keys = HashSet::new();
+        for tag in &event.tags {
+            let tag = tag.as_vec();
+            if let (Some("p"), Some(value)) = (tag.first().map(String::as_str), tag.get(1)) {
+                match domain::PubKey::new_from_hex(value) {
+                    Ok(pub_key) => {
+                        pub_keys.insert(pub_key);
+                    }
+                    Err(err) => log::debug!("skipping a followed pub key '{value}': {err}"),
+                }
+            }
+        }
+
+        Ok(ContactList::new(
+            domain::PubKey::new(event.pubkey),
+            pub_keys,
+            domain::events::event_info(event),
+        ))
+    }
+
+    pub fn pub_key(&self) -> domain::PubKey {
+        self.pub_key.clone()
+    }
+
+    pub fn pub_keys(&self) -> &HashSet<domain::PubKey> {
+        &self.pub_keys
+    }
+
+    pub fn event(&self) -> domain::EventInfo {
+        self.event.clone()
+    }
+
+    // Pub keys which this list follows and the previous list of the same author didn't. Nothing is
+    // new if the previous list isn't known as relays serve the latest list of every follower once
+    // the service subscribes, nor if this list doesn't supersede the previous one.
+    pub fn new_follows(&self, previous: Option<&ContactList>) -> HashSet<domain::PubKey> {
+        match previous {
+            Some(previous) if self.event.supersedes(&previous.event) => self
+                .pub_keys
+                .difference(&previous.pub_keys)
+                .cloned()
+                .collect(),
+            _ => HashSet::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn contact_lists_are_read_from_events() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let followed = fixtures::some_keys();
+        let event = fixtures::contact_list_event(
+            &keys,
+            vec![
+                nostr::Tag::PubKey(followed.public_key(), None),
+                nostr::Tag::Generic(
+                    nostr::event::TagKind::P,
+                    vec![String::from("not a pub key")],
+                ),
+                nostr::Tag::Hashtag(String::from("nostr")),
+            ],
+        );
+
+        let contact_list = ContactList::from_event(&event)?;
+        assert_eq!(
+            contact_list.pub_key(),
+            domain::PubKey::new(keys.public_key())
+        );
+        assert_eq!(
+            contact_list.pub_keys(),
+            &HashSet::from([domain::PubKey::new(followed.public_key())])
+        );
+        assert_eq!(contact_list.event(), domain::events::event_info(&event));
+        Ok(())
+    }
+
+    #[test]
+    fn events_of_other_kinds_are_rejected() {
+        let event = nostr::EventBuilder::new_text_note("hello", &[])
+            .to_event(&fixtures::some_keys())
+            .unwrap();
+        assert!(ContactList::from_event(&event).is_err());
+    }
+
+    #[test]
+    fn only_pub_keys_missing_from_the_previous_list_are_new_follows() {
+        let author = domain::PubKey::new(fixtures::some_keys().public_key());
+        let old = domain::PubKey::new(fixtures::some_keys().public_key());
+        let new = domain::PubKey::new(fixtures::some_keys().public_key());
+        let previous = ContactList::new(
+            author.clone(),
+            HashSet::from([old.clone()]),
+            domain::EventInfo::new(event_id(1), nostr::Timestamp::from(1000)),
+        );
+        let current = ContactList::new(
+            author.clone(),
+            HashSet::from([old.clone(), new.clone()]),
+            domain::EventInfo::new(event_id(2), nostr::Timestamp::from(2000)),
+        );
+        let republished = ContactList::new(
+            author.clone(),
+            HashSet::from([old.clone(), new.clone()]),
+            domain::EventInfo::new(event_id(3), nostr::Timestamp::from(3000)),
+        );
+        let stale = ContactList::new(
+            author,
+            HashSet::from([domain::PubKey::new(fixtures::some_keys().public_key())]),
+            domain::EventInfo::new(event_id(4), nostr::Timestamp::from(500)),
+        );
+
+        assert_eq!(current.new_follows(None), HashSet::new());
+        assert_eq!(current.new_follows(Some(&previous)), HashSet::from([new]));
+        assert_eq!(republished.new_follows(Some(&current)), HashSet::new());
+        assert_eq!(stale.new_follows(Some(&current)), HashSet::new());
+    }
+
+    fn event_id(byte: u8) -> nostr::EventId {
+        nostr::EventId::from_slice(&[byte; 32]).unwrap()
+    }
+}
@@ -0,0 +1,106 @@
+pub mod nip44;
+
+use crate::errors::Result;
+use nostr::secp256k1::{SecretKey, XOnlyPublicKey};
+
+const NIP04_IV_SEPARATOR: &str = "?iv=";
+
+// Decrypts content which was encrypted to the owner of the secret key. NIP-44 is preferred but
+// NIP-04 is still accepted as older clients don't support anything else.
+pub fn decrypt(secret_key: &SecretKey, sender: &XOnlyPublicKey, content: &str) -> Result<String> {
+    if content.contains(NIP04_IV_SEPARATOR) {
+        return Ok(nostr::nips::nip04::decrypt(secret_key, sender, content)?);
+    }
+
+    let conversation_key = nip44::ConversationKey::new(secret_key, sender);
+    nip44::decrypt(&conversation_key, content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn decrypt_nip44_vector() -> Result<()> {
+        let secret_key = SecretKey::from_str(
+            "0000000000000000000000000000000000000000000000000000000000000002",
+        )?;
+        let sender = SecretKey::from_str(
+            "0000000000000000000000000000000000000000000000000000000000000001",
+        )?
+        .x_only_public_key(nostr::SECP256K1)
+        .0;
+
+        let content = "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb";
+        assert_eq!(decrypt(&secret_key, &sender, content)?, "a");
+
+        Ok(())
+    }
+
+    #[test]
+    fn decrypt_nip04_vector() -> Result<()> {
+        let secret_key = SecretKey::from_str(
+            "0000000000000000000000000000000000000000000000000000000000000002",
+        )?;
+        let sender = SecretKey::from_str(
+            "0000000000000000000000000000000000000000000000000000000000000001",
+        )?
+        .x_only_public_key(nostr::SECP256K1)
+        .0;
+
+        let content = "qmhbQKDx2eZhxHDln2nH2w==?iv=HW+5sexHiT7z48W4rmf0HQ==";
+        assert_eq!(decrypt(&secret_key, &sender, content)?, "nostr");
+
+        Ok(())
+    }
+
+    #[test]
+    fn nip04_encrypted_content_can_be_decrypted() -> Result<()> {
+        let sender = nostr::Keys::generate();
+        let recipient = nostr::Keys::generate();
+
+        let content = nostr::nips::nip04::encrypt(
+            &sender.secret_key()?,
+            &recipient.public_key(),
+            "some content",
+        )?;
+        assert_eq!(
+            decrypt(&recipient.secret_key()?, &sender.public_key(), &content)?,
+            "some content"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn nip44_encrypted_content_can_be_decrypted() -> Result<()> {
+        let sender = nostr::Keys::generate();
+        let recipient = nostr::Keys::generate();
+
+        let conversation_key =
+            nip44::ConversationKey::new(&sender.secret_key()?, &recipient.public_key());
+        let content = nip44::encrypt(&conversation_key, "some content")?;
+        assert_eq!(
+            decrypt(&recipient.secret_key()?, &sender.public_key(), &content)?,
+            "some content"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn plaintext_content_is_rejected() -> Result<()> {
+        let sender = nostr::Keys::generate();
+        let recipient = nostr::Keys::generate();
+
+        let result = decrypt(
+            &recipient.secret_key()?,
+            &sender.public_key(),
+            "{\"apnsToken\":\"token\",\"relays\":[],\"locale\":\"en\"}",
+        );
+        assert!(result.is_err());
+
+        Ok(())
+    }
+}
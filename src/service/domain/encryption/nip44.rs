@@ -0,0 +1,347 @@
+use crate::errors::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use nostr::secp256k1::{ecdh, Parity, PublicKey, SecretKey, XOnlyPublicKey};
+use sha2::Sha256;
+
+const VERSION: u8 = 2;
+const SALT: &[u8] = b"nip44-v2";
+
+const MIN_PLAINTEXT_SIZE: usize = 1;
+#[cfg(test)]
+const MAX_PLAINTEXT_SIZE: usize = 65535;
+
+const MIN_PAYLOAD_SIZE: usize = 132;
+const MAX_PAYLOAD_SIZE: usize = 87472;
+const MIN_DECODED_PAYLOAD_SIZE: usize = 99;
+const MAX_DECODED_PAYLOAD_SIZE: usize = 65603;
+
+const NONCE_SIZE: usize = 32;
+const MAC_SIZE: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConversationKey([u8; 32]);
+
+impl ConversationKey {
+    pub fn new(secret_key: &SecretKey, public_key: &XOnlyPublicKey) -> Self {
+        let public_key = PublicKey::from_x_only_public_key(*public_key, Parity::Even);
+        let shared_point = ecdh::shared_secret_point(&public_key, secret_key);
+        let (prk, _) = Hkdf::<Sha256>::extract(Some(SALT), &shared_point[..32]);
+        Self(prk.into())
+    }
+
+    #[cfg(test)]
+    pub fn hex(&self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+struct MessageKeys {
+    chacha_key: [u8; 32],
+    chacha_nonce: [u8; 12],
+    hmac_key: [u8; 32],
+}
+
+impl MessageKeys {
+    fn new(conversation_key: &ConversationKey, nonce: &[u8]) -> Result<Self> {
+        let hkdf = Hkdf::<Sha256>::from_prk(&conversation_key.0)
+            .map_err(|_| "invalid conversation key")?;
+
+        let mut keys = [0u8; 76];
+        hkdf.expand(nonce, &mut keys)
+            .map_err(|_| "error expanding the conversation key")?;
+
+        let mut message_keys = MessageKeys {
+            chacha_key: [0u8; 32],
+            chacha_nonce: [0u8; 12],
+            hmac_key: [0u8; 32],
+        };
+        message_keys.chacha_key.copy_from_slice(&keys[0..32]);
+        message_keys.chacha_nonce.copy_from_slice(&keys[32..44]);
+        message_keys.hmac_key.copy_from_slice(&keys[44..76]);
+        Ok(message_keys)
+    }
+
+    fn mac(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Hmac<Sha256>> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.hmac_key).map_err(|_| "invalid hmac key")?;
+        mac.update(nonce);
+        mac.update(ciphertext);
+        Ok(mac)
+    }
+
+    fn apply_keystream(&self, buffer: &mut [u8]) {
+        let mut cipher = ChaCha20::new(&self.chacha_key.into(), &self.chacha_nonce.into());
+        cipher.apply_keystream(buffer);
+    }
+}
+
+#[cfg(test)]
+pub fn encrypt(conversation_key: &ConversationKey, plaintext: &str) -> Result<String> {
+    let nonce: [u8; NONCE_SIZE] = rand::random();
+    encrypt_with_nonce(conversation_key, plaintext, &nonce)
+}
+
+#[cfg(test)]
+pub fn encrypt_with_nonce(
+    conversation_key: &ConversationKey,
+    plaintext: &str,
+    nonce: &[u8; NONCE_SIZE],
+) -> Result<String> {
+    let keys = MessageKeys::new(conversation_key, nonce)?;
+
+    let mut ciphertext = pad(plaintext)?;
+    keys.apply_keystream(&mut ciphertext);
+    let mac = keys.mac(nonce, &ciphertext)?.finalize().into_bytes();
+
+    let mut payload = vec![VERSION];
+    payload.extend_from_slice(nonce);
+    payload.extend_from_slice(&ciphertext);
+    payload.extend_from_slice(&mac);
+    Ok(STANDARD.encode(payload))
+}
+
+pub fn decrypt(conversation_key: &ConversationKey, payload: &str) -> Result<String> {
+    if payload.is_empty() || payload.starts_with('#') {
+        return Err("unknown encryption version".into());
+    }
+
+    if payload.len() < MIN_PAYLOAD_SIZE || payload.len() > MAX_PAYLOAD_SIZE {
+        return Err("invalid payload size".into());
+    }
+
+    let data = STANDARD
+        .decode(payload)
+        .map_err(|_| "invalid base64 payload")?;
+    if data.len() < MIN_DECODED_PAYLOAD_SIZE || data.len() > MAX_DECODED_PAYLOAD_SIZE {
+        return Err("invalid payload size".into());
+    }
+
+    if data[0] != VERSION {
+        return Err("unknown encryption version".into());
+    }
+
+    let nonce = &data[1..1 + NONCE_SIZE];
+    let ciphertext = &data[1 + NONCE_SIZE..data.len() - MAC_SIZE];
+    let mac = &data[data.len() - MAC_SIZE..];
+
+    let keys = MessageKeys::new(conversation_key, nonce)?;
+    keys.mac(nonce, ciphertext)?
+        .verify_slice(mac)
+        .map_err(|_| "invalid mac")?;
+
+    let mut padded = ciphertext.to_vec();
+    keys.apply_keystream(&mut padded);
+    unpad(&padded)
+}
+
+pub fn calc_padded_len(unpadded_len: usize) -> usize {
+    if unpadded_len <= 32 {
+        return 32;
+    }
+
+    let next_power = 1 << (usize::BITS - (unpadded_len - 1).leading_zeros());
+    let chunk = if next_power <= 256 {
+        32
+    } else {
+        next_power / 8
+    };
+    chunk * ((unpadded_len - 1) / chunk + 1)
+}
+
+#[cfg(test)]
+fn pad(plaintext: &str) -> Result<Vec<u8>> {
+    let unpadded = plaintext.as_bytes();
+    if unpadded.len() < MIN_PLAINTEXT_SIZE || unpadded.len() > MAX_PLAINTEXT_SIZE {
+        return Err("invalid plaintext length".into());
+    }
+
+    let mut padded = (unpadded.len() as u16).to_be_bytes().to_vec();
+    padded.extend_from_slice(unpadded);
+    padded.resize(2 + calc_padded_len(unpadded.len()), 0);
+    Ok(padded)
+}
+
+fn unpad(padded: &[u8]) -> Result<String> {
+    if padded.len() < 2 {
+        return Err("invalid padding".into());
+    }
+
+    let unpadded_len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if unpadded_len < MIN_PLAINTEXT_SIZE || padded.len() != 2 + calc_padded_len(unpadded_len) {
+        return Err("invalid padding".into());
+    }
+
+    Ok(String::from_utf8(padded[2..2 + unpadded_len].to_vec())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    // Vectors from https://github.com/paulmillr/nip44/blob/main/nip44.vectors.json
+
+    #[test]
+    fn conversation_key_vectors() -> Result<()> {
+        let vectors = [
+            (
+                "315e59ff51cb9209768cf7da80791ddcaae56ac9775eb25b6dee1234bc5d2268",
+                "c2f9d9948dc8c7c38321e4b85c8558872eafa0641cd269db76848a6073e69133",
+                "3dfef0ce2a4d80a25e7a328accf73448ef67096f65f79588e358d9a0eb9013f1",
+            ),
+            (
+                "a1e37752c9fdc1273be53f68c5f74be7c8905728e8de75800b94262f9497c86e",
+                "03bb7947065dde12ba991ea045132581d0954f042c84e06d8c00066e23c1a800",
+                "4d14f36e81b8452128da64fe6f1eae873baae2f444b02c950b90e43553f2178b",
+            ),
+        ];
+
+        for (sec1, pub2, conversation_key) in vectors {
+            let secret_key = SecretKey::from_str(sec1)?;
+            let public_key = XOnlyPublicKey::from_str(pub2)?;
+            assert_eq!(
+                ConversationKey::new(&secret_key, &public_key).hex(),
+                conversation_key
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn calc_padded_len_vectors() {
+        let vectors = [
+            (16, 32),
+            (32, 32),
+            (33, 64),
+            (37, 64),
+            (45, 64),
+            (49, 64),
+            (64, 64),
+            (65, 96),
+            (100, 128),
+            (111, 128),
+            (200, 224),
+            (250, 256),
+            (320, 320),
+            (383, 384),
+            (384, 384),
+            (400, 448),
+            (500, 512),
+            (512, 512),
+            (515, 640),
+            (700, 768),
+            (800, 896),
+            (900, 1024),
+            (1020, 1024),
+            (65536, 65536),
+        ];
+
+        for (unpadded_len, padded_len) in vectors {
+            assert_eq!(calc_padded_len(unpadded_len), padded_len, "{unpadded_len}");
+        }
+    }
+
+    #[test]
+    fn encrypt_decrypt_vectors() -> Result<()> {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "a",
+                "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb",
+            ),
+            (
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d",
+                "f00000000000000000000000000000f00000000000000000000000000000000f",
+                "🍕🫃",
+                "AvAAAAAAAAAAAAAAAAAAAPAAAAAAAAAAAAAAAAAAAAAPSKSK6is9ngkX2+cSq85Th16oRTISAOfhStnixqZziKMDvB0QQzgFZdjLTPicCJaV8nDITO+QfaQ61+KbWQIOO2Yj",
+            ),
+        ];
+
+        for (sec1, sec2, conversation_key, nonce, plaintext, payload) in vectors {
+            let secret_key_1 = SecretKey::from_str(sec1)?;
+            let secret_key_2 = SecretKey::from_str(sec2)?;
+            let public_key_2 = secret_key_2.x_only_public_key(nostr::SECP256K1).0;
+
+            let key = ConversationKey::new(&secret_key_1, &public_key_2);
+            assert_eq!(key.hex(), conversation_key);
+
+            let mut nonce_bytes = [0u8; NONCE_SIZE];
+            nonce_bytes.copy_from_slice(&hex::decode(nonce)?);
+            assert_eq!(encrypt_with_nonce(&key, plaintext, &nonce_bytes)?, payload);
+            assert_eq!(decrypt(&key, payload)?, plaintext);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_messages_can_be_decrypted_by_the_recipient() -> Result<()> {
+        let sender = nostr::Keys::generate();
+        let recipient = nostr::Keys::generate();
+
+        let sender_key = ConversationKey::new(&sender.secret_key()?, &recipient.public_key());
+        let recipient_key = ConversationKey::new(&recipient.secret_key()?, &sender.public_key());
+        assert_eq!(sender_key, recipient_key);
+
+        let payload = encrypt(&sender_key, "some message")?;
+        assert_eq!(decrypt(&recipient_key, &payload)?, "some message");
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_payloads_are_rejected() -> Result<()> {
+        let key = ConversationKey::new(
+            &SecretKey::from_str(
+                "0000000000000000000000000000000000000000000000000000000000000001",
+            )?,
+            &SecretKey::from_str(
+                "0000000000000000000000000000000000000000000000000000000000000002",
+            )?
+            .x_only_public_key(nostr::SECP256K1)
+            .0,
+        );
+        let payload = "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb";
+
+        let mut tampered_mac = STANDARD.decode(payload)?;
+        let last = tampered_mac.len() - 1;
+        tampered_mac[last] ^= 1;
+
+        let mut unknown_version = STANDARD.decode(payload)?;
+        unknown_version[0] = 1;
+
+        let cases = [
+            (String::from(""), "unknown encryption version"),
+            (format!("#{}", &payload[1..]), "unknown encryption version"),
+            (
+                String::from("{\"apnsToken\":\"token\"}"),
+                "invalid payload size",
+            ),
+            (STANDARD.encode(tampered_mac), "invalid mac"),
+            (
+                STANDARD.encode(unknown_version),
+                "unknown encryption version",
+            ),
+        ];
+
+        for (payload, expected_error) in cases {
+            match decrypt(&key, &payload) {
+                Ok(_) => panic!("expected an error for '{payload}'"),
+                Err(err) => assert_eq!(err.to_string(), expected_error),
+            }
+        }
+
+        Ok(())
+    }
+}
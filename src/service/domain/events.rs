@@ -1,9 +1,961 @@
+use crate::errors::Result;
+use crate::service::domain;
+use crate::service::domain::notifications;
+use crate::service::domain::quiet_hours;
+use nostr::hashes::Hash;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
+// Kind of the events which clients send to register with the service unless the service is
+// configured to use a different one. Registration kinds are ephemeral as defined by NIP-01
+// (20000 <= kind < 30000) so that relays don't store push tokens if a client publishes a
+// registration by mistake. Neither this kind nor the unregistration kind is listed in the event
+// kinds table of the NIPs repository (https://github.com/nostr-protocol/nips#event-kinds) and both
+// are outside of the range of NIP-90 job requests and results (5000-6999).
+pub const DEFAULT_REGISTRATION_KIND: u64 = 26666;
+
+// Kind of the events which clients send to remove a registration, e.g. when the user logs out.
+pub const DEFAULT_UNREGISTRATION_KIND: u64 = 26667;
+
+// Number of trailing characters of the push token which are included in registration status
+// events so that clients can recognize their devices.
+pub const TOKEN_SUFFIX_LENGTH: usize = 4;
+
+// How far the creation time of registration and unregistration events can be from the current
+// time unless the service is configured to use a different value.
+pub const DEFAULT_FRESHNESS_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+// Name of the tag which identifies the deployment of the service that the event is addressed to.
+pub const SERVICE_TAG: &str = "service";
+
+// How far the creation time of NIP-42 authentication events can be from the current time.
+pub const MAX_AUTH_TIME_DIFFERENCE: Duration = Duration::from_secs(10 * 60);
+
+// Kind of the NIP-98 events which authenticate HTTP requests.
+pub const HTTP_AUTH_KIND: u64 = 27235;
+
+// How far the creation time of NIP-98 events can be from the current time.
+pub const MAX_HTTP_AUTH_TIME_DIFFERENCE: Duration = Duration::from_secs(60);
+
+// Clients identify the device with a provider and a token. Clients which were written before other
+// providers were supported send only an APNs token.
 #[derive(Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct RegistrationEventContent {
-    pub apns_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apns_token: Option<String>,
+    pub relays: Vec<String>,
+    pub locale: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferences: Option<NotificationPreferencesContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHoursContent>,
+}
+
+impl RegistrationEventContent {
+    // The event is the event which authenticated the registration, it isn't necessarily the event
+    // which carried the content.
+    pub fn registration(self, event: &nostr::Event) -> Result<domain::Registration> {
+        let relays = self
+            .relays
+            .into_iter()
+            .map(domain::RelayAddress::new)
+            .collect::<Result<Vec<_>>>()?;
+
+        domain::Registration::new(
+            domain::PubKey::new(event.pubkey),
+            push_token(self.provider, self.token, self.apns_token)?,
+            relays,
+            domain::Locale::new(self.locale)?,
+            self.preferences.unwrap_or_default().preferences(),
+            self.quiet_hours
+                .map(QuietHoursContent::quiet_hours)
+                .transpose()?,
+            event_info(event),
+        )
+    }
+}
+
+// Opt-in flags for every category of notifications. Flags which the client doesn't send keep
+// their default values so that clients don't have to be updated when categories are added.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NotificationPreferencesContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mentions: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replies: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direct_messages: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zaps: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reactions: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reposts: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_followers: Option<bool>,
+}
+
+impl NotificationPreferencesContent {
+    pub fn new(preferences: &notifications::Preferences) -> Self {
+        let flag = |category| Some(preferences.is_enabled(category));
+        NotificationPreferencesContent {
+            mentions: flag(notifications::Category::Mention),
+            replies: flag(notifications::Category::Reply),
+            direct_messages: flag(notifications::Category::DirectMessage),
+            zaps: flag(notifications::Category::Zap),
+            reactions: flag(notifications::Category::Reaction),
+            reposts: flag(notifications::Category::Repost),
+            new_followers: flag(notifications::Category::NewFollower),
+        }
+    }
+
+    pub fn preferences(self) -> notifications::Preferences {
+        let mut preferences = notifications::Preferences::default();
+        for (category, enabled) in [
+            (notifications::Category::Mention, self.mentions),
+            (notifications::Category::Reply, self.replies),
+            (notifications::Category::DirectMessage, self.direct_messages),
+            (notifications::Category::Zap, self.zaps),
+            (notifications::Category::Reaction, self.reactions),
+            (notifications::Category::Repost, self.reposts),
+            (notifications::Category::NewFollower, self.new_followers),
+        ] {
+            if let Some(enabled) = enabled {
+                preferences.set(category, enabled);
+            }
+        }
+        preferences
+    }
+}
+
+// Quiet hours in an IANA time zone, e.g. {"timeZone": "Europe/Warsaw", "windows": [{"start":
+// "22:00", "end": "07:00"}], "mode": "digest"}. Notifications are held and sent as a digest unless
+// the client asks for them to be dropped.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct QuietHoursContent {
+    pub time_zone: String,
+    pub windows: Vec<QuietWindowContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct QuietWindowContent {
+    pub start: String,
+    pub end: String,
+}
+
+impl QuietHoursContent {
+    pub fn new(quiet_hours: &quiet_hours::QuietHours) -> Self {
+        QuietHoursContent {
+            time_zone: quiet_hours.time_zone().to_string(),
+            windows: quiet_hours
+                .windows()
+                .iter()
+                .map(|window| QuietWindowContent {
+                    start: quiet_hours::format_time(window.start()),
+                    end: quiet_hours::format_time(window.end()),
+                })
+                .collect(),
+            mode: Some(quiet_hours.mode().as_str().to_string()),
+        }
+    }
+
+    pub fn quiet_hours(self) -> Result<quiet_hours::QuietHours> {
+        let windows = self
+            .windows
+            .iter()
+            .map(|window| quiet_hours::Window::parse(&window.start, &window.end))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mode = match self.mode {
+            Some(mode) => quiet_hours::Mode::from_str(&mode)?,
+            None => quiet_hours::Mode::Digest,
+        };
+
+        quiet_hours::QuietHours::new(&self.time_zone, windows, mode)
+    }
+}
+
+// Content of the events which the service sends to describe a stored registration. Only the end
+// of the push token is included as the token is a secret which lets anyone send notifications to
+// the device.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RegistrationStatusContent {
+    pub provider: String,
+    pub token_suffix: String,
     pub relays: Vec<String>,
     pub locale: String,
+    pub preferences: NotificationPreferencesContent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHoursContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<u64>,
+}
+
+impl RegistrationStatusContent {
+    pub fn new(
+        push_token: &domain::PushToken,
+        relays: &[domain::RelayAddress],
+        locale: &domain::Locale,
+        preferences: &notifications::Preferences,
+        quiet_hours: Option<&quiet_hours::QuietHours>,
+        updated_at: Option<nostr::Timestamp>,
+    ) -> Self {
+        let token: Vec<char> = push_token.as_ref().chars().collect();
+        let token_suffix = token[token.len().saturating_sub(TOKEN_SUFFIX_LENGTH)..]
+            .iter()
+            .collect();
+
+        RegistrationStatusContent {
+            provider: push_token.provider().as_str().to_string(),
+            token_suffix,
+            relays: relays
+                .iter()
+                .map(|relay| relay.as_ref().to_string())
+                .collect(),
+            locale: locale.as_ref().to_string(),
+            preferences: NotificationPreferencesContent::new(preferences),
+            quiet_hours: quiet_hours.map(QuietHoursContent::new),
+            updated_at: updated_at.map(|v| v.as_u64()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct UnregistrationEventContent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apns_token: Option<String>,
+}
+
+impl UnregistrationEventContent {
+    pub fn push_token(self) -> Result<domain::PushToken> {
+        push_token(self.provider, self.token, self.apns_token)
+    }
+}
+
+fn push_token(
+    provider: Option<String>,
+    token: Option<String>,
+    apns_token: Option<String>,
+) -> Result<domain::PushToken> {
+    match (provider, token, apns_token) {
+        (None, None, Some(apns_token)) => {
+            domain::PushToken::new(domain::PushProvider::ApnsProduction, apns_token)
+        }
+        (Some(provider), Some(token), None) => {
+            domain::PushToken::new(domain::PushProvider::from_str(&provider)?, token)
+        }
+        (_, _, Some(_)) => Err("apnsToken can't be combined with provider and token".into()),
+        (None, Some(_), None) => Err("missing provider".into()),
+        (_, None, None) => Err("missing token".into()),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerificationError {
+    InvalidId,
+    InvalidSignature,
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerificationError::InvalidId => write!(f, "invalid event id"),
+            VerificationError::InvalidSignature => write!(f, "invalid signature"),
+        }
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+// Event received from a client together with the JSON it was deserialized from. nostr::Event
+// normalizes some tags (e.g. relay URLs) when deserializing them so the id has to be computed
+// from the original JSON.
+pub struct ReceivedEvent {
+    event: nostr::Event,
+    value: serde_json::Value,
+}
+
+impl ReceivedEvent {
+    pub fn new(value: serde_json::Value) -> Result<Self> {
+        let event: nostr::Event = serde_json::from_value(value.clone())?;
+        Ok(Self { event, value })
+    }
+
+    pub fn event(&self) -> &nostr::Event {
+        &self.event
+    }
+
+    // nostr::Event::verify checks the signature against a freshly computed id but never compares
+    // that id with the one the client sent so both checks have to be done here.
+    pub fn verify(&self) -> std::result::Result<&nostr::Event, VerificationError> {
+        let serialized = serde_json::json!([
+            0,
+            self.value["pubkey"],
+            self.value["created_at"],
+            self.value["kind"],
+            self.value["tags"],
+            self.value["content"],
+        ])
+        .to_string();
+        let hash = nostr::hashes::sha256::Hash::hash(serialized.as_bytes());
+
+        let id = nostr::EventId::from(hash);
+        if id != self.event.id {
+            return Err(VerificationError::InvalidId);
+        }
+
+        let message = nostr::secp256k1::Message::from_slice(id.as_bytes())
+            .map_err(|_| VerificationError::InvalidId)?;
+        nostr::SECP256K1
+            .verify_schnorr(&self.event.sig, &message, &self.event.pubkey)
+            .map_err(|_| VerificationError::InvalidSignature)?;
+
+        Ok(&self.event)
+    }
+}
+
+pub fn event_info(event: &nostr::Event) -> domain::EventInfo {
+    domain::EventInfo::new(event.id, event.created_at)
+}
+
+// Checks that the event was created at most max_time_difference before or after now.
+pub fn validate_created_at(
+    event: &nostr::Event,
+    max_time_difference: Duration,
+    now: nostr::Timestamp,
+) -> Result<()> {
+    let time_difference = now.as_i64().abs_diff(event.created_at.as_i64());
+    if time_difference > max_time_difference.as_secs() {
+        return Err("event was created too far from the current time".into());
+    }
+    Ok(())
+}
+
+// Checks that the event carries exactly one service tag naming this service and no other tags.
+pub fn validate_tags(event: &nostr::Event, service: &str) -> Result<()> {
+    let mut services = vec![];
+    for tag in &event.tags {
+        let tag = tag.as_vec();
+        match tag.first().map(String::as_str) {
+            Some(SERVICE_TAG) => {
+                if tag.len() != 2 {
+                    return Err("malformed service tag".into());
+                }
+                services.push(tag[1].clone());
+            }
+            _ => return Err(format!("unexpected tag: {tag:?}").into()),
+        }
+    }
+
+    match services.as_slice() {
+        [] => Err("missing service tag".into()),
+        [tagged_service] if tagged_service == service => Ok(()),
+        [tagged_service] => {
+            Err(format!("event is addressed to a different service: '{tagged_service}'").into())
+        }
+        _ => Err("duplicate service tag".into()),
+    }
+}
+
+// Checks that the event is a NIP-42 authentication event created recently in response to the
+// challenge which was sent to the client by the given relay.
+pub fn validate_auth_event(
+    event: &nostr::Event,
+    challenge: &str,
+    relay: &nostr::Url,
+    now: nostr::Timestamp,
+) -> Result<()> {
+    if event.kind != nostr::Kind::Authentication {
+        return Err(format!("unexpected event kind: {}", event.kind.as_u64()).into());
+    }
+
+    validate_created_at(event, MAX_AUTH_TIME_DIFFERENCE, now)?;
+
+    let mut tagged_challenge = None;
+    let mut tagged_relay = None;
+    for tag in &event.tags {
+        let tag = tag.as_vec();
+        match (tag.first().map(String::as_str), tag.get(1)) {
+            (Some("challenge"), Some(value)) => tagged_challenge = Some(value.clone()),
+            (Some("relay"), Some(value)) => tagged_relay = Some(value.clone()),
+            _ => {}
+        }
+    }
+
+    match tagged_challenge {
+        Some(tagged_challenge) if tagged_challenge == challenge => {}
+        Some(_) => return Err("challenge doesn't match".into()),
+        None => return Err("missing challenge tag".into()),
+    }
+
+    match tagged_relay {
+        Some(tagged_relay) => {
+            let tagged_relay = nostr::Url::parse(&tagged_relay)
+                .map_err(|err| format!("invalid relay tag: {err}"))?;
+            if &tagged_relay != relay {
+                return Err(
+                    format!("event is addressed to a different relay: '{tagged_relay}'").into(),
+                );
+            }
+        }
+        None => return Err("missing relay tag".into()),
+    }
+
+    Ok(())
+}
+
+// Checks that the event is a NIP-98 event created recently to authenticate a request with the
+// given method sent to the given URL. Requests with a body have to tag the hash of the body so that
+// the event can't be reused with a different one.
+pub fn validate_http_auth_event(
+    event: &nostr::Event,
+    url: &nostr::Url,
+    method: &str,
+    body: &[u8],
+    now: nostr::Timestamp,
+) -> Result<()> {
+    if event.kind.as_u64() != HTTP_AUTH_KIND {
+        return Err(format!("unexpected event kind: {}", event.kind.as_u64()).into());
+    }
+
+    validate_created_at(event, MAX_HTTP_AUTH_TIME_DIFFERENCE, now)?;
+
+    let mut tagged_url = None;
+    let mut tagged_method = None;
+    let mut tagged_payload = None;
+    for tag in &event.tags {
+        let tag = tag.as_vec();
+        match (tag.first().map(String::as_str), tag.get(1)) {
+            (Some("u"), Some(value)) => tagged_url = Some(value.clone()),
+            (Some("method"), Some(value)) => tagged_method = Some(value.clone()),
+            (Some("payload"), Some(value)) => tagged_payload = Some(value.clone()),
+            _ => {}
+        }
+    }
+
+    match tagged_url {
+        Some(tagged_url) => {
+            let tagged_url =
+                nostr::Url::parse(&tagged_url).map_err(|err| format!("invalid u tag: {err}"))?;
+            if &tagged_url != url {
+                return Err(
+                    format!("event is addressed to a different url: '{tagged_url}'").into(),
+                );
+            }
+        }
+        None => return Err("missing u tag".into()),
+    }
+
+    match tagged_method {
+        Some(tagged_method) if tagged_method.eq_ignore_ascii_case(method) => {}
+        Some(tagged_method) => {
+            return Err(
+                format!("event is addressed to a different method: '{tagged_method}'").into(),
+            )
+        }
+        None => return Err("missing method tag".into()),
+    }
+
+    match tagged_payload {
+        Some(tagged_payload) => {
+            let payload = nostr::hashes::sha256::Hash::hash(body).to_string();
+            if !tagged_payload.eq_ignore_ascii_case(&payload) {
+                return Err("payload doesn't match the body".into());
+            }
+        }
+        None if !body.is_empty() => return Err("missing payload tag".into()),
+        None => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn content_with_unknown_fields_is_rejected() {
+        let content = r#"{"apnsToken":"token","relays":[],"locale":"en","extra":1}"#;
+        let result = serde_json::from_str::<RegistrationEventContent>(content);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn push_tokens_are_read_from_the_content() -> Result<()> {
+        let cases = vec![
+            (
+                format!(r#"{{"apnsToken":"{}"}}"#, fixtures::APNS_TOKEN),
+                domain::PushProvider::ApnsProduction,
+                fixtures::APNS_TOKEN,
+            ),
+            (
+                format!(
+                    r#"{{"provider":"apns-sandbox","token":"{}"}}"#,
+                    fixtures::APNS_TOKEN
+                ),
+                domain::PushProvider::ApnsSandbox,
+                fixtures::APNS_TOKEN,
+            ),
+            (
+                String::from(r#"{"provider":"fcm","token":"some-fcm-token"}"#),
+                domain::PushProvider::Fcm,
+                "some-fcm-token",
+            ),
+            (
+                String::from(r#"{"provider":"unifiedpush","token":"https://push.example.com/up"}"#),
+                domain::PushProvider::UnifiedPush,
+                "https://push.example.com/up",
+            ),
+        ];
+
+        for (content, provider, token) in cases {
+            let content: UnregistrationEventContent = serde_json::from_str(&content)?;
+            let push_token = content.push_token()?;
+            assert_eq!(push_token.provider(), provider);
+            assert_eq!(push_token.as_ref(), token);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn incomplete_push_tokens_are_rejected() {
+        let cases = vec![
+            (r#"{}"#, "missing token"),
+            (r#"{"provider":"fcm"}"#, "missing token"),
+            (r#"{"token":"some-token"}"#, "missing provider"),
+            (
+                r#"{"provider":"fcm","token":"some-token","apnsToken":"some-token"}"#,
+                "apnsToken can't be combined with provider and token",
+            ),
+            (
+                r#"{"provider":"gcm","token":"some-token"}"#,
+                "unknown push provider: 'gcm'",
+            ),
+        ];
+
+        for (content, expected_error) in cases {
+            let content: UnregistrationEventContent = serde_json::from_str(content).unwrap();
+            match content.push_token() {
+                Ok(_) => panic!("expected an error: {expected_error}"),
+                Err(err) => assert_eq!(err.to_string(), expected_error),
+            }
+        }
+    }
+
+    #[test]
+    fn unregistration_content_with_unknown_fields_is_rejected() {
+        let content = r#"{"apnsToken":"token","relays":[]}"#;
+        let result = serde_json::from_str::<UnregistrationEventContent>(content);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn validate_tags_accepts_correct_events() -> Result<()> {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+        validate_tags(&event, fixtures::SERVICE)
+    }
+
+    #[test]
+    fn validate_tags_rejects_events_without_service_tag() {
+        let event =
+            fixtures::registration_event(&fixtures::some_keys(), registration_kind(), vec![]);
+        assert_validation_error(&event, "missing service tag");
+    }
+
+    #[test]
+    fn validate_tags_rejects_events_addressed_to_other_services() {
+        let event = fixtures::registration_event(
+            &fixtures::some_keys(),
+            registration_kind(),
+            vec![fixtures::service_tag("other-service")],
+        );
+        assert_validation_error(
+            &event,
+            "event is addressed to a different service: 'other-service'",
+        );
+    }
+
+    #[test]
+    fn validate_tags_rejects_duplicate_service_tags() {
+        let event = fixtures::registration_event(
+            &fixtures::some_keys(),
+            registration_kind(),
+            vec![
+                fixtures::service_tag(fixtures::SERVICE),
+                fixtures::service_tag(fixtures::SERVICE),
+            ],
+        );
+        assert_validation_error(&event, "duplicate service tag");
+    }
+
+    #[test]
+    fn validate_tags_rejects_unexpected_tags() {
+        let event = fixtures::registration_event(
+            &fixtures::some_keys(),
+            registration_kind(),
+            vec![
+                fixtures::service_tag(fixtures::SERVICE),
+                nostr::Tag::Hashtag(String::from("nostr")),
+            ],
+        );
+        assert_validation_error(&event, "unexpected tag: [\"t\", \"nostr\"]");
+    }
+
+    fn assert_validation_error(event: &nostr::Event, expected: &str) {
+        match validate_tags(event, fixtures::SERVICE) {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => assert_eq!(err.to_string(), expected),
+        }
+    }
+
+    fn registration_kind() -> nostr::Kind {
+        nostr::Kind::from(DEFAULT_REGISTRATION_KIND)
+    }
+
+    #[test]
+    fn verify_accepts_correctly_signed_events() {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+        assert_eq!(received(&event).verify(), Ok(&event));
+    }
+
+    #[test]
+    fn verify_uses_tags_as_they_were_sent() {
+        let event = fixtures::auth_event(
+            &fixtures::some_keys(),
+            "challenge",
+            "wss://relay.example.com",
+        );
+        let verified = received(&event).verify().map(|event| event.id);
+        assert_eq!(verified, Ok(event.id));
+    }
+
+    #[test]
+    fn verify_rejects_events_with_tampered_content() {
+        let event =
+            fixtures::tampered_event(fixtures::some_registration_event(&fixtures::some_keys()));
+        assert_eq!(received(&event).verify(), Err(VerificationError::InvalidId));
+    }
+
+    #[test]
+    fn verify_rejects_events_with_a_different_id() {
+        let mut event = fixtures::some_registration_event(&fixtures::some_keys());
+        event.id = fixtures::some_registration_event(&fixtures::some_keys()).id;
+        assert_eq!(received(&event).verify(), Err(VerificationError::InvalidId));
+    }
+
+    #[test]
+    fn verify_rejects_events_forged_for_another_pub_key() {
+        let victim = fixtures::some_keys();
+        let event = fixtures::forged_event(
+            fixtures::some_registration_event(&fixtures::some_keys()),
+            victim.public_key(),
+        );
+        assert_eq!(
+            received(&event).verify(),
+            Err(VerificationError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn validate_auth_event_accepts_correct_events() -> Result<()> {
+        let event = fixtures::auth_event(&fixtures::some_keys(), "challenge", fixtures::RELAY);
+        validate_auth_event(&event, "challenge", &relay(), nostr::Timestamp::now())
+    }
+
+    #[test]
+    fn validate_auth_event_accepts_relay_urls_which_differ_only_in_formatting() -> Result<()> {
+        let event = fixtures::auth_event(
+            &fixtures::some_keys(),
+            "challenge",
+            "wss://Relay.Example.com",
+        );
+        validate_auth_event(&event, "challenge", &relay(), nostr::Timestamp::now())
+    }
+
+    #[test]
+    fn validate_auth_event_rejects_incorrect_events() {
+        let keys = fixtures::some_keys();
+        let now = nostr::Timestamp::now();
+
+        let cases = vec![
+            (
+                fixtures::some_registration_event(&keys),
+                now,
+                format!("unexpected event kind: {}", DEFAULT_REGISTRATION_KIND),
+            ),
+            (
+                fixtures::auth_event(&keys, "other-challenge", fixtures::RELAY),
+                now,
+                String::from("challenge doesn't match"),
+            ),
+            (
+                fixtures::auth_event(&keys, "challenge", "wss://other.example.com"),
+                now,
+                String::from("event is addressed to a different relay: 'wss://other.example.com/'"),
+            ),
+            (
+                fixtures::auth_event(&keys, "challenge", fixtures::RELAY),
+                nostr::Timestamp::from(now.as_u64() + MAX_AUTH_TIME_DIFFERENCE.as_secs() + 60),
+                String::from("event was created too far from the current time"),
+            ),
+            (
+                fixtures::auth_event(&keys, "challenge", fixtures::RELAY),
+                nostr::Timestamp::from(now.as_u64() - MAX_AUTH_TIME_DIFFERENCE.as_secs() - 60),
+                String::from("event was created too far from the current time"),
+            ),
+        ];
+
+        for (event, now, expected_error) in cases {
+            match validate_auth_event(&event, "challenge", &relay(), now) {
+                Ok(_) => panic!("expected an error: {expected_error}"),
+                Err(err) => assert_eq!(err.to_string(), expected_error),
+            }
+        }
+    }
+
+    #[test]
+    fn registration_content_is_converted_to_a_registration() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let event = fixtures::some_registration_event(&keys);
+        let content = fixtures::some_registration_event_content();
+        let push_token = fixtures::some_push_token();
+
+        let registration = content.registration(&event)?;
+        assert_eq!(
+            registration.pub_key(),
+            domain::PubKey::new(keys.public_key())
+        );
+        assert_eq!(registration.push_token(), push_token);
+        assert_eq!(
+            registration.event(),
+            domain::EventInfo::new(event.id, event.created_at)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn missing_preferences_fall_back_to_defaults() -> Result<()> {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+
+        let mut content = fixtures::some_registration_event_content();
+        content.preferences = None;
+        assert_eq!(
+            content.registration(&event)?.preferences(),
+            notifications::Preferences::default()
+        );
+
+        let content: NotificationPreferencesContent =
+            serde_json::from_str(r#"{"mentions":false,"reactions":true}"#)?;
+        let preferences = content.preferences();
+        assert!(!preferences.is_enabled(notifications::Category::Mention));
+        assert!(preferences.is_enabled(notifications::Category::Reaction));
+        assert!(preferences.is_enabled(notifications::Category::Reply));
+        assert!(!preferences.is_enabled(notifications::Category::Repost));
+        Ok(())
+    }
+
+    #[test]
+    fn quiet_hours_are_read_from_the_content() -> Result<()> {
+        let content: QuietHoursContent = serde_json::from_str(
+            r#"{"timeZone":"Europe/Warsaw","windows":[{"start":"22:00","end":"07:00"}]}"#,
+        )?;
+        let quiet_hours = content.quiet_hours()?;
+        assert_eq!(quiet_hours.time_zone(), "Europe/Warsaw");
+        assert_eq!(
+            quiet_hours.windows(),
+            vec![quiet_hours::Window::parse("22:00", "07:00")?]
+        );
+        assert_eq!(quiet_hours.mode(), quiet_hours::Mode::Digest);
+
+        let content: QuietHoursContent = serde_json::from_str(
+            r#"{"timeZone":"UTC","windows":[{"start":"13:00","end":"14:00"}],"mode":"drop"}"#,
+        )?;
+        assert_eq!(content.quiet_hours()?.mode(), quiet_hours::Mode::Drop);
+
+        let content: QuietHoursContent = serde_json::from_str(
+            r#"{"timeZone":"UTC","windows":[{"start":"13:00","end":"14:00"}],"mode":"later"}"#,
+        )?;
+        match content.quiet_hours() {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => assert_eq!(err.to_string(), "unknown quiet hours mode: 'later'"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn registration_status_does_not_include_the_whole_token() -> Result<()> {
+        let push_token = fixtures::some_push_token();
+        let content = RegistrationStatusContent::new(
+            &push_token,
+            &[domain::RelayAddress::new(String::from(
+                "wss://relay.example.com",
+            ))?],
+            &fixtures::some_locale(),
+            &notifications::Preferences::default(),
+            None,
+            Some(nostr::Timestamp::from(100)),
+        );
+
+        let json = serde_json::to_string(&content)?;
+        assert!(!json.contains(push_token.as_ref()));
+        assert_eq!(
+            content.token_suffix,
+            push_token.as_ref()[push_token.as_ref().len() - TOKEN_SUFFIX_LENGTH..]
+        );
+        assert_eq!(
+            content.relays,
+            vec![String::from("wss://relay.example.com")]
+        );
+        assert_eq!(content.preferences.reactions, Some(false));
+        assert_eq!(content.updated_at, Some(100));
+        Ok(())
+    }
+
+    #[test]
+    fn preferences_with_unknown_categories_are_rejected() {
+        let content = r#"{"likes":true}"#;
+        let result = serde_json::from_str::<NotificationPreferencesContent>(content);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn validate_created_at_accepts_events_within_the_time_difference() -> Result<()> {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+        let max = Duration::from_secs(60);
+
+        validate_created_at(&event, max, event.created_at)?;
+        validate_created_at(
+            &event,
+            max,
+            nostr::Timestamp::from(event.created_at.as_u64() + 60),
+        )?;
+        validate_created_at(
+            &event,
+            max,
+            nostr::Timestamp::from(event.created_at.as_u64() - 60),
+        )
+    }
+
+    #[test]
+    fn validate_created_at_rejects_events_outside_of_the_time_difference() {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+        let max = Duration::from_secs(60);
+
+        for now in [
+            event.created_at.as_u64() + 61,
+            event.created_at.as_u64() - 61,
+        ] {
+            match validate_created_at(&event, max, nostr::Timestamp::from(now)) {
+                Ok(_) => panic!("expected an error"),
+                Err(err) => assert_eq!(
+                    err.to_string(),
+                    "event was created too far from the current time"
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn validate_http_auth_event_accepts_correct_events() -> Result<()> {
+        let body = b"{}";
+        let cases = vec![
+            (
+                fixtures::http_auth_event(&fixtures::some_keys(), HTTP_URL, "POST", None),
+                &b""[..],
+            ),
+            (
+                fixtures::http_auth_event(&fixtures::some_keys(), HTTP_URL, "POST", Some(b"")),
+                &b""[..],
+            ),
+            (
+                fixtures::http_auth_event(&fixtures::some_keys(), HTTP_URL, "post", Some(body)),
+                &body[..],
+            ),
+        ];
+
+        for (event, body) in cases {
+            validate_http_auth_event(&event, &http_url(), "POST", body, nostr::Timestamp::now())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn validate_http_auth_event_rejects_incorrect_events() {
+        let keys = fixtures::some_keys();
+        let now = nostr::Timestamp::now();
+
+        let cases = vec![
+            (
+                fixtures::auth_event(&keys, "challenge", fixtures::RELAY),
+                now,
+                String::from("unexpected event kind: 22242"),
+            ),
+            (
+                fixtures::http_auth_event(&keys, "https://other.example.com/v1/registrations", "POST", None),
+                now,
+                String::from("event is addressed to a different url: 'https://other.example.com/v1/registrations'"),
+            ),
+            (
+                fixtures::http_auth_event(&keys, HTTP_URL, "DELETE", None),
+                now,
+                String::from("event is addressed to a different method: 'DELETE'"),
+            ),
+            (
+                fixtures::http_auth_event(&keys, HTTP_URL, "POST", Some(b"other body")),
+                now,
+                String::from("payload doesn't match the body"),
+            ),
+            (
+                fixtures::http_auth_event(&keys, HTTP_URL, "POST", None),
+                now,
+                String::from("missing payload tag"),
+            ),
+            (
+                fixtures::http_auth_event(&keys, HTTP_URL, "POST", None),
+                nostr::Timestamp::from(now.as_u64() + MAX_HTTP_AUTH_TIME_DIFFERENCE.as_secs() + 10),
+                String::from("event was created too far from the current time"),
+            ),
+        ];
+
+        for (event, now, expected_error) in cases {
+            match validate_http_auth_event(&event, &http_url(), "POST", b"{}", now) {
+                Ok(_) => panic!("expected an error: {expected_error}"),
+                Err(err) => assert_eq!(err.to_string(), expected_error),
+            }
+        }
+    }
+
+    const HTTP_URL: &str = "https://notifications.example.com/v1/registrations";
+
+    fn http_url() -> nostr::Url {
+        nostr::Url::parse(HTTP_URL).unwrap()
+    }
+
+    fn relay() -> nostr::Url {
+        nostr::Url::parse(fixtures::RELAY).unwrap()
+    }
+
+    fn received(event: &nostr::Event) -> ReceivedEvent {
+        ReceivedEvent::new(serde_json::to_value(event).unwrap()).unwrap()
+    }
 }
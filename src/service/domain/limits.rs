@@ -0,0 +1,104 @@
+use crate::errors::Result;
+
+// Limits which keep the number of relays that the service connects to and the amount of stored
+// data under control. Content size is measured in bytes of the decrypted registration content.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegistrationLimits {
+    max_relays_per_registration: usize,
+    max_relays: usize,
+    max_registrations: usize,
+    max_content_size: usize,
+}
+
+impl RegistrationLimits {
+    pub fn new(
+        max_relays_per_registration: usize,
+        max_relays: usize,
+        max_registrations: usize,
+        max_content_size: usize,
+    ) -> Result<RegistrationLimits> {
+        if max_relays_per_registration == 0 {
+            return Err("max relays per registration must be greater than zero".into());
+        }
+
+        if max_relays < max_relays_per_registration {
+            return Err("max relays can't be lower than max relays per registration".into());
+        }
+
+        if max_registrations == 0 {
+            return Err("max registrations must be greater than zero".into());
+        }
+
+        if max_content_size == 0 {
+            return Err("max content size must be greater than zero".into());
+        }
+
+        Ok(RegistrationLimits {
+            max_relays_per_registration,
+            max_relays,
+            max_registrations,
+            max_content_size,
+        })
+    }
+
+    pub fn max_relays_per_registration(&self) -> usize {
+        self.max_relays_per_registration
+    }
+
+    pub fn max_relays(&self) -> usize {
+        self.max_relays
+    }
+
+    pub fn max_registrations(&self) -> usize {
+        self.max_registrations
+    }
+
+    pub fn max_content_size(&self) -> usize {
+        self.max_content_size
+    }
+}
+
+impl Default for RegistrationLimits {
+    fn default() -> Self {
+        RegistrationLimits {
+            max_relays_per_registration: 16,
+            max_relays: 10_000,
+            max_registrations: 1_000_000,
+            max_content_size: 16 * 1024,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        let cases = vec![
+            (
+                RegistrationLimits::new(0, 10, 10, 10),
+                "max relays per registration must be greater than zero",
+            ),
+            (
+                RegistrationLimits::new(5, 4, 10, 10),
+                "max relays can't be lower than max relays per registration",
+            ),
+            (
+                RegistrationLimits::new(1, 1, 0, 10),
+                "max registrations must be greater than zero",
+            ),
+            (
+                RegistrationLimits::new(1, 1, 1, 0),
+                "max content size must be greater than zero",
+            ),
+        ];
+
+        for (result, expected_error) in cases {
+            match result {
+                Ok(_) => panic!("expected an error: {expected_error}"),
+                Err(err) => assert_eq!(err.to_string(), expected_error),
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use crate::errors::Result;
+use crate::service::domain;
+use std::collections::HashSet;
+
+// Kind of NIP-51 mute lists.
+pub const MUTE_LIST_KIND: u64 = 10000;
+
+// Public items of a NIP-51 mute list. Items can also be encrypted to the user in the content of
+// the event but the service can't read them. Hashtags and words are lowercased as they are
+// matched regardless of case.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MuteList {
+    pub_key: domain::PubKey,
+    pub_keys: HashSet<domain::PubKey>,
+    hashtags: HashSet<String>,
+    words: HashSet<String>,
+    threads: HashSet<nostr::EventId>,
+    event: domain::EventInfo,
+}
+
+impl MuteList {
+    pub fn new(
+        pub_key: domain::PubKey,
+        pub_keys: HashSet<domain::PubKey>,
+        hashtags: HashSet<String>,
+        words: HashSet<String>,
+        threads: HashSet<nostr::EventId>,
+        event: domain::EventInfo,
+    ) -> MuteList {
+        MuteList {
+            pub_key,
+            pub_keys,
+            hashtags: hashtags.iter().map(|v| v.to_lowercase()).collect(),
+            words: words.iter().map(|v| v.to_lowercase()).collect(),
+            threads,
+            event,
+        }
+    }
+
+    // Tags which can't be parsed are skipped so that a single malformed tag doesn't disable the
+    // whole list.
+    pub fn from_event(event: &nostr::Event) -> Result<MuteList> {
+        if event.kind.as_u64() != MUTE_LIST_KIND {
+            return Err(format!("unexpected event kind: {}", event.kind.as_u64()).into());
+        }
+
+        let mut pub_keys = HashSet::new();
+        let mut hashtags = HashSet::new();
+        let mut words = HashSet::new();
+        let mut threads = HashSet::new();
+        for tag in &event.tags {
+            let tag = tag.as_vec();
+            match (tag.first().map(String::as_str), tag.get(1)) {
+                (Some("p"), Some(value)) => match domain::PubKey::new_from_hex(value) {
+                    Ok(pub_key) => {
+                        pub_keys.insert(pub_key);
+                    }
+                    Err(err) => log::debug!("skipping a muted pub key '{value}': {err}"),
+                },
+                (Some("t"), Some(value)) if !value.is_empty() => {
+                    hashtags.insert(value.clone());
+                }
+                (Some("word"), Some(value)) if !value.is_empty() => {
+                    words.insert(value.clone());
+                }
+                (Some("e"), Some(value)) => match nostr::EventId::from_hex(value) {
+                    Ok(event_id) => {
+                        threads.insert(event_id);
+                    }
+                    Err(err) => log::debug!("skipping a muted thread '{value}': {err}"),
+                },
+                _ => {}
+            }
+        }
+
+        Ok(MuteList::new(
+            domain::PubKey::new(event.pubkey),
+            pub_keys,
+            hashtags,
+            words,
+            threads,
+            domain::events::event_info(event),
+        ))
+    }
+
+    pub fn pub_key(&self) -> domain::PubKey {
+        self.pub_key.clone()
+    }
+
+    pub fn pub_keys(&self) -> &HashSet<domain::PubKey> {
+        &self.pub_keys
+    }
+
+    pub fn hashtags(&self) -> &HashSet<String> {
+        &self.hashtags
+    }
+
+    pub fn words(&self) -> &HashSet<String> {
+        &self.words
+    }
+
+    pub fn threads(&self) -> &HashSet<nostr::EventId> {
+        &self.threads
+    }
+
+    pub fn event(&self) -> domain::EventInfo {
+        self.event.clone()
+    }
+
+    // Events are muted if they were written by a muted pub key, are part of a muted thread, carry
+    // a muted hashtag or contain a muted word.
+    pub fn mutes(&self, event: &nostr::Event) -> bool {
+        if self.pub_keys.contains(&domain::PubKey::new(event.pubkey)) {
+            return true;
+        }
+
+        if self.threads.contains(&event.id) {
+            return true;
+        }
+
+        for tag in &event.tags {
+            let tag = tag.as_vec();
+            match (tag.first().map(String::as_str), tag.get(1)) {
+                (Some("e"), Some(value)) => {
+                    if let Ok(event_id) = nostr::EventId::from_hex(value) {
+                        if self.threads.contains(&event_id) {
+                            return true;
+                        }
+                    }
+                }
+                (Some("t"), Some(value)) if self.hashtags.contains(&value.to_lowercase()) => {
+                    return true;
+                }
+                _ => {}
+            }
+        }
+
+        if !self.words.is_empty() {
+            let content = event.content.to_lowercase();
+            if self
+                .words
+                .iter()
+                .any(|word| content.contains(word.as_str()))
+            {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn mute_lists_are_read_from_events() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let muted = fixtures::some_keys();
+        let thread = nostr::EventId::from_slice(&[1; 32])?;
+        let event = fixtures::mute_list_event(
+            &keys,
+            vec![
+                nostr::Tag::PubKey(muted.public_key(), None),
+                nostr::Tag::Generic(
+                    nostr::event::TagKind::P,
+                    vec![String::from("not a pub key")],
+                ),
+                nostr::Tag::Hashtag(String::from("Politics")),
+                nostr::Tag::Generic(
+                    nostr::event::TagKind::Custom(String::from("word")),
+                    vec![String::from("GM")],
+                ),
+                nostr::Tag::Event(thread, None, None),
+            ],
+        );
+
+        let mute_list = MuteList::from_event(&event)?;
+        assert_eq!(mute_list.pub_key(), domain::PubKey::new(keys.public_key()));
+        assert_eq!(
+            mute_list.pub_keys(),
+            &HashSet::from([domain::PubKey::new(muted.public_key())])
+        );
+        assert_eq!(
+            mute_list.hashtags(),
+            &HashSet::from([String::from("politics")])
+        );
+        assert_eq!(mute_list.words(), &HashSet::from([String::from("gm")]));
+        assert_eq!(mute_list.threads(), &HashSet::from([thread]));
+        assert_eq!(mute_list.event(), domain::events::event_info(&event));
+        Ok(())
+    }
+
+    #[test]
+    fn events_of_other_kinds_are_rejected() {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+        assert!(MuteList::from_event(&event).is_err());
+    }
+
+    #[test]
+    fn muted_events_are_recognized() -> Result<()> {
+        let muted = fixtures::some_keys();
+        let other = fixtures::some_keys();
+        let thread = nostr::EventId::from_slice(&[1; 32])?;
+        let mute_list = MuteList::from_event(&fixtures::mute_list_event(
+            &fixtures::some_keys(),
+            vec![
+                nostr::Tag::PubKey(muted.public_key(), None),
+                nostr::Tag::Hashtag(String::from("politics")),
+                nostr::Tag::Generic(
+                    nostr::event::TagKind::Custom(String::from("word")),
+                    vec![String::from("gm")],
+                ),
+                nostr::Tag::Event(thread, None, None),
+            ],
+        ))?;
+
+        let cases = vec![
+            (note(&muted, "hello", vec![]), true),
+            (note(&other, "hello", vec![]), false),
+            (
+                note(&other, "hello", vec![nostr::Tag::Event(thread, None, None)]),
+                true,
+            ),
+            (
+                note(
+                    &other,
+                    "hello",
+                    vec![nostr::Tag::Hashtag(String::from("Politics"))],
+                ),
+                true,
+            ),
+            (note(&other, "GM everyone", vec![]), true),
+        ];
+
+        for (event, expected) in cases {
+            assert_eq!(mute_list.mutes(&event), expected, "{}", event.as_json());
+        }
+        Ok(())
+    }
+
+    fn note(keys: &nostr::Keys, content: &str, tags: Vec<nostr::Tag>) -> nostr::Event {
+        nostr::EventBuilder::new_text_note(content, &tags)
+            .to_event(keys)
+            .unwrap()
+    }
+}
@@ -0,0 +1,325 @@
+use crate::errors::Result;
+use crate::service::domain;
+use crate::service::domain::mutes;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+// Kinds of notifications which users can turn on or off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Category {
+    Mention,
+    Reply,
+    DirectMessage,
+    Zap,
+    Reaction,
+    Repost,
+    NewFollower,
+}
+
+impl Category {
+    pub const ALL: [Category; 7] = [
+        Category::Mention,
+        Category::Reply,
+        Category::DirectMessage,
+        Category::Zap,
+        Category::Reaction,
+        Category::Repost,
+        Category::NewFollower,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Mention => "mentions",
+            Category::Reply => "replies",
+            Category::DirectMessage => "direct_messages",
+            Category::Zap => "zaps",
+            Category::Reaction => "reactions",
+            Category::Repost => "reposts",
+            Category::NewFollower => "new_followers",
+        }
+    }
+
+    // Classifies an event which tags the user. Contact lists are treated as new followers as
+    // they tag everyone that their author follows, the caller must make sure that the user wasn't
+    // followed already.
+    pub fn of(event: &nostr::Event) -> Option<Category> {
+        match event.kind.as_u64() {
+            1 if event
+                .tags
+                .iter()
+                .any(|tag| tag.as_vec().first().map(String::as_str) == Some("e")) =>
+            {
+                Some(Category::Reply)
+            }
+            1 => Some(Category::Mention),
+            4 | 14 | 1059 => Some(Category::DirectMessage),
+            9735 => Some(Category::Zap),
+            7 => Some(Category::Reaction),
+            6 | 16 => Some(Category::Repost),
+            3 => Some(Category::NewFollower),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Category {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Category::ALL
+            .into_iter()
+            .find(|category| category.as_str() == s)
+            .ok_or_else(|| format!("unknown notification category: '{s}'").into())
+    }
+}
+
+// Categories of notifications which the user wants to receive. Reactions and reposts are off by
+// default as they are frequent and rarely need the user's attention.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preferences {
+    enabled: HashSet<Category>,
+}
+
+impl Preferences {
+    pub fn new(enabled: impl IntoIterator<Item = Category>) -> Self {
+        Self {
+            enabled: enabled.into_iter().collect(),
+        }
+    }
+
+    pub fn is_enabled(&self, category: Category) -> bool {
+        self.enabled.contains(&category)
+    }
+
+    pub fn set(&mut self, category: Category, enabled: bool) {
+        if enabled {
+            self.enabled.insert(category);
+        } else {
+            self.enabled.remove(&category);
+        }
+    }
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences::new([
+            Category::Mention,
+            Category::Reply,
+            Category::DirectMessage,
+            Category::Zap,
+            Category::NewFollower,
+        ])
+    }
+}
+
+// Returns the category of the notification which the event should produce for a user with the
+// given preferences and mute list or None if the user shouldn't be notified.
+pub fn category_to_notify(
+    event: &nostr::Event,
+    preferences: &Preferences,
+    mute_list: Option<&mutes::MuteList>,
+) -> Option<Category> {
+    let category = Category::of(event)?;
+    if !preferences.is_enabled(category) {
+        return None;
+    }
+    if mute_list.is_some_and(|mute_list| mute_list.mutes(event)) {
+        return None;
+    }
+    Some(category)
+}
+
+// Pub keys tagged by the event which should be notified about it. Authors are never notified about
+// their own events.
+pub fn recipients(event: &nostr::Event) -> Vec<domain::PubKey> {
+    let author = domain::PubKey::new(event.pubkey);
+    let mut recipients = vec![];
+    for tag in &event.tags {
+        let tag = tag.as_vec();
+        if let (Some("p"), Some(value)) = (tag.first().map(String::as_str), tag.get(1)) {
+            match domain::PubKey::new_from_hex(value) {
+                Ok(pub_key) if pub_key != author && !recipients.contains(&pub_key) => {
+                    recipients.push(pub_key)
+                }
+                Ok(_) => {}
+                Err(err) => log::debug!("skipping a tagged pub key '{value}': {err}"),
+            }
+        }
+    }
+    recipients
+}
+
+// Notification about a single event for a single device of a pub key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+    pub_key: domain::PubKey,
+    push_token: domain::PushToken,
+    locale: domain::Locale,
+    event_id: nostr::EventId,
+    category: Category,
+}
+
+impl Notification {
+    pub fn new(
+        pub_key: domain::PubKey,
+        push_token: domain::PushToken,
+        locale: domain::Locale,
+        event_id: nostr::EventId,
+        category: Category,
+    ) -> Notification {
+        Notification {
+            pub_key,
+            push_token,
+            locale,
+            event_id,
+            category,
+        }
+    }
+
+    pub fn pub_key(&self) -> domain::PubKey {
+        self.pub_key.clone()
+    }
+
+    pub fn push_token(&self) -> domain::PushToken {
+        self.push_token.clone()
+    }
+
+    pub fn locale(&self) -> domain::Locale {
+        self.locale.clone()
+    }
+
+    pub fn event_id(&self) -> nostr::EventId {
+        self.event_id
+    }
+
+    pub fn category(&self) -> Category {
+        self.category
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn categories_are_parsed() -> Result<()> {
+        for category in Category::ALL {
+            assert_eq!(Category::from_str(category.as_str())?, category);
+        }
+        assert!(Category::from_str("likes").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn events_are_classified() {
+        let keys = nostr::Keys::generate();
+        let p_tag = nostr::Tag::PubKey(nostr::Keys::generate().public_key(), None);
+        let e_tag = nostr::Tag::Event(nostr::EventId::from_slice(&[1; 32]).unwrap(), None, None);
+
+        let cases = vec![
+            (1, vec![p_tag.clone()], Some(Category::Mention)),
+            (1, vec![e_tag.clone(), p_tag.clone()], Some(Category::Reply)),
+            (4, vec![p_tag.clone()], Some(Category::DirectMessage)),
+            (1059, vec![p_tag.clone()], Some(Category::DirectMessage)),
+            (9735, vec![p_tag.clone()], Some(Category::Zap)),
+            (
+                7,
+                vec![e_tag.clone(), p_tag.clone()],
+                Some(Category::Reaction),
+            ),
+            (
+                6,
+                vec![e_tag.clone(), p_tag.clone()],
+                Some(Category::Repost),
+            ),
+            (3, vec![p_tag.clone()], Some(Category::NewFollower)),
+            (30023, vec![p_tag.clone()], None),
+        ];
+
+        for (kind, tags, expected) in cases {
+            let event = nostr::EventBuilder::new(nostr::Kind::from(kind), "", &tags)
+                .to_event(&keys)
+                .unwrap();
+            assert_eq!(Category::of(&event), expected, "kind {kind}");
+        }
+    }
+
+    #[test]
+    fn noisy_categories_are_disabled_by_default() {
+        let preferences = Preferences::default();
+        assert!(preferences.is_enabled(Category::Mention));
+        assert!(preferences.is_enabled(Category::DirectMessage));
+        assert!(!preferences.is_enabled(Category::Reaction));
+        assert!(!preferences.is_enabled(Category::Repost));
+    }
+
+    #[test]
+    fn muted_and_disabled_events_are_not_notified() -> Result<()> {
+        let muted = fixtures::some_keys();
+        let other = fixtures::some_keys();
+        let mute_list = mutes::MuteList::from_event(&fixtures::mute_list_event(
+            &fixtures::some_keys(),
+            vec![nostr::Tag::PubKey(muted.public_key(), None)],
+        ))?;
+        let preferences = Preferences::default();
+
+        let mention = nostr::EventBuilder::new_text_note("hello", &[]).to_event(&other)?;
+        let muted_mention = nostr::EventBuilder::new_text_note("hello", &[]).to_event(&muted)?;
+        let reaction =
+            nostr::EventBuilder::new(nostr::Kind::Reaction, "+", &[]).to_event(&other)?;
+
+        assert_eq!(
+            category_to_notify(&mention, &preferences, Some(&mute_list)),
+            Some(Category::Mention)
+        );
+        assert_eq!(
+            category_to_notify(&muted_mention, &preferences, Some(&mute_list)),
+            None
+        );
+        assert_eq!(
+            category_to_notify(&muted_mention, &preferences, None),
+            Some(Category::Mention)
+        );
+        assert_eq!(
+            category_to_notify(&reaction, &preferences, Some(&mute_list)),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tagged_pub_keys_other_than_the_author_are_recipients() -> Result<()> {
+        let author = fixtures::some_keys();
+        let first = fixtures::some_keys();
+        let second = fixtures::some_keys();
+        let event = nostr::EventBuilder::new_text_note(
+            "hello",
+            &[
+                nostr::Tag::PubKey(first.public_key(), None),
+                nostr::Tag::PubKey(author.public_key(), None),
+                nostr::Tag::PubKey(second.public_key(), None),
+                nostr::Tag::PubKey(first.public_key(), None),
+            ],
+        )
+        .to_event(&author)?;
+
+        assert_eq!(
+            recipients(&event),
+            vec![
+                domain::PubKey::new(first.public_key()),
+                domain::PubKey::new(second.public_key())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn categories_can_be_toggled() {
+        let mut preferences = Preferences::default();
+        preferences.set(Category::Reaction, true);
+        preferences.set(Category::Mention, false);
+        assert!(preferences.is_enabled(Category::Reaction));
+        assert!(!preferences.is_enabled(Category::Mention));
+    }
+}
@@ -0,0 +1,395 @@
+use crate::errors::Result;
+use crate::service::domain;
+use crate::service::domain::notifications;
+use chrono::TimeZone;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+// What happens to notifications which would be sent during quiet hours.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    // Notifications are never sent.
+    Drop,
+    // Notifications are held and a single digest is sent once quiet hours end.
+    Digest,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Drop => "drop",
+            Mode::Digest => "digest",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "drop" => Ok(Mode::Drop),
+            "digest" => Ok(Mode::Digest),
+            _ => Err(format!("unknown quiet hours mode: '{s}'").into()),
+        }
+    }
+}
+
+// A daily window of local time. Windows which end before they start span midnight, e.g.
+// 22:00-07:00. The start is inclusive and the end is exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    start: chrono::NaiveTime,
+    end: chrono::NaiveTime,
+}
+
+impl Window {
+    pub fn new(start: chrono::NaiveTime, end: chrono::NaiveTime) -> Result<Window> {
+        if start == end {
+            return Err("quiet hours window can't be empty".into());
+        }
+        Ok(Window { start, end })
+    }
+
+    // Times are written as HH:MM.
+    pub fn parse(start: &str, end: &str) -> Result<Window> {
+        Window::new(parse_time(start)?, parse_time(end)?)
+    }
+
+    pub fn start(&self) -> chrono::NaiveTime {
+        self.start
+    }
+
+    pub fn end(&self) -> chrono::NaiveTime {
+        self.end
+    }
+
+    fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+pub fn format_time(time: chrono::NaiveTime) -> String {
+    time.format("%H:%M").to_string()
+}
+
+fn parse_time(s: &str) -> Result<chrono::NaiveTime> {
+    chrono::NaiveTime::parse_from_str(s, "%H:%M")
+        .map_err(|_| format!("invalid quiet hours time '{s}': expected HH:MM").into())
+}
+
+// Windows of local time in an IANA time zone during which the user doesn't want to receive
+// notifications.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+    time_zone: chrono_tz::Tz,
+    windows: Vec<Window>,
+    mode: Mode,
+}
+
+impl QuietHours {
+    pub fn new(time_zone: &str, windows: Vec<Window>, mode: Mode) -> Result<QuietHours> {
+        let time_zone = chrono_tz::Tz::from_str(time_zone)
+            .map_err(|_| format!("unknown time zone '{time_zone}'"))?;
+
+        if windows.is_empty() {
+            return Err("empty quiet hours windows".into());
+        }
+
+        Ok(QuietHours {
+            time_zone,
+            windows,
+            mode,
+        })
+    }
+
+    pub fn time_zone(&self) -> &str {
+        self.time_zone.name()
+    }
+
+    pub fn windows(&self) -> Vec<Window> {
+        self.windows.clone()
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn delivery(&self, at: nostr::Timestamp) -> Delivery {
+        if !self.is_quiet(to_date_time(at)) {
+            return Delivery::Now;
+        }
+
+        match self.mode {
+            Mode::Drop => Delivery::Drop,
+            Mode::Digest => Delivery::HoldUntil(self.end_of_quiet_hours(at)),
+        }
+    }
+
+    fn is_quiet(&self, at: chrono::DateTime<chrono::Utc>) -> bool {
+        let time = at.with_timezone(&self.time_zone).time();
+        self.windows.iter().any(|window| window.contains(time))
+    }
+
+    // Windows which overlap or follow each other are treated as a single window.
+    fn end_of_quiet_hours(&self, at: nostr::Timestamp) -> nostr::Timestamp {
+        let mut at = to_date_time(at);
+        for _ in 0..=self.windows.len() {
+            let local = at.with_timezone(&self.time_zone).naive_local();
+            let Some(window) = self.windows.iter().find(|w| w.contains(local.time())) else {
+                break;
+            };
+
+            let mut end = local.date().and_time(window.end);
+            if end <= local {
+                end += chrono::Duration::days(1);
+            }
+            at = self.to_utc(end);
+        }
+        nostr::Timestamp::from(u64::try_from(at.timestamp()).unwrap_or_default())
+    }
+
+    // Local times which are skipped when clocks move forward are moved forward as well.
+    fn to_utc(&self, local: chrono::NaiveDateTime) -> chrono::DateTime<chrono::Utc> {
+        let mut local = local;
+        loop {
+            if let Some(date_time) = self.time_zone.from_local_datetime(&local).earliest() {
+                return date_time.with_timezone(&chrono::Utc);
+            }
+            local += chrono::Duration::minutes(15);
+        }
+    }
+}
+
+fn to_date_time(timestamp: nostr::Timestamp) -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::from_timestamp(timestamp.as_i64(), 0).unwrap_or_default()
+}
+
+// What should happen to a notification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Now,
+    Drop,
+    HoldUntil(nostr::Timestamp),
+}
+
+// Notification held for a device until quiet hours end.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeldNotification {
+    pub_key: domain::PubKey,
+    push_token: domain::PushToken,
+    event_id: nostr::EventId,
+    category: notifications::Category,
+    release_at: nostr::Timestamp,
+}
+
+impl HeldNotification {
+    pub fn new(
+        pub_key: domain::PubKey,
+        push_token: domain::PushToken,
+        event_id: nostr::EventId,
+        category: notifications::Category,
+        release_at: nostr::Timestamp,
+    ) -> HeldNotification {
+        HeldNotification {
+            pub_key,
+            push_token,
+            event_id,
+            category,
+            release_at,
+        }
+    }
+
+    pub fn pub_key(&self) -> domain::PubKey {
+        self.pub_key.clone()
+    }
+
+    pub fn push_token(&self) -> domain::PushToken {
+        self.push_token.clone()
+    }
+
+    pub fn event_id(&self) -> nostr::EventId {
+        self.event_id
+    }
+
+    pub fn category(&self) -> notifications::Category {
+        self.category
+    }
+
+    pub fn release_at(&self) -> nostr::Timestamp {
+        self.release_at
+    }
+}
+
+// Summary of the notifications which were held for a device during quiet hours, sent once they
+// end.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Digest {
+    pub_key: domain::PubKey,
+    push_token: domain::PushToken,
+    counts: BTreeMap<notifications::Category, usize>,
+}
+
+impl Digest {
+    pub fn new(
+        pub_key: domain::PubKey,
+        push_token: domain::PushToken,
+        counts: BTreeMap<notifications::Category, usize>,
+    ) -> Digest {
+        Digest {
+            pub_key,
+            push_token,
+            counts,
+        }
+    }
+
+    pub fn pub_key(&self) -> domain::PubKey {
+        self.pub_key.clone()
+    }
+
+    pub fn push_token(&self) -> domain::PushToken {
+        self.push_token.clone()
+    }
+
+    pub fn counts(&self) -> &BTreeMap<notifications::Category, usize> {
+        &self.counts
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_can_span_midnight() -> Result<()> {
+        let window = Window::parse("22:00", "07:00")?;
+        assert!(window.contains(time("22:00")));
+        assert!(window.contains(time("03:00")));
+        assert!(!window.contains(time("07:00")));
+        assert!(!window.contains(time("12:00")));
+
+        let window = Window::parse("13:00", "14:00")?;
+        assert!(window.contains(time("13:30")));
+        assert!(!window.contains(time("14:30")));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_quiet_hours_are_rejected() {
+        let cases = vec![
+            (
+                Window::parse("22:00", "22:00").map(|_| ()),
+                "quiet hours window can't be empty",
+            ),
+            (
+                Window::parse("24:00", "07:00").map(|_| ()),
+                "invalid quiet hours time '24:00': expected HH:MM",
+            ),
+            (
+                QuietHours::new(
+                    "Mars/Olympus_Mons",
+                    vec![Window::parse("22:00", "07:00").unwrap()],
+                    Mode::Drop,
+                )
+                .map(|_| ()),
+                "unknown time zone 'Mars/Olympus_Mons'",
+            ),
+            (
+                QuietHours::new("Europe/Warsaw", vec![], Mode::Drop).map(|_| ()),
+                "empty quiet hours windows",
+            ),
+        ];
+
+        for (result, expected_error) in cases {
+            match result {
+                Ok(_) => panic!("expected an error: {expected_error}"),
+                Err(err) => assert_eq!(err.to_string(), expected_error),
+            }
+        }
+    }
+
+    #[test]
+    fn notifications_are_sent_outside_of_quiet_hours() -> Result<()> {
+        let quiet_hours = night("Europe/Warsaw", Mode::Digest)?;
+        // 12:00 in Warsaw.
+        assert_eq!(
+            quiet_hours.delivery(timestamp("2023-06-01T10:00:00Z")),
+            Delivery::Now
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn notifications_are_dropped_or_held_during_quiet_hours() -> Result<()> {
+        // 23:30 in Warsaw.
+        let at = timestamp("2023-06-01T21:30:00Z");
+
+        let quiet_hours = night("Europe/Warsaw", Mode::Drop)?;
+        assert_eq!(quiet_hours.delivery(at), Delivery::Drop);
+
+        let quiet_hours = night("Europe/Warsaw", Mode::Digest)?;
+        assert_eq!(
+            quiet_hours.delivery(at),
+            Delivery::HoldUntil(timestamp("2023-06-02T05:00:00Z"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn adjacent_windows_are_merged() -> Result<()> {
+        let quiet_hours = QuietHours::new(
+            "UTC",
+            vec![
+                Window::parse("00:00", "07:00")?,
+                Window::parse("22:00", "00:00")?,
+            ],
+            Mode::Digest,
+        )?;
+        assert_eq!(
+            quiet_hours.delivery(timestamp("2023-06-01T23:00:00Z")),
+            Delivery::HoldUntil(timestamp("2023-06-02T07:00:00Z"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn quiet_hours_follow_daylight_saving_time() -> Result<()> {
+        let quiet_hours = QuietHours::new(
+            "America/New_York",
+            vec![Window::parse("01:00", "02:30")?],
+            Mode::Digest,
+        )?;
+        // Clocks moved from 02:00 to 03:00 on 2023-03-12 so 02:30 never happened.
+        assert_eq!(
+            quiet_hours.delivery(timestamp("2023-03-12T06:30:00Z")),
+            Delivery::HoldUntil(timestamp("2023-03-12T07:00:00Z"))
+        );
+        // 01:30 during summer time.
+        assert_eq!(
+            quiet_hours.delivery(timestamp("2023-06-01T05:30:00Z")),
+            Delivery::HoldUntil(timestamp("2023-06-01T06:30:00Z"))
+        );
+        Ok(())
+    }
+
+    fn night(time_zone: &str, mode: Mode) -> Result<QuietHours> {
+        QuietHours::new(time_zone, vec![Window::parse("22:00", "07:00")?], mode)
+    }
+
+    fn time(s: &str) -> chrono::NaiveTime {
+        parse_time(s).unwrap()
+    }
+
+    fn timestamp(s: &str) -> nostr::Timestamp {
+        let date_time = chrono::DateTime::parse_from_rfc3339(s).unwrap();
+        nostr::Timestamp::from(date_time.timestamp() as u64)
+    }
+}
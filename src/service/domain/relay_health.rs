@@ -0,0 +1,298 @@
+use crate::errors::Result;
+use crate::service::domain;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Delay before the first reconnection attempt, it doubles with every consecutive failure.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+// Longest delay between reconnection attempts of relays which aren't dead.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+// Relays which keep failing for this long are considered dead.
+pub const DEAD_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Delay between reconnection attempts of dead relays.
+pub const DEAD_RETRY_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+// Connections which stay open for this long are considered working even if the relay never
+// finished sending stored events.
+pub const MIN_UPTIME: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    // Nothing is known about the relay yet.
+    Unknown,
+    Connected,
+    // The connection failed and the relay will be connected to again after a delay.
+    BackingOff,
+    // The relay kept failing for a long time and is only retried rarely.
+    Dead,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Unknown => "unknown",
+            State::Connected => "connected",
+            State::BackingOff => "backing_off",
+            State::Dead => "dead",
+        }
+    }
+}
+
+impl FromStr for State {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "unknown" => Ok(State::Unknown),
+            "connected" => Ok(State::Connected),
+            "backing_off" => Ok(State::BackingOff),
+            "dead" => Ok(State::Dead),
+            _ => Err(format!("unknown relay state: '{s}'").into()),
+        }
+    }
+}
+
+// What is known about connecting to a relay. The time of the next attempt is stored instead of
+// being computed from the failures as it is randomized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayHealth {
+    relay: domain::RelayAddress,
+    state: State,
+    last_error: Option<String>,
+    consecutive_failures: u32,
+    last_connected_at: Option<nostr::Timestamp>,
+    failing_since: Option<nostr::Timestamp>,
+    retry_at: Option<nostr::Timestamp>,
+}
+
+impl RelayHealth {
+    pub fn new(relay: domain::RelayAddress) -> RelayHealth {
+        RelayHealth {
+            relay,
+            state: State::Unknown,
+            last_error: None,
+            consecutive_failures: 0,
+            last_connected_at: None,
+            failing_since: None,
+            retry_at: None,
+        }
+    }
+
+    pub fn restore(
+        relay: domain::RelayAddress,
+        state: State,
+        last_error: Option<String>,
+        consecutive_failures: u32,
+        last_connected_at: Option<nostr::Timestamp>,
+        failing_since: Option<nostr::Timestamp>,
+        retry_at: Option<nostr::Timestamp>,
+    ) -> RelayHealth {
+        RelayHealth {
+            relay,
+            state,
+            last_error,
+            consecutive_failures,
+            last_connected_at,
+            failing_since,
+            retry_at,
+        }
+    }
+
+    pub fn relay(&self) -> domain::RelayAddress {
+        self.relay.clone()
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.clone()
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    pub fn last_connected_at(&self) -> Option<nostr::Timestamp> {
+        self.last_connected_at
+    }
+
+    pub fn failing_since(&self) -> Option<nostr::Timestamp> {
+        self.failing_since
+    }
+
+    pub fn retry_at(&self) -> Option<nostr::Timestamp> {
+        self.retry_at
+    }
+
+    // Failures aren't forgotten until the connection proves to be working as relays which accept
+    // connections and drop them right away would otherwise be reconnected to without a backoff.
+    pub fn connected(&mut self, now: nostr::Timestamp) {
+        self.state = State::Connected;
+        self.last_connected_at = Some(now);
+        self.retry_at = None;
+    }
+
+    // Called once the relay sent all stored events. The last error is kept so that it can still
+    // be inspected.
+    pub fn working(&mut self) {
+        self.consecutive_failures = 0;
+        self.failing_since = None;
+    }
+
+    // Jitter is a number from 0 to 1 which randomizes the delay so that connections to relays
+    // which failed at the same time aren't retried at the same time.
+    pub fn failed(&mut self, error: String, now: nostr::Timestamp, jitter: f64) {
+        let up_for_long = self.state == State::Connected
+            && self
+                .last_connected_at
+                .is_some_and(|connected_at| now >= connected_at + MIN_UPTIME);
+        if up_for_long {
+            self.working();
+        }
+
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.last_error = Some(error);
+        let failing_since = *self.failing_since.get_or_insert(now);
+
+        let failing_for = Duration::from_secs(now.as_u64().saturating_sub(failing_since.as_u64()));
+        let delay = if failing_for >= DEAD_AFTER {
+            self.state = State::Dead;
+            DEAD_RETRY_INTERVAL
+        } else {
+            self.state = State::BackingOff;
+            backoff(self.consecutive_failures)
+        };
+
+        // Half of the delay is fixed so that jitter can't make it arbitrarily short.
+        let delay = delay.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0);
+        self.retry_at = Some(now + delay);
+    }
+
+    // How long to wait before connecting to the relay.
+    pub fn retry_delay(&self, now: nostr::Timestamp) -> Duration {
+        match self.retry_at {
+            Some(retry_at) => Duration::from_secs(retry_at.as_u64().saturating_sub(now.as_u64())),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+fn backoff(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(31);
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.pow(exponent))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_a_limit() {
+        let now = nostr::Timestamp::from(1_000_000);
+        let mut health = RelayHealth::new(fixtures::some_relay_address());
+
+        let mut delays = vec![];
+        for _ in 0..12 {
+            health.failed(String::from("error"), now, 1.0);
+            delays.push(health.retry_delay(now).as_secs());
+        }
+
+        assert_eq!(
+            delays,
+            vec![2, 4, 8, 16, 32, 64, 128, 256, 300, 300, 300, 300]
+        );
+        assert_eq!(health.state(), State::BackingOff);
+        assert_eq!(health.consecutive_failures(), 12);
+        assert_eq!(health.last_error(), Some(String::from("error")));
+    }
+
+    #[test]
+    fn jitter_shortens_the_delay_by_at_most_a_half() {
+        let now = nostr::Timestamp::from(1_000_000);
+        let mut health = RelayHealth::new(fixtures::some_relay_address());
+        for _ in 0..10 {
+            health.failed(String::from("error"), now, 1.0);
+        }
+        assert_eq!(health.retry_delay(now), MAX_BACKOFF);
+
+        health.failed(String::from("error"), now, 0.0);
+        assert_eq!(health.retry_delay(now), MAX_BACKOFF / 2);
+    }
+
+    #[test]
+    fn relays_which_fail_for_long_are_dead_until_they_connect() {
+        let start = nostr::Timestamp::from(1_000_000);
+        let mut health = RelayHealth::new(fixtures::some_relay_address());
+
+        health.failed(String::from("error"), start, 1.0);
+        assert_eq!(health.failing_since(), Some(start));
+
+        let later = start + DEAD_AFTER;
+        health.failed(String::from("error"), later, 1.0);
+        assert_eq!(health.state(), State::Dead);
+        assert_eq!(health.retry_delay(later), DEAD_RETRY_INTERVAL);
+
+        let now = later + DEAD_RETRY_INTERVAL;
+        health.connected(now);
+        assert_eq!(health.state(), State::Connected);
+        assert_eq!(health.last_connected_at(), Some(now));
+        assert_eq!(health.retry_delay(now), Duration::ZERO);
+
+        health.working();
+        assert_eq!(health.consecutive_failures(), 0);
+        assert_eq!(health.failing_since(), None);
+
+        health.failed(String::from("error"), now, 1.0);
+        assert_eq!(health.state(), State::BackingOff);
+        assert_eq!(health.retry_delay(now), INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn flapping_relays_keep_backing_off() {
+        let mut now = nostr::Timestamp::from(1_000_000);
+        let mut health = RelayHealth::new(fixtures::some_relay_address());
+
+        let mut delays = vec![];
+        for _ in 0..6 {
+            health.connected(now);
+            now = now + Duration::from_secs(1);
+            health.failed(String::from("connection reset"), now, 1.0);
+            delays.push(health.retry_delay(now).as_secs());
+            now = now + health.retry_delay(now);
+        }
+
+        assert_eq!(delays, vec![2, 4, 8, 16, 32, 64]);
+        assert_eq!(health.state(), State::BackingOff);
+        assert_eq!(health.consecutive_failures(), 6);
+    }
+
+    #[test]
+    fn failures_are_forgotten_once_the_connection_works() {
+        let now = nostr::Timestamp::from(1_000_000);
+        let mut health = RelayHealth::new(fixtures::some_relay_address());
+        for _ in 0..5 {
+            health.failed(String::from("error"), now, 1.0);
+        }
+
+        health.connected(now);
+        let later = now + MIN_UPTIME;
+        health.failed(String::from("connection reset"), later, 1.0);
+        assert_eq!(health.consecutive_failures(), 1);
+        assert_eq!(health.failing_since(), Some(later));
+        assert_eq!(health.retry_delay(later), INITIAL_BACKOFF);
+
+        health.connected(later);
+        health.working();
+        health.failed(String::from("connection reset"), later, 1.0);
+        assert_eq!(health.consecutive_failures(), 1);
+        assert_eq!(health.retry_delay(later), INITIAL_BACKOFF);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::errors::Result;
+use crate::service::domain::subscriptions;
+
+// Length of a pub key in a filter, including the quotes and the comma.
+const PUB_KEY_LENGTH: usize = 67;
+
+// Space taken by everything in a REQ apart from pub keys, per filter.
+const FILTER_OVERHEAD: usize = 128;
+
+// NIP-11 relay information document. The whole document is kept so that it can be inspected but
+// only limitations which affect subscriptions are read from it. Values which have unexpected
+// types are ignored as if the relay didn't advertise them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayInformation {
+    document: String,
+    fetched_at: nostr::Timestamp,
+    max_message_length: Option<usize>,
+    max_subscriptions: Option<usize>,
+    max_filters: Option<usize>,
+    max_limit: Option<usize>,
+    payment_required: bool,
+}
+
+impl RelayInformation {
+    pub fn new(document: String, fetched_at: nostr::Timestamp) -> Result<RelayInformation> {
+        let value: serde_json::Value = serde_json::from_str(&document)
+            .map_err(|err| format!("invalid relay information document: {err}"))?;
+        if !value.is_object() {
+            return Err("relay information document is not an object".into());
+        }
+
+        let limitation = &value["limitation"];
+        let number = |name: &str| {
+            limitation[name]
+                .as_u64()
+                .and_then(|v| usize::try_from(v).ok())
+        };
+        let flag = |name: &str| limitation[name].as_bool().unwrap_or(false);
+
+        Ok(RelayInformation {
+            max_message_length: number("max_message_length"),
+            max_subscriptions: number("max_subscriptions"),
+            max_filters: number("max_filters"),
+            max_limit: number("max_limit"),
+            payment_required: flag("payment_required"),
+            document,
+            fetched_at,
+        })
+    }
+
+    pub fn document(&self) -> &str {
+        &self.document
+    }
+
+    pub fn fetched_at(&self) -> nostr::Timestamp {
+        self.fetched_at
+    }
+
+    pub fn max_limit(&self) -> Option<usize> {
+        self.max_limit
+    }
+
+    pub fn payment_required(&self) -> bool {
+        self.payment_required
+    }
+
+    // Advertised limits can only lower the given ones. Limits of zero are ignored as they would
+    // make subscribing impossible.
+    pub fn subscription_limits(
+        &self,
+        limits: &subscriptions::SubscriptionLimits,
+    ) -> Result<subscriptions::SubscriptionLimits> {
+        let lower = |limit: usize, advertised: Option<usize>| match advertised {
+            Some(advertised) if advertised > 0 => limit.min(advertised),
+            _ => limit,
+        };
+
+        let max_filters_per_subscription =
+            lower(limits.max_filters_per_subscription(), self.max_filters);
+        let max_pub_keys_per_filter = lower(
+            limits.max_pub_keys_per_filter(),
+            self.max_message_length.map(|max_message_length| {
+                let per_filter = max_message_length / max_filters_per_subscription;
+                (per_filter.saturating_sub(FILTER_OVERHEAD) / PUB_KEY_LENGTH).max(1)
+            }),
+        );
+
+        subscriptions::SubscriptionLimits::new(
+            max_pub_keys_per_filter,
+            max_filters_per_subscription,
+            lower(limits.max_subscriptions(), self.max_subscriptions),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limitations_are_read_from_the_document() -> Result<()> {
+        let information = RelayInformation::new(
+            String::from(
+                r#"{
+                    "name": "relay",
+                    "supported_nips": [1, 11, 42],
+                    "limitation": {
+                        "max_subscriptions": 5,
+                        "max_filters": "ten",
+                        "max_limit": 500,
+                        "auth_required": false,
+                        "payment_required": true
+                    }
+                }"#,
+            ),
+            nostr::Timestamp::from(1000),
+        )?;
+
+        assert_eq!(information.max_limit(), Some(500));
+        assert!(information.payment_required());
+        assert_eq!(
+            information
+                .subscription_limits(&subscriptions::SubscriptionLimits::new(200, 10, 20)?)?,
+            subscriptions::SubscriptionLimits::new(200, 10, 5)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn documents_without_limitations_do_not_change_limits() -> Result<()> {
+        let information = RelayInformation::new(
+            String::from(r#"{"name": "relay"}"#),
+            nostr::Timestamp::from(1),
+        )?;
+        let limits = subscriptions::SubscriptionLimits::default();
+
+        assert_eq!(information.subscription_limits(&limits)?, limits);
+        assert!(!information.payment_required());
+        Ok(())
+    }
+
+    #[test]
+    fn message_length_limits_pub_keys_per_filter() -> Result<()> {
+        let information = RelayInformation::new(
+            String::from(r#"{"limitation": {"max_message_length": 16384, "max_filters": 2}}"#),
+            nostr::Timestamp::from(1),
+        )?;
+
+        let limits = information
+            .subscription_limits(&subscriptions::SubscriptionLimits::new(200, 10, 20)?)?;
+        assert_eq!(limits.max_filters_per_subscription(), 2);
+        assert_eq!(limits.max_pub_keys_per_filter(), (8192 - 128) / 67);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_documents_are_rejected() {
+        assert!(RelayInformation::new(String::from("<html>"), nostr::Timestamp::from(1)).is_err());
+        assert!(RelayInformation::new(String::from("[]"), nostr::Timestamp::from(1)).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::errors::Result;
+use crate::service::domain;
+use crate::service::domain::relay_information;
+use std::collections::HashSet;
+
+// Chunks are never split more than this many times so that subscription ids stay short. Keys
+// would have to share this many leading bits for a chunk to end up larger than allowed.
+const MAX_PREFIX_LENGTH: usize = 48;
+
+// Limits which a relay places on subscriptions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubscriptionLimits {
+    max_pub_keys_per_filter: usize,
+    max_filters_per_subscription: usize,
+    max_subscriptions: usize,
+}
+
+impl SubscriptionLimits {
+    pub fn new(
+        max_pub_keys_per_filter: usize,
+        max_filters_per_subscription: usize,
+        max_subscriptions: usize,
+    ) -> Result<SubscriptionLimits> {
+        if max_pub_keys_per_filter == 0 {
+            return Err("max pub keys per filter must be positive".into());
+        }
+        if max_filters_per_subscription == 0 {
+            return Err("max filters per subscription must be positive".into());
+        }
+        if max_subscriptions == 0 {
+            return Err("max subscriptions must be positive".into());
+        }
+
+        Ok(SubscriptionLimits {
+            max_pub_keys_per_filter,
+            max_filters_per_subscription,
+            max_subscriptions,
+        })
+    }
+
+    pub fn max_pub_keys_per_filter(&self) -> usize {
+        self.max_pub_keys_per_filter
+    }
+
+    pub fn max_filters_per_subscription(&self) -> usize {
+        self.max_filters_per_subscription
+    }
+
+    pub fn max_subscriptions(&self) -> usize {
+        self.max_subscriptions
+    }
+
+    fn max_pub_keys_per_subscription(&self) -> usize {
+        self.max_pub_keys_per_filter
+            .saturating_mul(self.max_filters_per_subscription)
+    }
+}
+
+// Limits which most relays accept.
+impl Default for SubscriptionLimits {
+    fn default() -> Self {
+        SubscriptionLimits {
+            max_pub_keys_per_filter: 200,
+            max_filters_per_subscription: 10,
+            max_subscriptions: 20,
+        }
+    }
+}
+
+impl SubscriptionLimits {
+    // Relays which don't advertise limits or advertise invalid ones get the default limits.
+    pub fn of_relay(
+        information: Option<&relay_information::RelayInformation>,
+    ) -> SubscriptionLimits {
+        let limits = SubscriptionLimits::default();
+        match information {
+            Some(information) => information.subscription_limits(&limits).unwrap_or(limits),
+            None => limits,
+        }
+    }
+}
+
+// Pub keys of a single subscription split into groups which fit in a single filter. The id is
+// derived from the pub keys which the chunk covers so it doesn't change when other chunks do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    id: String,
+    filters: Vec<Vec<domain::PubKey>>,
+}
+
+impl Chunk {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn filters(&self) -> &[Vec<domain::PubKey>] {
+        &self.filters
+    }
+}
+
+// Splits pub keys into chunks which fit in a single subscription. Pub keys are uniformly
+// distributed so chunks cover pub keys which start with the same bits and a chunk is split in
+// two by the next bit once it has too many pub keys. As a result adding or removing a pub key
+// only changes the chunk which covers it. Chunks above the limit of subscriptions are returned as
+// well, the caller decides what to do with them.
+pub fn chunk(pub_keys: &HashSet<domain::PubKey>, limits: &SubscriptionLimits) -> Vec<Chunk> {
+    let mut pub_keys: Vec<([u8; 32], domain::PubKey)> = pub_keys
+        .iter()
+        .map(|pub_key| (pub_key.key().serialize(), pub_key.clone()))
+        .collect();
+    pub_keys.sort_by_key(|(bytes, _)| *bytes);
+
+    let mut chunks = vec![];
+    split(&pub_keys, String::new(), limits, &mut chunks);
+    chunks
+}
+
+fn split(
+    pub_keys: &[([u8; 32], domain::PubKey)],
+    prefix: String,
+    limits: &SubscriptionLimits,
+    chunks: &mut Vec<Chunk>,
+) {
+    if pub_keys.is_empty() {
+        return;
+    }
+
+    if pub_keys.len() <= limits.max_pub_keys_per_subscription() || prefix.len() >= MAX_PREFIX_LENGTH
+    {
+        chunks.push(Chunk {
+            id: format!("p{prefix}"),
+            filters: pub_keys
+                .chunks(limits.max_pub_keys_per_filter())
+                .map(|group| group.iter().map(|(_, pub_key)| pub_key.clone()).collect())
+                .collect(),
+        });
+        return;
+    }
+
+    // Pub keys are sorted so the ones with the next bit unset come first.
+    let bit = prefix.len();
+    let index = pub_keys.partition_point(|(bytes, _)| bytes[bit / 8] & (0x80 >> (bit % 8)) == 0);
+    split(&pub_keys[..index], format!("{prefix}0"), limits, chunks);
+    split(&pub_keys[index..], format!("{prefix}1"), limits, chunks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::collections::HashMap;
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        assert!(SubscriptionLimits::new(0, 1, 1).is_err());
+        assert!(SubscriptionLimits::new(1, 0, 1).is_err());
+        assert!(SubscriptionLimits::new(1, 1, 0).is_err());
+    }
+
+    #[test]
+    fn no_pub_keys_produce_no_chunks() -> Result<()> {
+        assert!(chunk(&HashSet::new(), &SubscriptionLimits::new(2, 2, 2)?).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn pub_keys_which_fit_in_a_subscription_are_split_into_filters() -> Result<()> {
+        let pub_keys = some_pub_keys(5);
+        let chunks = chunk(&pub_keys, &SubscriptionLimits::new(2, 3, 1)?);
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].id(), "p");
+        assert_eq!(
+            chunks[0].filters().iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        assert_eq!(covered(&chunks), pub_keys);
+        Ok(())
+    }
+
+    #[test]
+    fn chunks_respect_the_limits() -> Result<()> {
+        let limits = SubscriptionLimits::new(3, 2, 100)?;
+        let pub_keys = some_pub_keys(500);
+        let chunks = chunk(&pub_keys, &limits);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.filters().len() <= limits.max_filters_per_subscription());
+            for filter in chunk.filters() {
+                assert!(!filter.is_empty());
+                assert!(filter.len() <= limits.max_pub_keys_per_filter());
+            }
+        }
+
+        let total: usize = chunks
+            .iter()
+            .flat_map(|chunk| chunk.filters())
+            .map(Vec::len)
+            .sum();
+        assert_eq!(total, pub_keys.len());
+        assert_eq!(covered(&chunks), pub_keys);
+
+        let ids: HashSet<&str> = chunks.iter().map(Chunk::id).collect();
+        assert_eq!(ids.len(), chunks.len());
+        Ok(())
+    }
+
+    #[test]
+    fn chunks_do_not_depend_on_the_order_of_pub_keys() -> Result<()> {
+        let limits = SubscriptionLimits::new(3, 2, 100)?;
+        let pub_keys = some_pub_keys(100);
+        let mut reordered: Vec<domain::PubKey> = pub_keys.iter().cloned().collect();
+        reordered.reverse();
+        let reordered: HashSet<domain::PubKey> = reordered.into_iter().collect();
+
+        assert_eq!(chunk(&pub_keys, &limits), chunk(&reordered, &limits));
+        Ok(())
+    }
+
+    #[test]
+    fn adding_a_pub_key_changes_a_single_chunk() -> Result<()> {
+        let limits = SubscriptionLimits::new(5, 2, 100)?;
+        let mut pub_keys = some_pub_keys(300);
+
+        for _ in 0..20 {
+            let before = by_id(chunk(&pub_keys, &limits));
+            pub_keys.insert(fixtures::some_pub_key());
+            let after = by_id(chunk(&pub_keys, &limits));
+
+            let changed: Vec<&String> = before
+                .iter()
+                .filter(|(id, filters)| after.get(*id) != Some(filters))
+                .map(|(id, _)| id)
+                .collect();
+            assert!(changed.len() <= 1, "changed chunks: {changed:?}");
+
+            let added = after.keys().filter(|id| !before.contains_key(*id)).count();
+            assert!(added <= 2, "added chunks: {added}");
+        }
+        Ok(())
+    }
+
+    fn by_id(chunks: Vec<Chunk>) -> HashMap<String, Vec<Vec<domain::PubKey>>> {
+        chunks
+            .into_iter()
+            .map(|chunk| (chunk.id, chunk.filters))
+            .collect()
+    }
+
+    fn covered(chunks: &[Chunk]) -> HashSet<domain::PubKey> {
+        chunks
+            .iter()
+            .flat_map(|chunk| chunk.filters())
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    fn some_pub_keys(n: usize) -> HashSet<domain::PubKey> {
+        (0..n).map(|_| fixtures::some_pub_key()).collect()
+    }
+}
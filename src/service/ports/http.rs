@@ -1,71 +1,2003 @@
+pub mod rate_limits;
+
 use crate::errors::Result;
 use crate::service::app;
-use crate::service::app::commands::Register;
+use crate::service::app::commands::{LimitExceededError, OutdatedEventError, Register, Unregister};
+use crate::service::app::common::RegistrationInfo;
+use crate::service::app::queries::GetRegistration;
 use crate::service::domain;
+use crate::service::domain::encryption;
 use crate::service::domain::events;
-use crossbeam::thread;
 use nostr::ClientMessage;
-use std::net::TcpListener;
+use rate_limits::{RateLimiter, RateLimits};
+use serde_json::json;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use tungstenite;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, WebSocketConfig};
+
+pub(super) const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+pub struct Config {
+    address: String,
+    events: Events,
+    service: String,
+    service_keys: nostr::Keys,
+    service_secret_key: nostr::secp256k1::SecretKey,
+    auth: Auth,
+    limits: Limits,
+    rate_limits: RateLimits,
+}
+
+impl Config {
+    pub fn new(
+        address: String,
+        events: Events,
+        service: String,
+        service_keys: &nostr::Keys,
+        auth: Auth,
+        limits: Limits,
+        rate_limits: RateLimits,
+    ) -> Result<Config> {
+        if address.is_empty() {
+            return Err("empty address".into());
+        }
+
+        if service.is_empty() {
+            return Err("empty service".into());
+        }
+
+        Ok(Config {
+            address,
+            events,
+            service,
+            service_keys: service_keys.clone(),
+            service_secret_key: service_keys.secret_key()?,
+            auth,
+            limits,
+            rate_limits,
+        })
+    }
+}
+
+// Events which clients send to the server. Events created further than the freshness window from
+// the current time are rejected so that old events can't be replayed.
+pub struct Events {
+    registration_kind: nostr::Kind,
+    unregistration_kind: nostr::Kind,
+    freshness_window: Duration,
+}
+
+impl Events {
+    pub fn new(
+        registration_kind: nostr::Kind,
+        unregistration_kind: nostr::Kind,
+        freshness_window: Duration,
+    ) -> Result<Events> {
+        if registration_kind.as_u64() == unregistration_kind.as_u64() {
+            return Err("registration and unregistration kinds must be different".into());
+        }
+
+        if freshness_window.is_zero() {
+            return Err("freshness window must be greater than zero".into());
+        }
+
+        Ok(Events {
+            registration_kind,
+            unregistration_kind,
+            freshness_window,
+        })
+    }
+}
+
+// NIP-42 authentication. Clients are always challenged but answering the challenge is only
+// mandatory if authentication is required so that older clients can still register.
+pub struct Auth {
+    relay: nostr::Url,
+    required: bool,
+}
+
+impl Auth {
+    pub fn new(relay: nostr::Url, required: bool) -> Auth {
+        Auth { relay, required }
+    }
+}
+
+// Limits which protect the server from clients which misbehave or open too many connections.
+// The read timeout applies to every single read or write and to the handshake as a whole. The idle
+// timeout is the time after which connections which didn't send a complete message are closed,
+// bytes which don't complete a message don't count.
+pub struct Limits {
+    max_connections: usize,
+    max_message_size: usize,
+    read_timeout: Duration,
+    idle_timeout: Duration,
+}
+
+impl Limits {
+    pub fn new(
+        max_connections: usize,
+        max_message_size: usize,
+        read_timeout: Duration,
+        idle_timeout: Duration,
+    ) -> Result<Limits> {
+        if max_connections == 0 {
+            return Err("max connections must be greater than zero".into());
+        }
+
+        if max_message_size == 0 {
+            return Err("max message size must be greater than zero".into());
+        }
+
+        if read_timeout.is_zero() {
+            return Err("read timeout must be greater than zero".into());
+        }
+
+        if idle_timeout < read_timeout {
+            return Err("idle timeout can't be shorter than the read timeout".into());
+        }
+
+        Ok(Limits {
+            max_connections,
+            max_message_size,
+            read_timeout,
+            idle_timeout,
+        })
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        self.read_timeout
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: 1024,
+            max_message_size: 64 * 1024,
+            read_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+struct Connection {
+    address: IpAddr,
+    challenge: String,
+    authenticated_pub_key: Option<nostr::key::XOnlyPublicKey>,
+}
+
+impl Connection {
+    fn new(address: IpAddr) -> Self {
+        let challenge: [u8; 16] = rand::random();
+        Self {
+            address,
+            challenge: hex::encode(challenge),
+            authenticated_pub_key: None,
+        }
+    }
+}
 
 pub struct Server<'a> {
     app: &'a app::Application<'a>,
+    config: Config,
+    rate_limiter: RateLimiter,
 }
 
 impl<'a> Server<'_> {
-    pub fn new(app: &'a app::Application) -> Server<'a> {
-        Server { app }
+    pub fn new(app: &'a app::Application, config: Config) -> Server<'a> {
+        let rate_limiter = RateLimiter::new("websocket", &config.rate_limits);
+        Server {
+            app,
+            config,
+            rate_limiter,
+        }
     }
 
-    pub fn listen_and_serve(&self) {
-        let address = String::from("127.0.0.1:7878");
-        let listener = TcpListener::bind(address).unwrap();
+    pub fn listen_and_serve(&self) -> Result<()> {
+        let listener = TcpListener::bind(&self.config.address)
+            .map_err(|err| format!("error listening on '{}': {err}", self.config.address))?;
+        self.serve(listener)
+    }
+
+    // std::thread::scope is used instead of crossbeam as crossbeam keeps the handles of all
+    // spawned threads until the scope ends which would never happen here.
+    fn serve(&self, listener: TcpListener) -> Result<()> {
+        let open_connections = AtomicUsize::new(0);
 
         thread::scope(|s| {
             for stream in listener.incoming() {
-                s.spawn(|_| {
-                    let mut websocket = tungstenite::accept(stream.unwrap()).unwrap();
-                    loop {
-                        let msg = websocket.read_message().unwrap();
-                        if let Err(err) = self.handle_message(msg) {
-                            println!("error handling the received message: {err}");
-                            break;
-                        }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        // Errors such as running out of file descriptors usually persist for a
+                        // while, retrying right away would only spin.
+                        log::warn!("error accepting a connection: {err}");
+                        thread::sleep(ACCEPT_ERROR_DELAY);
+                        continue;
+                    }
+                };
+
+                let slot = match ConnectionSlot::acquire(
+                    &open_connections,
+                    self.config.limits.max_connections,
+                ) {
+                    Some(slot) => slot,
+                    None => {
+                        log::warn!("too many open connections, dropping a new connection");
+                        continue;
+                    }
+                };
+
+                s.spawn(move || {
+                    let _slot = slot;
+                    if let Err(err) = self.serve_connection(stream) {
+                        log::debug!("connection error: {err}");
                     }
                 });
             }
-        })
-        .unwrap();
+        });
+
+        Ok(())
     }
 
-    fn handle_message(&self, msg: tungstenite::Message) -> Result<()> {
+    fn serve_connection(&self, stream: TcpStream) -> Result<()> {
+        let limits = &self.config.limits;
+        let address = stream.peer_addr()?.ip();
+        stream.set_write_timeout(Some(limits.read_timeout))?;
+        let stream = DeadlineStream::new(
+            stream,
+            limits.read_timeout,
+            Instant::now() + limits.read_timeout,
+        );
+
+        let websocket_config = WebSocketConfig {
+            max_message_size: Some(limits.max_message_size),
+            max_frame_size: Some(limits.max_message_size),
+            ..Default::default()
+        };
+        let mut websocket = tungstenite::accept_with_config(stream, Some(websocket_config))
+            .map_err(|err| format!("handshake failed: {err}"))?;
+
+        let mut connection = Connection::new(address);
+        let challenge = Reply::Auth(connection.challenge.clone());
+        send_replies(&mut websocket, vec![challenge])?;
+
+        websocket
+            .get_mut()
+            .set_deadline(Instant::now() + limits.idle_timeout);
+        loop {
+            let msg = match websocket.read_message() {
+                Ok(msg) => msg,
+                Err(tungstenite::Error::Io(err)) if is_timeout(&err) => {
+                    if !websocket.get_ref().deadline_passed() {
+                        continue;
+                    }
+                    close(&mut websocket, CloseCode::Away, "idle timeout");
+                    return Ok(());
+                }
+                Err(tungstenite::Error::ConnectionClosed)
+                | Err(tungstenite::Error::AlreadyClosed) => return Ok(()),
+                Err(tungstenite::Error::Capacity(err)) => {
+                    close(&mut websocket, CloseCode::Size, "message too big");
+                    return Err(err.into());
+                }
+                Err(tungstenite::Error::Protocol(err)) => {
+                    close(&mut websocket, CloseCode::Protocol, "protocol error");
+                    return Err(err.into());
+                }
+                Err(err) => return Err(err.into()),
+            };
+            websocket
+                .get_mut()
+                .set_deadline(Instant::now() + limits.idle_timeout);
+
+            if msg.is_close() {
+                // tungstenite queues the reply to the close frame, it has to be flushed.
+                return match websocket.write_pending() {
+                    Ok(_) | Err(tungstenite::Error::ConnectionClosed) => Ok(()),
+                    Err(err) => Err(err.into()),
+                };
+            }
+
+            let replies = self.handle_message(&mut connection, msg)?;
+            send_replies(&mut websocket, replies)?;
+        }
+    }
+
+    fn handle_message(
+        &self,
+        connection: &mut Connection,
+        msg: tungstenite::Message,
+    ) -> Result<Vec<Reply>> {
         if msg.is_ping() || msg.is_pong() {
-            return Ok(());
+            return Ok(vec![]);
         }
 
-        let msg_text = msg.into_text()?;
-        let client_message = nostr::ClientMessage::from_json(msg_text)?;
+        // Every message counts towards the limit of the address, not only events, as otherwise
+        // AUTH and REQ messages could be used to flood the server.
+        let ip_rate_limit = self.check_ip_rate_limit(connection);
+
+        let msg_text = match msg.into_text() {
+            Ok(msg_text) => msg_text,
+            Err(err) => {
+                return Ok(vec![Reply::Notice(format!(
+                    "could not parse the message: {err}"
+                ))])
+            }
+        };
+        let client_message = match parse_client_message(&msg_text) {
+            Ok(client_message) => client_message,
+            Err(err) => {
+                return Ok(vec![Reply::Notice(format!(
+                    "could not parse the message: {err}"
+                ))])
+            }
+        };
+
+        if let Err(rejection) = ip_rate_limit {
+            return Ok(vec![Reply::rejected(&client_message, rejection)]);
+        }
 
         match client_message {
-            ClientMessage::Event(event) => {
-                // todo check if right event?
-
-                let registration_event_content: events::RegistrationEventContent =
-                    serde_json::from_str(&event.content)?;
-                let pub_key = domain::PubKey::new(event.pubkey);
-                let apns_token = domain::APNSToken::new(registration_event_content.apns_token)?;
-                let relays: Result<Vec<domain::RelayAddress>> = registration_event_content
-                    .relays
-                    .iter()
-                    .map(|v| domain::RelayAddress::new(v.clone()))
+            IncomingMessage::Event(event) => {
+                let result = self.handle_event(connection, &event);
+                Ok(vec![Reply::ok(event.event(), result)])
+            }
+            IncomingMessage::Auth(event) => {
+                let result = self.handle_auth(connection, &event);
+                Ok(vec![Reply::ok(event.event(), result)])
+            }
+            IncomingMessage::Other(ClientMessage::Req {
+                subscription_id,
+                filters,
+            }) => Ok(self.handle_req(connection, subscription_id, &filters)),
+            IncomingMessage::Other(ClientMessage::Count {
+                subscription_id, ..
+            }) => Ok(vec![Reply::Closed {
+                subscription_id,
+                message: Rejection::Unsupported("counting events is not supported".into())
+                    .to_string(),
+            }]),
+            // Subscriptions are never kept open so there is nothing to close.
+            IncomingMessage::Other(ClientMessage::Close(_)) => Ok(vec![]),
+            IncomingMessage::Other(_) => Ok(vec![Reply::Notice("unsupported message".into())]),
+        }
+    }
+
+    // Requests for registrations are answered with a single event for every registered device
+    // followed by EOSE. The subscription isn't kept open.
+    fn handle_req(
+        &self,
+        connection: &Connection,
+        subscription_id: nostr::SubscriptionId,
+        filters: &[nostr::Filter],
+    ) -> Vec<Reply> {
+        let result = self.query_registrations(connection, filters);
+
+        match result {
+            Ok(events) => {
+                let mut replies: Vec<Reply> = events
+                    .into_iter()
+                    .map(|event| Reply::Event {
+                        subscription_id: subscription_id.clone(),
+                        event: Box::new(event),
+                    })
                     .collect();
-                let locale = domain::Locale::new(registration_event_content.locale)?;
+                replies.push(Reply::EndOfStoredEvents(subscription_id));
+                replies
+            }
+            Err(rejection) => vec![Reply::Closed {
+                subscription_id,
+                message: rejection.to_string(),
+            }],
+        }
+    }
+
+    // Only the registrations of the authenticated pub key can be queried so that nobody can
+    // learn which relays and devices someone else uses.
+    fn query_registrations(
+        &self,
+        connection: &Connection,
+        filters: &[nostr::Filter],
+    ) -> std::result::Result<Vec<nostr::Event>, Rejection> {
+        let registration_kind = self.config.events.registration_kind.as_u64();
+        let is_registration_filter = |filter: &nostr::Filter| {
+            filter
+                .kinds
+                .as_ref()
+                .is_some_and(|kinds| kinds.iter().any(|kind| kind.as_u64() == registration_kind))
+        };
+        if filters.is_empty() || !filters.iter().all(is_registration_filter) {
+            return Err(Rejection::Unsupported(
+                "subscriptions are not supported".into(),
+            ));
+        }
+
+        let pub_key = connection
+            .authenticated_pub_key
+            .ok_or_else(|| Rejection::AuthRequired("authenticate to query registrations".into()))?;
+
+        let hex_pub_key = pub_key.to_string();
+        let is_own_filter = |filter: &nostr::Filter| {
+            filter.authors.as_ref().is_some_and(|authors| {
+                !authors.is_empty() && authors.iter().all(|author| *author == hex_pub_key)
+            })
+        };
+        if !filters.iter().all(is_own_filter) {
+            return Err(Rejection::Restricted(
+                "registrations can only be queried for the authenticated pub key".into(),
+            ));
+        }
+
+        let query = GetRegistration {
+            pub_key: domain::PubKey::new(pub_key),
+        };
+        self.app
+            .queries
+            .get_registration
+            .handle(&query)
+            .and_then(|registrations| {
+                registrations
+                    .iter()
+                    .map(|registration| self.registration_status_event(&pub_key, registration))
+                    .collect()
+            })
+            .map_err(|err| Rejection::Error(err.to_string()))
+    }
+
+    // Status events are signed by the service and tag the pub key which they describe.
+    fn registration_status_event(
+        &self,
+        pub_key: &nostr::key::XOnlyPublicKey,
+        registration: &RegistrationInfo,
+    ) -> Result<nostr::Event> {
+        let content = events::RegistrationStatusContent::new(
+            &registration.push_token(),
+            &registration.relays(),
+            &registration.locale(),
+            &registration.preferences(),
+            registration.quiet_hours().as_ref(),
+            registration.updated_at(),
+        );
+        let event = nostr::EventBuilder::new(
+            self.config.events.registration_kind,
+            serde_json::to_string(&content)?,
+            &[nostr::Tag::PubKey(*pub_key, None)],
+        )
+        .to_event(&self.config.service_keys)?;
+        Ok(event)
+    }
+
+    // The IP is checked before the message is handled so that flooding the server is cheap to
+    // reject. The pub key is checked only once the signature is known to be valid as otherwise
+    // anyone could use up the limit of someone else's pub key.
+    fn check_ip_rate_limit(&self, connection: &Connection) -> std::result::Result<(), Rejection> {
+        if !self
+            .rate_limiter
+            .check_ip(connection.address, Instant::now())
+        {
+            log::debug!("rate limited messages from {}", connection.address);
+            return Err(Rejection::RateLimited(
+                "too many messages sent from this address".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_pub_key_rate_limit(
+        &self,
+        pub_key: nostr::key::XOnlyPublicKey,
+    ) -> std::result::Result<(), Rejection> {
+        if !self.rate_limiter.check_pub_key(pub_key, Instant::now()) {
+            log::debug!("rate limited events from {pub_key}");
+            return Err(Rejection::RateLimited(
+                "too many events sent for this pub key".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn handle_auth(
+        &self,
+        connection: &mut Connection,
+        event: &events::ReceivedEvent,
+    ) -> std::result::Result<(), Rejection> {
+        let event = event.verify().map_err(Rejection::invalid)?;
+        events::validate_auth_event(
+            event,
+            &connection.challenge,
+            &self.config.auth.relay,
+            nostr::Timestamp::now(),
+        )
+        .map_err(Rejection::invalid)?;
+
+        connection.authenticated_pub_key = Some(event.pubkey);
+        Ok(())
+    }
 
-                let registration = domain::Registration::new(pub_key, apns_token, relays?, locale)?;
-                let cmd = Register { registration };
-                self.app.commands.register.handle(&cmd)
+    fn handle_event(
+        &self,
+        connection: &Connection,
+        event: &events::ReceivedEvent,
+    ) -> std::result::Result<(), Rejection> {
+        let event = event.verify().map_err(Rejection::invalid)?;
+
+        match connection.authenticated_pub_key {
+            Some(pub_key) if pub_key != event.pubkey => {
+                return Err(Rejection::Restricted(
+                    "connection is authenticated as a different pub key".into(),
+                ))
+            }
+            Some(_) => {}
+            None if self.config.auth.required => {
+                return Err(Rejection::AuthRequired(
+                    "authenticate before sending events".into(),
+                ))
             }
-            _ => Ok(()),
+            None => {}
+        }
+
+        self.check_pub_key_rate_limit(event.pubkey)?;
+
+        let kind = event.kind.as_u64();
+        if kind != self.config.events.registration_kind.as_u64()
+            && kind != self.config.events.unregistration_kind.as_u64()
+        {
+            return Err(Rejection::Invalid(format!("unexpected event kind: {kind}")));
+        }
+
+        events::validate_created_at(
+            event,
+            self.config.events.freshness_window,
+            nostr::Timestamp::now(),
+        )
+        .map_err(Rejection::invalid)?;
+
+        if kind == self.config.events.registration_kind.as_u64() {
+            self.handle_registration(event)
+        } else {
+            self.handle_unregistration(event)
+        }
+    }
+
+    fn handle_registration(&self, event: &nostr::Event) -> std::result::Result<(), Rejection> {
+        let content = self.read_content(event)?;
+        let registration_event_content: events::RegistrationEventContent =
+            serde_json::from_str(&content).map_err(Rejection::invalid)?;
+        let registration = registration_event_content
+            .registration(event)
+            .map_err(Rejection::invalid)?;
+        let cmd = Register {
+            registration,
+            content_size: content.len(),
+        };
+        self.app
+            .commands
+            .register
+            .handle(&cmd)
+            .map_err(Rejection::from_handler_error)
+    }
+
+    fn handle_unregistration(&self, event: &nostr::Event) -> std::result::Result<(), Rejection> {
+        let content = self.read_content(event)?;
+        let unregistration_event_content: events::UnregistrationEventContent =
+            serde_json::from_str(&content).map_err(Rejection::invalid)?;
+
+        let cmd = Unregister {
+            pub_key: domain::PubKey::new(event.pubkey),
+            push_token: unregistration_event_content
+                .push_token()
+                .map_err(Rejection::invalid)?,
+            event: events::event_info(event),
+        };
+        self.app
+            .commands
+            .unregister
+            .handle(&cmd)
+            .map_err(Rejection::from_handler_error)
+    }
+
+    fn read_content(&self, event: &nostr::Event) -> std::result::Result<String, Rejection> {
+        events::validate_tags(event, &self.config.service).map_err(Rejection::invalid)?;
+
+        encryption::decrypt(
+            &self.config.service_secret_key,
+            &event.pubkey,
+            &event.content,
+        )
+        .map_err(|err| Rejection::Invalid(format!("could not decrypt the content: {err}")))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Ok {
+        event_id: nostr::EventId,
+        accepted: bool,
+        message: String,
+    },
+    Closed {
+        subscription_id: nostr::SubscriptionId,
+        message: String,
+    },
+    Event {
+        subscription_id: nostr::SubscriptionId,
+        event: Box<nostr::Event>,
+    },
+    EndOfStoredEvents(nostr::SubscriptionId),
+    Notice(String),
+    Auth(String),
+}
+
+impl Reply {
+    fn ok(event: &nostr::Event, result: std::result::Result<(), Rejection>) -> Self {
+        match result {
+            Ok(_) => Reply::Ok {
+                event_id: event.id,
+                accepted: true,
+                message: String::new(),
+            },
+            Err(rejection) => Reply::Ok {
+                event_id: event.id,
+                accepted: false,
+                message: rejection.to_string(),
+            },
+        }
+    }
+
+    // Rejections are reported in the way which the client expects for the given message.
+    fn rejected(client_message: &IncomingMessage, rejection: Rejection) -> Self {
+        match client_message {
+            IncomingMessage::Event(event) | IncomingMessage::Auth(event) => {
+                Reply::ok(event.event(), Err(rejection))
+            }
+            IncomingMessage::Other(ClientMessage::Req {
+                subscription_id, ..
+            })
+            | IncomingMessage::Other(ClientMessage::Count {
+                subscription_id, ..
+            }) => Reply::Closed {
+                subscription_id: subscription_id.clone(),
+                message: rejection.to_string(),
+            },
+            IncomingMessage::Other(_) => Reply::Notice(rejection.to_string()),
+        }
+    }
+
+    fn as_json(&self) -> String {
+        match self {
+            Reply::Ok {
+                event_id,
+                accepted,
+                message,
+            } => json!(["OK", event_id, accepted, message]),
+            Reply::Closed {
+                subscription_id,
+                message,
+            } => json!(["CLOSED", subscription_id, message]),
+            Reply::Event {
+                subscription_id,
+                event,
+            } => json!(["EVENT", subscription_id, event]),
+            Reply::EndOfStoredEvents(subscription_id) => json!(["EOSE", subscription_id]),
+            Reply::Notice(message) => json!(["NOTICE", message]),
+            Reply::Auth(challenge) => json!(["AUTH", challenge]),
+        }
+        .to_string()
+    }
+}
+
+// Reasons for refusing an event which are reported to the clients using the machine-readable
+// prefixes defined in NIP-01.
+#[derive(Debug, PartialEq, Eq)]
+enum Rejection {
+    Invalid(String),
+    AuthRequired(String),
+    Restricted(String),
+    RateLimited(String),
+    Unsupported(String),
+    Error(String),
+}
+
+impl Rejection {
+    fn invalid(err: impl fmt::Display) -> Self {
+        Rejection::Invalid(err.to_string())
+    }
+
+    // Outdated events are the client's fault and exceeded limits are reported as restrictions,
+    // everything else is an internal error.
+    fn from_handler_error(err: Box<dyn std::error::Error>) -> Self {
+        if err.is::<OutdatedEventError>() {
+            Rejection::Invalid(err.to_string())
+        } else if err.is::<LimitExceededError>() {
+            Rejection::Restricted(err.to_string())
+        } else {
+            Rejection::Error(err.to_string())
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Invalid(reason) => write!(f, "invalid: {reason}"),
+            Rejection::AuthRequired(reason) => write!(f, "auth-required: {reason}"),
+            Rejection::Restricted(reason) => write!(f, "restricted: {reason}"),
+            Rejection::RateLimited(reason) => write!(f, "rate-limited: {reason}"),
+            Rejection::Unsupported(reason) => write!(f, "unsupported: {reason}"),
+            Rejection::Error(reason) => write!(f, "error: {reason}"),
+        }
+    }
+}
+
+// Counts towards the limit of open connections until dropped, even if the connection thread
+// panics.
+pub(super) struct ConnectionSlot<'a> {
+    open_connections: &'a AtomicUsize,
+}
+
+impl<'a> ConnectionSlot<'a> {
+    pub(super) fn acquire(
+        open_connections: &'a AtomicUsize,
+        max_connections: usize,
+    ) -> Option<Self> {
+        open_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max_connections).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot { open_connections })
+    }
+}
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Reads fail once the deadline passes no matter how often the client sends a single byte, the read
+// timeout of the socket is shortened accordingly before every read.
+pub(super) struct DeadlineStream {
+    stream: TcpStream,
+    read_timeout: Duration,
+    deadline: Instant,
+}
+
+impl DeadlineStream {
+    pub(super) fn new(stream: TcpStream, read_timeout: Duration, deadline: Instant) -> Self {
+        Self {
+            stream,
+            read_timeout,
+            deadline,
+        }
+    }
+
+    pub(super) fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+    }
+
+    fn deadline_passed(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline exceeded"));
+        }
+        self.stream
+            .set_read_timeout(Some(remaining.min(self.read_timeout)))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+pub(super) fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// Closes the connection on a best effort basis, the connection is dropped right after anyway.
+fn close<S>(websocket: &mut tungstenite::WebSocket<S>, code: CloseCode, reason: &str)
+where
+    S: Read + Write,
+{
+    let frame = CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    };
+    if websocket.close(Some(frame)).is_ok() {
+        let _ = websocket.write_pending();
+    }
+}
+
+fn send_replies<S>(websocket: &mut tungstenite::WebSocket<S>, replies: Vec<Reply>) -> Result<()>
+where
+    S: Read + Write,
+{
+    for reply in replies {
+        websocket.write_message(tungstenite::Message::Text(reply.as_json()))?;
+    }
+    Ok(())
+}
+
+// nostr::ClientMessage::from_json verifies events on its own but reports all failures in the same
+// way so events are deserialized here and verified later, see events::ReceivedEvent.
+fn parse_client_message(text: &str) -> Result<IncomingMessage> {
+    let value: serde_json::Value = serde_json::from_str(text)?;
+
+    if let Some(array) = value.as_array() {
+        if array.len() == 2 && array[0] == "EVENT" {
+            let event = events::ReceivedEvent::new(array[1].clone())?;
+            return Ok(IncomingMessage::Event(event));
+        }
+
+        if array.len() == 2 && array[0] == "AUTH" {
+            let event = events::ReceivedEvent::new(array[1].clone())?;
+            return Ok(IncomingMessage::Auth(event));
+        }
+    }
+
+    Ok(IncomingMessage::Other(ClientMessage::from_value(value)?))
+}
+
+enum IncomingMessage {
+    Event(events::ReceivedEvent),
+    Auth(events::ReceivedEvent),
+    Other(ClientMessage),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::fixtures::{GetRegistrationHandlerMock, RegisterHandlerMock, UnregisterHandlerMock};
+
+    #[test]
+    fn registration_events_are_passed_to_the_register_handler() {
+        let keys = fixtures::some_keys();
+        let event = fixtures::some_registration_event(&keys);
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(replies, vec![ok(&event, true, "")]);
+        assert_eq!(
+            handler.pub_keys(),
+            vec![domain::PubKey::new(keys.public_key())]
+        );
+    }
+
+    #[test]
+    fn tampered_events_are_rejected() {
+        let event =
+            fixtures::tampered_event(fixtures::some_registration_event(&fixtures::some_keys()));
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(
+            replies,
+            vec![ok(&event, false, "invalid: invalid event id")]
+        );
+        assert!(handler.pub_keys().is_empty());
+    }
+
+    #[test]
+    fn forged_events_are_rejected() {
+        let victim = fixtures::some_keys();
+        let event = fixtures::forged_event(
+            fixtures::some_registration_event(&fixtures::some_keys()),
+            victim.public_key(),
+        );
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(
+            replies,
+            vec![ok(&event, false, "invalid: invalid signature")]
+        );
+        assert!(handler.pub_keys().is_empty());
+    }
+
+    #[test]
+    fn events_of_other_kinds_are_rejected() {
+        let event = fixtures::registration_event(
+            &fixtures::some_keys(),
+            nostr::Kind::TextNote,
+            vec![fixtures::service_tag(fixtures::SERVICE)],
+        );
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(
+            replies,
+            vec![ok(&event, false, "invalid: unexpected event kind: 1")]
+        );
+        assert!(handler.pub_keys().is_empty());
+    }
+
+    #[test]
+    fn events_addressed_to_other_services_are_rejected() {
+        let event = fixtures::registration_event(
+            &fixtures::some_keys(),
+            nostr::Kind::from(events::DEFAULT_REGISTRATION_KIND),
+            vec![fixtures::service_tag("other-service")],
+        );
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(
+            replies,
+            vec![ok(
+                &event,
+                false,
+                "invalid: event is addressed to a different service: 'other-service'"
+            )]
+        );
+        assert!(handler.pub_keys().is_empty());
+    }
+
+    #[test]
+    fn nip04_encrypted_events_are_accepted() {
+        let keys = fixtures::some_keys();
+        let content = nostr::nips::nip04::encrypt(
+            &keys.secret_key().unwrap(),
+            &fixtures::service_keys().public_key(),
+            serde_json::to_string(&fixtures::some_registration_event_content()).unwrap(),
+        )
+        .unwrap();
+        let event = fixtures::event_with_content(
+            &keys,
+            nostr::Kind::from(events::DEFAULT_REGISTRATION_KIND),
+            vec![fixtures::service_tag(fixtures::SERVICE)],
+            content,
+        );
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(replies, vec![ok(&event, true, "")]);
+        assert_eq!(
+            handler.pub_keys(),
+            vec![domain::PubKey::new(keys.public_key())]
+        );
+    }
+
+    #[test]
+    fn registrations_with_invalid_locales_are_rejected() {
+        let keys = fixtures::some_keys();
+        let mut content = fixtures::some_registration_event_content();
+        content.locale = String::from("en US");
+        let event = fixtures::event_with_content(
+            &keys,
+            nostr::Kind::from(events::DEFAULT_REGISTRATION_KIND),
+            vec![fixtures::service_tag(fixtures::SERVICE)],
+            fixtures::encrypt_for_service(&keys, &content),
+        );
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(
+            replies,
+            vec![ok(
+                &event,
+                false,
+                "invalid: invalid locale 'en US': the given language subtag is invalid"
+            )]
+        );
+        assert!(handler.pub_keys().is_empty());
+    }
+
+    #[test]
+    fn events_with_plaintext_content_are_rejected() {
+        let event = fixtures::event_with_content(
+            &fixtures::some_keys(),
+            nostr::Kind::from(events::DEFAULT_REGISTRATION_KIND),
+            vec![fixtures::service_tag(fixtures::SERVICE)],
+            serde_json::to_string(&fixtures::some_registration_event_content()).unwrap(),
+        );
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(replies.len(), 1);
+        match &replies[0] {
+            Reply::Ok {
+                accepted, message, ..
+            } => {
+                assert!(!accepted);
+                assert!(message.starts_with("invalid: could not decrypt the content"));
+            }
+            reply => panic!("unexpected reply: {reply:?}"),
+        }
+        assert!(handler.pub_keys().is_empty());
+    }
+
+    #[test]
+    fn events_encrypted_to_other_keys_are_rejected() {
+        let keys = fixtures::some_keys();
+        let conversation_key = encryption::nip44::ConversationKey::new(
+            &keys.secret_key().unwrap(),
+            &fixtures::some_keys().public_key(),
+        );
+        let content = encryption::nip44::encrypt(
+            &conversation_key,
+            &serde_json::to_string(&fixtures::some_registration_event_content()).unwrap(),
+        )
+        .unwrap();
+        let event = fixtures::event_with_content(
+            &keys,
+            nostr::Kind::from(events::DEFAULT_REGISTRATION_KIND),
+            vec![fixtures::service_tag(fixtures::SERVICE)],
+            content,
+        );
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(
+            replies,
+            vec![ok(
+                &event,
+                false,
+                "invalid: could not decrypt the content: invalid mac"
+            )]
+        );
+    }
+
+    #[test]
+    fn unregistration_events_are_passed_to_the_unregister_handler() {
+        let keys = fixtures::some_keys();
+        let event = fixtures::some_unregistration_event(&keys, &fixtures::some_push_token());
+
+        let register = RegisterHandlerMock::new();
+        let unregister = UnregisterHandlerMock::new();
+        let replies = handle_with(
+            &register,
+            &unregister,
+            ClientMessage::new_event(event.clone()).as_json(),
+        );
+
+        assert_eq!(replies, vec![ok(&event, true, "")]);
+        assert_eq!(
+            unregister.commands(),
+            vec![(
+                domain::PubKey::new(keys.public_key()),
+                String::from(fixtures::APNS_TOKEN)
+            )]
+        );
+        assert!(register.pub_keys().is_empty());
+    }
+
+    #[test]
+    fn forged_unregistration_events_are_rejected() {
+        let victim = fixtures::some_keys();
+        let event = fixtures::forged_event(
+            fixtures::some_unregistration_event(
+                &fixtures::some_keys(),
+                &fixtures::some_push_token(),
+            ),
+            victim.public_key(),
+        );
+
+        let register = RegisterHandlerMock::new();
+        let unregister = UnregisterHandlerMock::new();
+        let replies = handle_with(
+            &register,
+            &unregister,
+            ClientMessage::new_event(event.clone()).as_json(),
+        );
+
+        assert_eq!(
+            replies,
+            vec![ok(&event, false, "invalid: invalid signature")]
+        );
+        assert!(unregister.commands().is_empty());
+    }
+
+    #[test]
+    fn clients_can_authenticate() {
+        let keys = fixtures::some_keys();
+        let mut connection = Connection::new(localhost());
+        let auth_event = fixtures::auth_event(&keys, &connection.challenge, fixtures::RELAY);
+
+        let replies = handle_all(
+            &RegisterHandlerMock::new(),
+            &UnregisterHandlerMock::new(),
+            true,
+            &mut connection,
+            vec![ClientMessage::new_auth(auth_event.clone()).as_json()],
+        );
+
+        assert_eq!(replies, vec![vec![ok(&auth_event, true, "")]]);
+        assert_eq!(connection.authenticated_pub_key, Some(keys.public_key()));
+    }
+
+    #[test]
+    fn auth_events_with_incorrect_challenge_are_rejected() {
+        let mut connection = Connection::new(localhost());
+        let auth_event =
+            fixtures::auth_event(&fixtures::some_keys(), "other-challenge", fixtures::RELAY);
+
+        let replies = handle_all(
+            &RegisterHandlerMock::new(),
+            &UnregisterHandlerMock::new(),
+            true,
+            &mut connection,
+            vec![ClientMessage::new_auth(auth_event.clone()).as_json()],
+        );
+
+        assert_eq!(
+            replies,
+            vec![vec![ok(
+                &auth_event,
+                false,
+                "invalid: challenge doesn't match"
+            )]]
+        );
+        assert_eq!(connection.authenticated_pub_key, None);
+    }
+
+    #[test]
+    fn authenticated_connections_can_only_register_their_own_pub_key() {
+        let keys = fixtures::some_keys();
+        let mut connection = Connection::new(localhost());
+        let auth_event = fixtures::auth_event(&keys, &connection.challenge, fixtures::RELAY);
+        let own_event = fixtures::some_registration_event(&keys);
+        let other_event = fixtures::some_registration_event(&fixtures::some_keys());
+
+        let register = RegisterHandlerMock::new();
+        let replies = handle_all(
+            &register,
+            &UnregisterHandlerMock::new(),
+            false,
+            &mut connection,
+            vec![
+                ClientMessage::new_auth(auth_event.clone()).as_json(),
+                ClientMessage::new_event(own_event.clone()).as_json(),
+                ClientMessage::new_event(other_event.clone()).as_json(),
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            vec![
+                vec![ok(&auth_event, true, "")],
+                vec![ok(&own_event, true, "")],
+                vec![ok(
+                    &other_event,
+                    false,
+                    "restricted: connection is authenticated as a different pub key"
+                )],
+            ]
+        );
+        assert_eq!(
+            register.pub_keys(),
+            vec![domain::PubKey::new(keys.public_key())]
+        );
+    }
+
+    #[test]
+    fn events_are_rejected_if_auth_is_required_and_connection_is_not_authenticated() {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+
+        let register = RegisterHandlerMock::new();
+        let replies = handle_all(
+            &register,
+            &UnregisterHandlerMock::new(),
+            true,
+            &mut Connection::new(localhost()),
+            vec![ClientMessage::new_event(event.clone()).as_json()],
+        );
+
+        assert_eq!(
+            replies,
+            vec![vec![ok(
+                &event,
+                false,
+                "auth-required: authenticate before sending events"
+            )]]
+        );
+        assert!(register.pub_keys().is_empty());
+    }
+
+    #[test]
+    fn events_created_too_far_from_the_current_time_are_rejected() {
+        let keys = fixtures::some_keys();
+        let now = nostr::Timestamp::now().as_u64();
+        let window = events::DEFAULT_FRESHNESS_WINDOW.as_secs();
+
+        for created_at in [now - window - 60, now + window + 60] {
+            let event = fixtures::with_created_at(
+                &keys,
+                fixtures::some_registration_event(&keys),
+                nostr::Timestamp::from(created_at),
+            );
+
+            let handler = RegisterHandlerMock::new();
+            let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+            assert_eq!(
+                replies,
+                vec![ok(
+                    &event,
+                    false,
+                    "invalid: event was created too far from the current time"
+                )]
+            );
+            assert!(handler.pub_keys().is_empty());
+        }
+    }
+
+    #[test]
+    fn outdated_events_are_rejected() {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+
+        let handler = RegisterHandlerMock::new_outdated();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(
+            replies,
+            vec![ok(
+                &event,
+                false,
+                "invalid: a newer registration is already stored"
+            )]
+        );
+    }
+
+    #[test]
+    fn events_sent_from_the_same_address_are_rate_limited() {
+        let events: Vec<nostr::Event> = (0..3)
+            .map(|_| fixtures::some_registration_event(&nostr::Keys::generate()))
+            .collect();
+
+        let register = RegisterHandlerMock::new();
+        let replies = handle_all_with_rate_limits(
+            &register,
+            &UnregisterHandlerMock::new(),
+            false,
+            RateLimits::new(rate_limit(2), rate_limit(10)),
+            &mut Connection::new(localhost()),
+            events
+                .iter()
+                .map(|event| ClientMessage::new_event(event.clone()).as_json())
+                .collect(),
+        );
+
+        assert_eq!(
+            replies,
+            vec![
+                vec![ok(&events[0], true, "")],
+                vec![ok(&events[1], true, "")],
+                vec![ok(
+                    &events[2],
+                    false,
+                    "rate-limited: too many messages sent from this address"
+                )],
+            ]
+        );
+        assert_eq!(register.pub_keys().len(), 2);
+    }
+
+    #[test]
+    fn all_messages_sent_from_the_same_address_are_rate_limited() {
+        let keys = fixtures::some_keys();
+        let auth_event = fixtures::auth_event(&keys, "challenge", fixtures::RELAY);
+        let event = fixtures::some_registration_event(&keys);
+        let subscription_id = nostr::SubscriptionId::new("sub");
+
+        let replies = handle_all_with_rate_limits(
+            &RegisterHandlerMock::new(),
+            &UnregisterHandlerMock::new(),
+            false,
+            RateLimits::new(rate_limit(1), rate_limit(10)),
+            &mut Connection::new(localhost()),
+            vec![
+                ClientMessage::Close(subscription_id.clone()).as_json(),
+                ClientMessage::new_auth(auth_event.clone()).as_json(),
+                ClientMessage::new_req(subscription_id.clone(), vec![]).as_json(),
+                ClientMessage::new_event(event.clone()).as_json(),
+                ClientMessage::Close(subscription_id.clone()).as_json(),
+            ],
+        );
+
+        let message = "rate-limited: too many messages sent from this address";
+        assert_eq!(
+            replies,
+            vec![
+                vec![],
+                vec![ok(&auth_event, false, message)],
+                vec![Reply::Closed {
+                    subscription_id,
+                    message: message.into(),
+                }],
+                vec![ok(&event, false, message)],
+                vec![Reply::Notice(message.into())],
+            ]
+        );
+    }
+
+    #[test]
+    fn events_sent_for_the_same_pub_key_are_rate_limited() {
+        let keys = fixtures::some_keys();
+        let forged_event = fixtures::forged_event(
+            fixtures::some_registration_event(&nostr::Keys::generate()),
+            keys.public_key(),
+        );
+        let first_event = fixtures::some_registration_event(&keys);
+        let second_event = fixtures::some_unregistration_event(&keys, &fixtures::some_push_token());
+
+        let register = RegisterHandlerMock::new();
+        let unregister = UnregisterHandlerMock::new();
+        let replies = handle_all_with_rate_limits(
+            &register,
+            &unregister,
+            false,
+            RateLimits::new(rate_limit(10), rate_limit(1)),
+            &mut Connection::new(localhost()),
+            vec![
+                ClientMessage::new_event(forged_event.clone()).as_json(),
+                ClientMessage::new_event(first_event.clone()).as_json(),
+                ClientMessage::new_event(second_event.clone()).as_json(),
+            ],
+        );
+
+        assert_eq!(
+            replies,
+            vec![
+                vec![ok(&forged_event, false, "invalid: invalid signature")],
+                vec![ok(&first_event, true, "")],
+                vec![ok(
+                    &second_event,
+                    false,
+                    "rate-limited: too many events sent for this pub key"
+                )],
+            ]
+        );
+        assert_eq!(register.pub_keys().len(), 1);
+        assert!(unregister.commands().is_empty());
+    }
+
+    #[test]
+    fn exceeded_limits_are_reported_to_the_client() {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+
+        let handler = RegisterHandlerMock::new_limited();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(
+            replies,
+            vec![ok(
+                &event,
+                false,
+                "restricted: the service doesn't accept new registrations"
+            )]
+        );
+    }
+
+    #[test]
+    fn handler_errors_are_reported_to_the_client() {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+
+        let handler = RegisterHandlerMock::new_failing();
+        let replies = handle(&handler, ClientMessage::new_event(event.clone()).as_json());
+
+        assert_eq!(replies, vec![ok(&event, false, "error: mock error")]);
+    }
+
+    #[test]
+    fn registrations_are_returned_to_authenticated_clients() -> Result<()> {
+        let keys = fixtures::some_keys();
+        let registration = fixtures::some_registration();
+        let get_registration = GetRegistrationHandlerMock::new(vec![RegistrationInfo::new(
+            registration.push_token(),
+            registration.relays(),
+            registration.locale(),
+            registration.preferences(),
+            None,
+            Some(nostr::Timestamp::from(100)),
+        )]);
+        let mut connection = Connection::new(localhost());
+        connection.authenticated_pub_key = Some(keys.public_key());
+        let subscription_id = nostr::SubscriptionId::new("registration");
+
+        let replies = query(
+            &get_registration,
+            &mut connection,
+            ClientMessage::new_req(
+                subscription_id.clone(),
+                vec![registration_filter(&keys.public_key().to_string())],
+            )
+            .as_json(),
+        );
+
+        assert_eq!(replies.len(), 2);
+        let event = match &replies[0] {
+            Reply::Event {
+                subscription_id: event_subscription_id,
+                event,
+            } => {
+                assert_eq!(event_subscription_id, &subscription_id);
+                event
+            }
+            reply => panic!("unexpected reply: {reply:?}"),
+        };
+        event.verify()?;
+        assert_eq!(event.pubkey, fixtures::service_keys().public_key());
+        assert_eq!(event.kind.as_u64(), events::DEFAULT_REGISTRATION_KIND);
+        assert!(!event.content.contains(registration.push_token().as_ref()));
+        let content: events::RegistrationStatusContent = serde_json::from_str(&event.content)?;
+        assert_eq!(content.updated_at, Some(100));
+        assert_eq!(replies[1], Reply::EndOfStoredEvents(subscription_id));
+        assert_eq!(
+            get_registration.pub_keys(),
+            vec![domain::PubKey::new(keys.public_key())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn registrations_can_only_be_queried_for_the_authenticated_pub_key() {
+        let keys = fixtures::some_keys();
+        let other = fixtures::some_keys();
+        let subscription_id = nostr::SubscriptionId::new("registration");
+
+        let cases = vec![
+            (
+                None,
+                registration_filter(&keys.public_key().to_string()),
+                "auth-required: authenticate to query registrations",
+            ),
+            (
+                Some(keys.public_key()),
+                registration_filter(&other.public_key().to_string()),
+                "restricted: registrations can only be queried for the authenticated pub key",
+            ),
+            (
+                Some(keys.public_key()),
+                nostr::Filter::new().kind(nostr::Kind::from(events::DEFAULT_REGISTRATION_KIND)),
+                "restricted: registrations can only be queried for the authenticated pub key",
+            ),
+        ];
+
+        for (authenticated_pub_key, filter, expected_message) in cases {
+            let get_registration = GetRegistrationHandlerMock::new(vec![]);
+            let mut connection = Connection::new(localhost());
+            connection.authenticated_pub_key = authenticated_pub_key;
+
+            let replies = query(
+                &get_registration,
+                &mut connection,
+                ClientMessage::new_req(subscription_id.clone(), vec![filter]).as_json(),
+            );
+
+            assert_eq!(
+                replies,
+                vec![Reply::Closed {
+                    subscription_id: subscription_id.clone(),
+                    message: String::from(expected_message),
+                }]
+            );
+            assert!(get_registration.pub_keys().is_empty());
+        }
+    }
+
+    #[test]
+    fn missing_registrations_are_reported_with_eose() {
+        let keys = fixtures::some_keys();
+        let mut connection = Connection::new(localhost());
+        connection.authenticated_pub_key = Some(keys.public_key());
+        let subscription_id = nostr::SubscriptionId::new("registration");
+
+        let replies = query(
+            &GetRegistrationHandlerMock::new(vec![]),
+            &mut connection,
+            ClientMessage::new_req(
+                subscription_id.clone(),
+                vec![registration_filter(&keys.public_key().to_string())],
+            )
+            .as_json(),
+        );
+
+        assert_eq!(replies, vec![Reply::EndOfStoredEvents(subscription_id)]);
+    }
+
+    #[test]
+    fn subscriptions_are_closed() {
+        let subscription_id = nostr::SubscriptionId::new("some-subscription");
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(
+            &handler,
+            ClientMessage::new_req(subscription_id.clone(), vec![nostr::Filter::new()]).as_json(),
+        );
+
+        assert_eq!(
+            replies,
+            vec![Reply::Closed {
+                subscription_id,
+                message: String::from("unsupported: subscriptions are not supported"),
+            }]
+        );
+    }
+
+    #[test]
+    fn counts_are_closed() {
+        let subscription_id = nostr::SubscriptionId::new("some-subscription");
+
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(
+            &handler,
+            ClientMessage::new_count(subscription_id.clone(), vec![nostr::Filter::new()]).as_json(),
+        );
+
+        assert_eq!(
+            replies,
+            vec![Reply::Closed {
+                subscription_id,
+                message: String::from("unsupported: counting events is not supported"),
+            }]
+        );
+    }
+
+    #[test]
+    fn closing_subscriptions_is_ignored() {
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(
+            &handler,
+            ClientMessage::close(nostr::SubscriptionId::new("some-subscription")).as_json(),
+        );
+
+        assert_eq!(replies, vec![]);
+    }
+
+    #[test]
+    fn malformed_messages_result_in_a_notice() {
+        let handler = RegisterHandlerMock::new();
+        let replies = handle(&handler, String::from("[\"EVENT\", {}]"));
+
+        assert_eq!(replies.len(), 1);
+        match &replies[0] {
+            Reply::Notice(message) => assert!(message.starts_with("could not parse the message")),
+            reply => panic!("unexpected reply: {reply:?}"),
+        }
+    }
+
+    #[test]
+    fn replies_are_serialized_as_nip_01_messages() {
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+
+        assert_eq!(
+            ok(&event, false, "invalid: reason").as_json(),
+            format!(
+                "[\"OK\",\"{}\",false,\"invalid: reason\"]",
+                event.id.to_hex()
+            )
+        );
+        assert_eq!(
+            Reply::Closed {
+                subscription_id: nostr::SubscriptionId::new("sub"),
+                message: String::from("error: reason"),
+            }
+            .as_json(),
+            "[\"CLOSED\",\"sub\",\"error: reason\"]"
+        );
+        assert_eq!(
+            Reply::Notice(String::from("message")).as_json(),
+            "[\"NOTICE\",\"message\"]"
+        );
+        assert_eq!(
+            Reply::Auth(String::from("challenge")).as_json(),
+            "[\"AUTH\",\"challenge\"]"
+        );
+    }
+
+    #[test]
+    fn server_accepts_registrations() {
+        let address = start_server(Limits::default());
+        let mut websocket = connect(address);
+        assert_eq!(read_reply(&mut websocket)[0], "AUTH");
+
+        let event = fixtures::some_registration_event(&fixtures::some_keys());
+        websocket
+            .write_message(tungstenite::Message::Text(
+                ClientMessage::new_event(event.clone()).as_json(),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            read_reply(&mut websocket),
+            json!(["OK", event.id, true, ""])
+        );
+    }
+
+    #[test]
+    fn server_replies_to_close_frames() {
+        let address = start_server(Limits::default());
+        let mut websocket = connect(address);
+        read_reply(&mut websocket);
+
+        websocket.close(None).unwrap();
+        loop {
+            match websocket.read_message() {
+                Ok(msg) => assert!(msg.is_close()),
+                Err(tungstenite::Error::ConnectionClosed) => break,
+                Err(err) => panic!("unexpected error: {err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn server_survives_clients_which_misbehave() {
+        let address = start_server(Limits::default());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"not a websocket handshake\r\n\r\n")
+            .unwrap();
+        assert_connection_closed(stream);
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: ").unwrap();
+        drop(stream);
+
+        let mut websocket = connect(address);
+        read_reply(&mut websocket);
+        websocket.get_mut().write_all(&[0x81, 0xfe, 0x00]).unwrap();
+        drop(websocket);
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(&[0xff; 1024]).unwrap();
+        assert_connection_closed(stream);
+
+        let mut websocket = connect(address);
+        assert_eq!(read_reply(&mut websocket)[0], "AUTH");
+    }
+
+    #[test]
+    fn server_closes_connections_which_send_unmasked_frames() {
+        let address = start_server(Limits::default());
+        let mut websocket = connect(address);
+        read_reply(&mut websocket);
+
+        websocket
+            .get_mut()
+            .write_all(&[0x81, 0x02, b'h', b'i'])
+            .unwrap();
+        assert_close_code(&mut websocket, CloseCode::Protocol);
+    }
+
+    #[test]
+    fn server_closes_connections_which_send_messages_which_are_too_big() {
+        let address = start_server(Limits::new(10, 1024, ms(500), ms(5000)).unwrap());
+        let mut websocket = connect(address);
+        read_reply(&mut websocket);
+
+        websocket
+            .write_message(tungstenite::Message::Text("a".repeat(2048)))
+            .unwrap();
+        assert_close_code(&mut websocket, CloseCode::Size);
+    }
+
+    #[test]
+    fn server_closes_idle_connections() {
+        let address = start_server(Limits::new(10, 1024, ms(100), ms(300)).unwrap());
+        let mut websocket = connect(address);
+        read_reply(&mut websocket);
+
+        let start = Instant::now();
+        assert_close_code(&mut websocket, CloseCode::Away);
+        assert!(start.elapsed() >= ms(300));
+    }
+
+    #[test]
+    fn server_closes_connections_which_never_finish_the_handshake() {
+        let address = start_server(Limits::new(10, 1024, ms(100), ms(300)).unwrap());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        assert_connection_closed(stream);
+    }
+
+    #[test]
+    fn server_closes_connections_which_trickle_the_handshake() {
+        let address = start_server(Limits::new(10, 1024, ms(200), ms(1000)).unwrap());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let start = Instant::now();
+        for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\n".iter().cycle() {
+            if stream.write_all(&[*byte]).is_err() || start.elapsed() > ms(2000) {
+                break;
+            }
+            thread::sleep(ms(50));
+        }
+        assert!(start.elapsed() < ms(1000));
+    }
+
+    #[test]
+    fn server_closes_connections_which_trickle_partial_messages() {
+        let address = start_server(Limits::new(10, 1024, ms(100), ms(300)).unwrap());
+        let mut websocket = connect(address);
+        read_reply(&mut websocket);
+
+        let start = Instant::now();
+        websocket.get_mut().write_all(&[0x81, 0xfe]).unwrap();
+        while websocket.get_mut().write_all(&[0x00]).is_ok() && start.elapsed() < ms(2000) {
+            thread::sleep(ms(50));
+        }
+        assert!(start.elapsed() < ms(1000));
+    }
+
+    #[test]
+    fn server_limits_the_number_of_open_connections() {
+        let address = start_server(Limits::new(2, 1024, ms(500), ms(5000)).unwrap());
+
+        let mut first = connect(address);
+        read_reply(&mut first);
+        let mut second = connect(address);
+        read_reply(&mut second);
+
+        assert_connection_closed(TcpStream::connect(address).unwrap());
+
+        drop(first);
+        let mut third = retry(|| {
+            let mut websocket =
+                tungstenite::client(format!("ws://{address}"), TcpStream::connect(address).ok()?)
+                    .ok()?
+                    .0;
+            websocket.read_message().ok()?;
+            Some(websocket)
+        });
+        websocket_is_usable(&mut third);
+        websocket_is_usable(&mut second);
+    }
+
+    fn start_server(limits: Limits) -> std::net::SocketAddr {
+        let register: &'static RegisterHandlerMock =
+            Box::leak(Box::new(RegisterHandlerMock::new()));
+        let unregister: &'static UnregisterHandlerMock =
+            Box::leak(Box::new(UnregisterHandlerMock::new()));
+        let commands: &'static app::Commands =
+            Box::leak(Box::new(app::Commands::new(register, unregister)));
+        let get_registration: &'static GetRegistrationHandlerMock =
+            Box::leak(Box::new(GetRegistrationHandlerMock::new(vec![])));
+        let queries: &'static app::Queries =
+            Box::leak(Box::new(app::Queries::new(get_registration)));
+        let app: &'static app::Application =
+            Box::leak(Box::new(app::Application::new(commands, queries)));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = Config::new(
+            address.to_string(),
+            default_events(),
+            String::from(fixtures::SERVICE),
+            &fixtures::service_keys(),
+            Auth::new(nostr::Url::parse(fixtures::RELAY).unwrap(), false),
+            limits,
+            RateLimits::default(),
+        )
+        .unwrap();
+
+        thread::spawn(move || Server::new(app, config).serve(listener).unwrap());
+        address
+    }
+
+    fn connect(address: std::net::SocketAddr) -> tungstenite::WebSocket<TcpStream> {
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(ms(5000))).unwrap();
+        tungstenite::client(format!("ws://{address}"), stream)
+            .unwrap()
+            .0
+    }
+
+    fn read_reply(websocket: &mut tungstenite::WebSocket<TcpStream>) -> serde_json::Value {
+        let msg = websocket.read_message().unwrap();
+        serde_json::from_str(&msg.into_text().unwrap()).unwrap()
+    }
+
+    fn websocket_is_usable(websocket: &mut tungstenite::WebSocket<TcpStream>) {
+        websocket
+            .write_message(tungstenite::Message::Text(String::from("[]")))
+            .unwrap();
+        assert_eq!(read_reply(websocket)[0], "NOTICE");
+    }
+
+    fn assert_close_code(websocket: &mut tungstenite::WebSocket<TcpStream>, code: CloseCode) {
+        match websocket.read_message() {
+            Ok(tungstenite::Message::Close(Some(frame))) => assert_eq!(frame.code, code),
+            other => panic!("expected a close frame, got {other:?}"),
+        }
+    }
+
+    fn assert_connection_closed(mut stream: TcpStream) {
+        stream.set_read_timeout(Some(ms(5000))).unwrap();
+        let mut buf = vec![];
+        match stream.read_to_end(&mut buf) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::ConnectionReset => {}
+            Err(err) => panic!("expected the connection to be closed: {err}"),
+        }
+    }
+
+    fn retry<T>(f: impl Fn() -> Option<T>) -> T {
+        for _ in 0..50 {
+            if let Some(v) = f() {
+                return v;
+            }
+            thread::sleep(ms(20));
+        }
+        panic!("gave up retrying");
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn handle(handler: &RegisterHandlerMock, msg: String) -> Vec<Reply> {
+        handle_with(handler, &UnregisterHandlerMock::new(), msg)
+    }
+
+    fn handle_with(
+        register: &RegisterHandlerMock,
+        unregister: &UnregisterHandlerMock,
+        msg: String,
+    ) -> Vec<Reply> {
+        let mut replies = handle_all(
+            register,
+            unregister,
+            false,
+            &mut Connection::new(localhost()),
+            vec![msg],
+        );
+        replies.remove(0)
+    }
+
+    fn handle_all(
+        register: &RegisterHandlerMock,
+        unregister: &UnregisterHandlerMock,
+        auth_required: bool,
+        connection: &mut Connection,
+        msgs: Vec<String>,
+    ) -> Vec<Vec<Reply>> {
+        handle_all_with_rate_limits(
+            register,
+            unregister,
+            auth_required,
+            RateLimits::default(),
+            connection,
+            msgs,
+        )
+    }
+
+    fn handle_all_with_rate_limits(
+        register: &RegisterHandlerMock,
+        unregister: &UnregisterHandlerMock,
+        auth_required: bool,
+        rate_limits: RateLimits,
+        connection: &mut Connection,
+        msgs: Vec<String>,
+    ) -> Vec<Vec<Reply>> {
+        let commands = app::Commands::new(register, unregister);
+        let get_registration = GetRegistrationHandlerMock::new(vec![]);
+        let queries = app::Queries::new(&get_registration);
+        let app = app::Application::new(&commands, &queries);
+        handle_all_with_app(&app, auth_required, rate_limits, connection, msgs)
+    }
+
+    fn query(
+        get_registration: &GetRegistrationHandlerMock,
+        connection: &mut Connection,
+        msg: String,
+    ) -> Vec<Reply> {
+        let register = RegisterHandlerMock::new();
+        let unregister = UnregisterHandlerMock::new();
+        let commands = app::Commands::new(&register, &unregister);
+        let queries = app::Queries::new(get_registration);
+        let app = app::Application::new(&commands, &queries);
+        let mut replies =
+            handle_all_with_app(&app, false, RateLimits::default(), connection, vec![msg]);
+        replies.remove(0)
+    }
+
+    fn handle_all_with_app(
+        app: &app::Application,
+        auth_required: bool,
+        rate_limits: RateLimits,
+        connection: &mut Connection,
+        msgs: Vec<String>,
+    ) -> Vec<Vec<Reply>> {
+        let config = Config::new(
+            String::from("127.0.0.1:0"),
+            default_events(),
+            String::from(fixtures::SERVICE),
+            &fixtures::service_keys(),
+            Auth::new(nostr::Url::parse(fixtures::RELAY).unwrap(), auth_required),
+            Limits::default(),
+            rate_limits,
+        )
+        .unwrap();
+        let server = Server::new(app, config);
+
+        msgs.into_iter()
+            .map(|msg| {
+                server
+                    .handle_message(connection, tungstenite::Message::Text(msg))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn localhost() -> IpAddr {
+        IpAddr::from([127, 0, 0, 1])
+    }
+
+    fn default_events() -> Events {
+        Events::new(
+            nostr::Kind::from(events::DEFAULT_REGISTRATION_KIND),
+            nostr::Kind::from(events::DEFAULT_UNREGISTRATION_KIND),
+            events::DEFAULT_FRESHNESS_WINDOW,
+        )
+        .unwrap()
+    }
+
+    fn rate_limit(burst: u32) -> rate_limits::RateLimit {
+        rate_limits::RateLimit::new(burst, 1).unwrap()
+    }
+
+    fn registration_filter(author: &str) -> nostr::Filter {
+        nostr::Filter::new()
+            .kind(nostr::Kind::from(events::DEFAULT_REGISTRATION_KIND))
+            .authors(vec![author.to_string()])
+    }
+
+    fn ok(event: &nostr::Event, accepted: bool, message: &str) -> Reply {
+        Reply::Ok {
+            event_id: event.id,
+            accepted,
+            message: String::from(message),
         }
     }
 }
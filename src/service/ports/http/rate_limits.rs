@@ -0,0 +1,326 @@
+use crate::errors::Result;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Buckets which are full are forgotten once in a while so that clients which stopped sending
+// events don't take up memory forever.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Rejections are logged at most once per interval so that a flood doesn't flood the logs as well.
+const METRICS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+// Limits applied to events and requests received by the servers. Every remote IP and every pub key
+// gets its own bucket.
+#[derive(Clone, Copy)]
+pub struct RateLimits {
+    per_ip: RateLimit,
+    per_pub_key: RateLimit,
+}
+
+impl RateLimits {
+    pub fn new(per_ip: RateLimit, per_pub_key: RateLimit) -> RateLimits {
+        RateLimits {
+            per_ip,
+            per_pub_key,
+        }
+    }
+
+    pub fn per_ip(&self) -> &RateLimit {
+        &self.per_ip
+    }
+
+    pub fn per_pub_key(&self) -> &RateLimit {
+        &self.per_pub_key
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            per_ip: RateLimit {
+                burst: 30,
+                per_minute: 60,
+            },
+            per_pub_key: RateLimit {
+                burst: 5,
+                per_minute: 10,
+            },
+        }
+    }
+}
+
+// A token bucket which holds up to burst tokens and is refilled with per_minute tokens every
+// minute. Every event takes one token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    burst: u32,
+    per_minute: u32,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, per_minute: u32) -> Result<RateLimit> {
+        if burst == 0 {
+            return Err("burst must be greater than zero".into());
+        }
+
+        if per_minute == 0 {
+            return Err("rate must be greater than zero".into());
+        }
+
+        Ok(RateLimit { burst, per_minute })
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    pub fn per_minute(&self) -> u32 {
+        self.per_minute
+    }
+
+    fn refill(&self, elapsed: Duration) -> f64 {
+        elapsed.as_secs_f64() * f64::from(self.per_minute) / 60.0
+    }
+}
+
+// Counts messages which were rejected because of the rate limits since the server started.
+#[derive(Default)]
+pub struct Metrics {
+    rate_limited_by_ip: AtomicU64,
+    rate_limited_by_pub_key: AtomicU64,
+}
+
+impl Metrics {
+    pub fn rate_limited_by_ip(&self) -> u64 {
+        self.rate_limited_by_ip.load(Ordering::Relaxed)
+    }
+
+    pub fn rate_limited_by_pub_key(&self) -> u64 {
+        self.rate_limited_by_pub_key.load(Ordering::Relaxed)
+    }
+}
+
+// The name identifies the server in the logged metrics.
+pub(in crate::service::ports) struct RateLimiter {
+    name: &'static str,
+    per_ip: Buckets<IpAddr>,
+    per_pub_key: Buckets<nostr::key::XOnlyPublicKey>,
+    metrics: Metrics,
+    metrics_logged_at: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub(in crate::service::ports) fn new(name: &'static str, limits: &RateLimits) -> Self {
+        RateLimiter {
+            name,
+            per_ip: Buckets::new(limits.per_ip),
+            per_pub_key: Buckets::new(limits.per_pub_key),
+            metrics: Metrics::default(),
+            metrics_logged_at: Mutex::new(None),
+        }
+    }
+
+    #[cfg(test)]
+    fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub(in crate::service::ports) fn check_ip(&self, address: IpAddr, now: Instant) -> bool {
+        let allowed = self.per_ip.take(address, now);
+        if !allowed {
+            self.metrics
+                .rate_limited_by_ip
+                .fetch_add(1, Ordering::Relaxed);
+            self.log_metrics(now);
+        }
+        allowed
+    }
+
+    pub(in crate::service::ports) fn check_pub_key(
+        &self,
+        pub_key: nostr::key::XOnlyPublicKey,
+        now: Instant,
+    ) -> bool {
+        let allowed = self.per_pub_key.take(pub_key, now);
+        if !allowed {
+            self.metrics
+                .rate_limited_by_pub_key
+                .fetch_add(1, Ordering::Relaxed);
+            self.log_metrics(now);
+        }
+        allowed
+    }
+
+    fn log_metrics(&self, now: Instant) {
+        let mut logged_at = self
+            .metrics_logged_at
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if logged_at.is_some_and(|logged_at| {
+            now.saturating_duration_since(logged_at) < METRICS_LOG_INTERVAL
+        }) {
+            return;
+        }
+        *logged_at = Some(now);
+
+        log::info!(
+            "{} server rate limited {} messages by address and {} by pub key so far",
+            self.name,
+            self.metrics.rate_limited_by_ip(),
+            self.metrics.rate_limited_by_pub_key()
+        );
+    }
+}
+
+struct Buckets<K> {
+    limit: RateLimit,
+    state: Mutex<BucketsState<K>>,
+}
+
+struct BucketsState<K> {
+    buckets: HashMap<K, Bucket>,
+    last_prune: Option<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl<K: Eq + Hash> Buckets<K> {
+    fn new(limit: RateLimit) -> Self {
+        Buckets {
+            limit,
+            state: Mutex::new(BucketsState {
+                buckets: HashMap::new(),
+                last_prune: None,
+            }),
+        }
+    }
+
+    fn take(&self, key: K, now: Instant) -> bool {
+        let burst = f64::from(self.limit.burst);
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+
+        match state.last_prune {
+            Some(last_prune) if now.saturating_duration_since(last_prune) < PRUNE_INTERVAL => {}
+            _ => {
+                let limit = self.limit;
+                state.buckets.retain(|_, bucket| {
+                    bucket.tokens + limit.refill(now.saturating_duration_since(bucket.updated_at))
+                        < burst
+                });
+                state.last_prune = Some(now);
+            }
+        }
+
+        let bucket = state.buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + self.limit.refill(elapsed)).min(burst);
+        bucket.updated_at = bucket.updated_at.max(now);
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn rate_limit_must_be_positive() {
+        assert!(RateLimit::new(0, 10).is_err());
+        assert!(RateLimit::new(10, 0).is_err());
+        assert!(RateLimit::new(1, 1).is_ok());
+    }
+
+    #[test]
+    fn bursts_are_allowed_and_then_limited() {
+        let buckets = Buckets::new(RateLimit::new(3, 60).unwrap());
+        let now = Instant::now();
+
+        assert!(buckets.take("a", now));
+        assert!(buckets.take("a", now));
+        assert!(buckets.take("a", now));
+        assert!(!buckets.take("a", now));
+    }
+
+    #[test]
+    fn buckets_are_refilled_over_time() {
+        let buckets = Buckets::new(RateLimit::new(2, 60).unwrap());
+        let now = Instant::now();
+
+        assert!(buckets.take("a", now));
+        assert!(buckets.take("a", now));
+        assert!(!buckets.take("a", now + Duration::from_millis(500)));
+        assert!(buckets.take("a", now + Duration::from_millis(1500)));
+        assert!(!buckets.take("a", now + Duration::from_millis(1500)));
+
+        // The bucket never holds more than burst tokens.
+        let later = now + Duration::from_secs(3600);
+        assert!(buckets.take("a", later));
+        assert!(buckets.take("a", later));
+        assert!(!buckets.take("a", later));
+    }
+
+    #[test]
+    fn keys_have_separate_buckets() {
+        let buckets = Buckets::new(RateLimit::new(1, 1).unwrap());
+        let now = Instant::now();
+
+        assert!(buckets.take("a", now));
+        assert!(!buckets.take("a", now));
+        assert!(buckets.take("b", now));
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let buckets = Buckets::new(RateLimit::new(1, 60).unwrap());
+        let now = Instant::now();
+
+        assert!(buckets.take("a", now));
+        assert!(buckets.take("b", now));
+        assert_eq!(buckets.len(), 2);
+
+        assert!(buckets.take("c", now + PRUNE_INTERVAL));
+        assert_eq!(buckets.len(), 1);
+    }
+
+    #[test]
+    fn rejections_are_counted() {
+        let limiter = RateLimiter::new(
+            "test",
+            &RateLimits::new(RateLimit::new(1, 1).unwrap(), RateLimit::new(1, 1).unwrap()),
+        );
+        let now = Instant::now();
+        let address = IpAddr::from([127, 0, 0, 1]);
+        let pub_key = fixtures::some_keys().public_key();
+
+        assert!(limiter.check_ip(address, now));
+        assert!(!limiter.check_ip(address, now));
+        assert!(!limiter.check_ip(address, now));
+        assert!(limiter.check_pub_key(pub_key, now));
+        assert!(!limiter.check_pub_key(pub_key, now));
+
+        assert_eq!(limiter.metrics().rate_limited_by_ip(), 2);
+        assert_eq!(limiter.metrics().rate_limited_by_pub_key(), 1);
+    }
+}
@@ -0,0 +1,1038 @@
+use super::http;
+use super::http::rate_limits::{RateLimiter, RateLimits};
+use super::http::{ConnectionSlot, DeadlineStream};
+use crate::errors::Result;
+use crate::service::app;
+use crate::service::app::commands::{LimitExceededError, OutdatedEventError, Register, Unregister};
+use crate::service::domain;
+use crate::service::domain::events;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REGISTRATIONS_PATH: &str = "/v1/registrations";
+const AUTHORIZATION_SCHEME: &str = "Nostr";
+
+// Requests are small so anything above these limits is most likely an attack.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 32;
+
+pub struct Config {
+    address: String,
+    public_url: nostr::Url,
+    max_body_size: usize,
+    max_connections: usize,
+    timeout: Duration,
+    rate_limits: RateLimits,
+}
+
+impl Config {
+    // The public URL is the URL under which clients reach the server, NIP-98 events have to be
+    // addressed to it. Limits are shared with the websocket server, the read timeout applies to
+    // reading a whole request.
+    pub fn new(
+        address: String,
+        public_url: nostr::Url,
+        limits: &http::Limits,
+        rate_limits: &RateLimits,
+    ) -> Result<Config> {
+        if address.is_empty() {
+            return Err("empty address".into());
+        }
+
+        if public_url.cannot_be_a_base() {
+            return Err(format!("public url '{public_url}' can't be used as a base").into());
+        }
+
+        Ok(Config {
+            address,
+            public_url,
+            max_body_size: limits.max_message_size(),
+            max_connections: limits.max_connections(),
+            timeout: limits.read_timeout(),
+            rate_limits: *rate_limits,
+        })
+    }
+}
+
+// HTTP interface for clients which would rather send a single request than speak NIP-01 over a
+// websocket. Requests are authenticated with NIP-98. Every connection is served on its own thread
+// and carries a single request.
+pub struct Server<'a> {
+    app: &'a app::Application<'a>,
+    config: Config,
+    listener: TcpListener,
+    rate_limiter: RateLimiter,
+    used_events: UsedEvents,
+    shutting_down: AtomicBool,
+}
+
+impl<'a> Server<'a> {
+    // Starts listening right away so that errors are reported before anything else is started.
+    pub fn new(app: &'a app::Application, config: Config) -> Result<Server<'a>> {
+        let listener = TcpListener::bind(&config.address)
+            .map_err(|err| format!("error listening on '{}': {err}", config.address))?;
+        let rate_limiter = RateLimiter::new("rest", &config.rate_limits);
+        Ok(Server {
+            app,
+            config,
+            listener,
+            rate_limiter,
+            used_events: UsedEvents::new(events::MAX_HTTP_AUTH_TIME_DIFFERENCE),
+            shutting_down: AtomicBool::new(false),
+        })
+    }
+
+    // Serves requests until shut_down is called.
+    pub fn serve(&self) {
+        let open_connections = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for stream in self.listener.incoming() {
+                if self.shutting_down.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::warn!("error accepting a connection: {err}");
+                        thread::sleep(http::ACCEPT_ERROR_DELAY);
+                        continue;
+                    }
+                };
+
+                let slot =
+                    match ConnectionSlot::acquire(&open_connections, self.config.max_connections) {
+                        Some(slot) => slot,
+                        None => {
+                            log::warn!("too many open connections, dropping a new connection");
+                            continue;
+                        }
+                    };
+
+                s.spawn(move || {
+                    let _slot = slot;
+                    if let Err(err) = self.serve_connection(stream) {
+                        log::debug!("connection error: {err}");
+                    }
+                });
+            }
+        });
+    }
+
+    // The listener is woken up by a connection of its own as accept can't be interrupted.
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        if let Ok(address) = self.listener.local_addr() {
+            let _ = TcpStream::connect(address);
+        }
+    }
+
+    fn serve_connection(&self, stream: TcpStream) -> Result<()> {
+        let address = stream.peer_addr()?.ip();
+        let timeout = self.config.timeout;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut stream = DeadlineStream::new(stream, timeout, Instant::now() + timeout);
+
+        let result = read_request(&mut stream, self.config.max_body_size)
+            .and_then(|request| self.handle(address, &request));
+        write_response(&mut stream, response(result))?;
+
+        // Unread data makes the connection end with a reset which could discard the response
+        // before the client reads it so the rest of the request is drained first.
+        stream.get_ref().shutdown(Shutdown::Write)?;
+        let _ = io::copy(
+            &mut (&mut stream).take(self.config.max_body_size as u64),
+            &mut io::sink(),
+        );
+        Ok(())
+    }
+
+    // The IP is checked before the request is authenticated so that flooding the server is cheap
+    // to reject, see http::Server.
+    fn handle(&self, address: IpAddr, request: &Request) -> std::result::Result<(), Error> {
+        if !self.rate_limiter.check_ip(address, Instant::now()) {
+            log::debug!("rate limited requests from {address}");
+            return Err(Error::TooManyRequests(
+                "too many requests sent from this address".into(),
+            ));
+        }
+        self.handle_request(request)
+    }
+
+    fn handle_request(&self, request: &Request) -> std::result::Result<(), Error> {
+        let (path, query) = match request.url.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (request.url.as_str(), None),
+        };
+
+        if path != REGISTRATIONS_PATH {
+            return Err(Error::NotFound);
+        }
+
+        // NIP-98 events are addressed to the absolute URL which the client used.
+        let mut url = self.config.public_url.clone();
+        url.set_path(path);
+        url.set_query(query);
+
+        match request.method.as_str() {
+            "POST" => {
+                let event = self.authenticate(request, &url)?;
+                let content: events::RegistrationEventContent = parse_body(&request.body)?;
+                let registration = content.registration(&event).map_err(Error::bad_request)?;
+                self.app
+                    .commands
+                    .register
+                    .handle(&Register {
+                        registration,
+                        content_size: request.body.len(),
+                    })
+                    .map_err(Error::from_handler_error)
+            }
+            "DELETE" => {
+                let event = self.authenticate(request, &url)?;
+                let content: events::UnregistrationEventContent = parse_body(&request.body)?;
+                let cmd = Unregister {
+                    pub_key: domain::PubKey::new(event.pubkey),
+                    push_token: content.push_token().map_err(Error::bad_request)?,
+                    event: events::event_info(&event),
+                };
+                self.app
+                    .commands
+                    .unregister
+                    .handle(&cmd)
+                    .map_err(Error::from_handler_error)
+            }
+            _ => Err(Error::MethodNotAllowed),
+        }
+    }
+
+    fn authenticate(
+        &self,
+        request: &Request,
+        url: &nostr::Url,
+    ) -> std::result::Result<nostr::Event, Error> {
+        let authorization = request
+            .authorization
+            .as_deref()
+            .ok_or_else(|| Error::Unauthorized("missing authorization header".into()))?;
+
+        let encoded_event = match authorization.split_once(' ') {
+            Some((scheme, encoded_event)) if scheme.eq_ignore_ascii_case(AUTHORIZATION_SCHEME) => {
+                encoded_event.trim()
+            }
+            _ => {
+                return Err(Error::Unauthorized(
+                    "unsupported authorization scheme".into(),
+                ))
+            }
+        };
+
+        let decoded_event = base64::engine::general_purpose::STANDARD
+            .decode(encoded_event)
+            .map_err(|err| Error::Unauthorized(format!("invalid base64: {err}")))?;
+        let event = serde_json::from_slice(&decoded_event)
+            .map_err(Error::unauthorized)
+            .and_then(|value| events::ReceivedEvent::new(value).map_err(Error::unauthorized))?;
+        let event = event.verify().map_err(Error::unauthorized)?;
+
+        events::validate_http_auth_event(
+            event,
+            url,
+            &request.method,
+            &request.body,
+            nostr::Timestamp::now(),
+        )
+        .map_err(Error::unauthorized)?;
+
+        if !self
+            .rate_limiter
+            .check_pub_key(event.pubkey, Instant::now())
+        {
+            log::debug!("rate limited requests from {}", event.pubkey);
+            return Err(Error::TooManyRequests(
+                "too many requests sent for this pub key".into(),
+            ));
+        }
+
+        // NIP-98 events are only checked for freshness so without remembering them an event could
+        // be replayed until it expires.
+        if !self.used_events.insert(event, nostr::Timestamp::now()) {
+            return Err(Error::Unauthorized("event has already been used".into()));
+        }
+
+        Ok(event.clone())
+    }
+}
+
+struct Request {
+    method: String,
+    url: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+// Only requests with a Content-Length are supported, clients send small JSON bodies anyway.
+fn read_request(
+    reader: &mut impl Read,
+    max_body_size: usize,
+) -> std::result::Result<Request, Error> {
+    let mut buf = vec![];
+    let mut chunk = [0; 1024];
+    loop {
+        let n = reader.read(&mut chunk).map_err(Error::from_read_error)?;
+        if n == 0 {
+            return Err(Error::BadRequest("incomplete request".into()));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        let head_size = match parsed.parse(&buf).map_err(Error::bad_request)? {
+            httparse::Status::Complete(head_size) => head_size,
+            httparse::Status::Partial if buf.len() > MAX_HEAD_SIZE => {
+                return Err(Error::BadRequest("request head is too large".into()))
+            }
+            httparse::Status::Partial => continue,
+        };
+
+        let header = |name: &str| {
+            parsed
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(name))
+                .map(|header| String::from_utf8_lossy(header.value).trim().to_string())
+        };
+
+        if header("Transfer-Encoding").is_some() {
+            return Err(Error::BadRequest("chunked bodies are not supported".into()));
+        }
+
+        let content_length = match header("Content-Length") {
+            Some(value) => value
+                .parse::<usize>()
+                .map_err(|err| Error::BadRequest(format!("invalid content length: {err}")))?,
+            None => 0,
+        };
+        if content_length > max_body_size {
+            return Err(Error::PayloadTooLarge);
+        }
+
+        let method = parsed.method.unwrap_or_default().to_uppercase();
+        let url = parsed.path.unwrap_or_default().to_string();
+        let authorization = header("Authorization");
+
+        let mut body = buf.split_off(head_size);
+        if body.len() > content_length {
+            return Err(Error::BadRequest(
+                "body is longer than its content length".into(),
+            ));
+        }
+        reader
+            .take((content_length - body.len()) as u64)
+            .read_to_end(&mut body)
+            .map_err(Error::from_read_error)?;
+        if body.len() < content_length {
+            return Err(Error::BadRequest("incomplete body".into()));
+        }
+
+        return Ok(Request {
+            method,
+            url,
+            authorization,
+            body,
+        });
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> std::result::Result<T, Error> {
+    serde_json::from_slice(body).map_err(|err| Error::BadRequest(format!("invalid body: {err}")))
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, &'static str)>,
+    body: Vec<u8>,
+}
+
+fn response(result: std::result::Result<(), Error>) -> Response {
+    let err = match result {
+        Ok(_) => {
+            return Response {
+                status: 204,
+                headers: vec![],
+                body: vec![],
+            }
+        }
+        Err(err) => err,
+    };
+
+    let mut headers = vec![("Content-Type", "application/json")];
+    match err {
+        Error::Unauthorized(_) => headers.push(("WWW-Authenticate", AUTHORIZATION_SCHEME)),
+        Error::MethodNotAllowed => headers.push(("Allow", "POST, DELETE")),
+        _ => {}
+    }
+    Response {
+        status: err.status(),
+        headers,
+        body: err.as_json().into_bytes(),
+    }
+}
+
+fn write_response(writer: &mut impl Write, response: Response) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.body.len()
+    );
+    for (field, value) in response.headers {
+        head.push_str(&format!("{field}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(&response.body)?;
+    writer.flush()
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        _ => "Internal Server Error",
+    }
+}
+
+// Ids of NIP-98 events which were already used. Events are forgotten once they are too old to be
+// accepted anyway.
+struct UsedEvents {
+    max_age: Duration,
+    state: Mutex<HashMap<nostr::EventId, nostr::Timestamp>>,
+}
+
+impl UsedEvents {
+    fn new(max_age: Duration) -> Self {
+        UsedEvents {
+            max_age,
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns false if the event was already used.
+    fn insert(&self, event: &nostr::Event, now: nostr::Timestamp) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.retain(|_, expires_at| *expires_at >= now);
+
+        let expires_at = nostr::Timestamp::from(event.created_at.as_u64() + self.max_age.as_secs());
+        state.insert(event.id, expires_at).is_none()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Error {
+    BadRequest(String),
+    Unauthorized(String),
+    NotFound,
+    MethodNotAllowed,
+    Forbidden(String),
+    Conflict(String),
+    PayloadTooLarge,
+    RequestTimeout,
+    TooManyRequests(String),
+    Internal(String),
+}
+
+impl Error {
+    fn bad_request(err: impl fmt::Display) -> Self {
+        Error::BadRequest(err.to_string())
+    }
+
+    fn unauthorized(err: impl fmt::Display) -> Self {
+        Error::Unauthorized(err.to_string())
+    }
+
+    fn from_read_error(err: io::Error) -> Self {
+        if http::is_timeout(&err) {
+            Error::RequestTimeout
+        } else {
+            Error::BadRequest(format!("error reading the request: {err}"))
+        }
+    }
+
+    // Outdated events and exceeded limits are the client's fault, everything else is an internal
+    // error.
+    fn from_handler_error(err: Box<dyn std::error::Error>) -> Self {
+        if err.is::<OutdatedEventError>() {
+            Error::Conflict(err.to_string())
+        } else if err.is::<LimitExceededError>() {
+            Error::Forbidden(err.to_string())
+        } else {
+            Error::Internal(err.to_string())
+        }
+    }
+
+    fn status(&self) -> u16 {
+        match self {
+            Error::BadRequest(_) => 400,
+            Error::Unauthorized(_) => 401,
+            Error::Forbidden(_) => 403,
+            Error::NotFound => 404,
+            Error::MethodNotAllowed => 405,
+            Error::Conflict(_) => 409,
+            Error::PayloadTooLarge => 413,
+            Error::RequestTimeout => 408,
+            Error::TooManyRequests(_) => 429,
+            Error::Internal(_) => 500,
+        }
+    }
+
+    fn as_json(&self) -> String {
+        json!({ "error": self.to_string() }).to_string()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadRequest(reason) => write!(f, "bad request: {reason}"),
+            Error::Unauthorized(reason) => write!(f, "unauthorized: {reason}"),
+            Error::Forbidden(reason) => write!(f, "forbidden: {reason}"),
+            Error::NotFound => write!(f, "not found"),
+            Error::MethodNotAllowed => write!(f, "method not allowed"),
+            Error::Conflict(reason) => write!(f, "conflict: {reason}"),
+            Error::PayloadTooLarge => write!(f, "payload too large"),
+            Error::RequestTimeout => write!(f, "request timeout"),
+            Error::TooManyRequests(reason) => write!(f, "too many requests: {reason}"),
+            Error::Internal(reason) => write!(f, "internal error: {reason}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::fixtures::{GetRegistrationHandlerMock, RegisterHandlerMock, UnregisterHandlerMock};
+    use crate::service::ports::http::rate_limits::RateLimit;
+
+    const PUBLIC_URL: &str = "https://notifications.example.com";
+    const REGISTRATIONS_URL: &str = "https://notifications.example.com/v1/registrations";
+
+    #[test]
+    fn registrations_are_passed_to_the_register_handler() {
+        let keys = fixtures::some_keys();
+        let register = RegisterHandlerMock::new();
+
+        let body = registration_body();
+        let result = handle_with(
+            &register,
+            &UnregisterHandlerMock::new(),
+            request("POST", &keys, REGISTRATIONS_URL, "POST", body),
+        );
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            register.pub_keys(),
+            vec![domain::PubKey::new(keys.public_key())]
+        );
+    }
+
+    #[test]
+    fn unregistrations_are_passed_to_the_unregister_handler() {
+        let keys = fixtures::some_keys();
+        let unregister = UnregisterHandlerMock::new();
+
+        let body = json!({"apnsToken": fixtures::APNS_TOKEN})
+            .to_string()
+            .into_bytes();
+        let result = handle_with(
+            &RegisterHandlerMock::new(),
+            &unregister,
+            request("DELETE", &keys, REGISTRATIONS_URL, "DELETE", body),
+        );
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            unregister.commands(),
+            vec![(
+                domain::PubKey::new(keys.public_key()),
+                String::from(fixtures::APNS_TOKEN)
+            )]
+        );
+    }
+
+    #[test]
+    fn requests_which_are_not_authenticated_correctly_are_rejected() {
+        let keys = fixtures::some_keys();
+        let body = registration_body();
+
+        let mut without_header = request("POST", &keys, REGISTRATIONS_URL, "POST", body.clone());
+        without_header.authorization = None;
+
+        let mut other_scheme = request("POST", &keys, REGISTRATIONS_URL, "POST", body.clone());
+        other_scheme.authorization = Some(String::from("Bearer token"));
+
+        let mut forged = request("POST", &keys, REGISTRATIONS_URL, "POST", body.clone());
+        let event = fixtures::forged_event(
+            fixtures::http_auth_event(&keys, REGISTRATIONS_URL, "POST", None),
+            fixtures::some_keys().public_key(),
+        );
+        forged.authorization = Some(authorization(&event));
+
+        let mut without_payload = request("POST", &keys, REGISTRATIONS_URL, "POST", body.clone());
+        let event = fixtures::http_auth_event(&keys, REGISTRATIONS_URL, "POST", None);
+        without_payload.authorization = Some(authorization(&event));
+
+        let cases = vec![
+            (without_header, "unauthorized: missing authorization header"),
+            (other_scheme, "unauthorized: unsupported authorization scheme"),
+            (forged, "unauthorized: invalid signature"),
+            (without_payload, "unauthorized: missing payload tag"),
+            (
+                request(
+                    "POST",
+                    &keys,
+                    "https://other.example.com/v1/registrations",
+                    "POST",
+                    body.clone(),
+                ),
+                "unauthorized: event is addressed to a different url: 'https://other.example.com/v1/registrations'",
+            ),
+            (
+                request("POST", &keys, REGISTRATIONS_URL, "DELETE", body),
+                "unauthorized: event is addressed to a different method: 'DELETE'",
+            ),
+        ];
+
+        for (request, expected_error) in cases {
+            let register = RegisterHandlerMock::new();
+            let result = handle_with(&register, &UnregisterHandlerMock::new(), request);
+            match result {
+                Err(err @ Error::Unauthorized(_)) => assert_eq!(err.to_string(), expected_error),
+                other => panic!("expected {expected_error}, got {other:?}"),
+            }
+            assert!(register.pub_keys().is_empty());
+        }
+    }
+
+    #[test]
+    fn invalid_requests_are_rejected() {
+        let keys = fixtures::some_keys();
+
+        let mut with_other_path = request("POST", &keys, REGISTRATIONS_URL, "POST", vec![]);
+        with_other_path.url = String::from("/v1/other");
+
+        let cases = vec![
+            (with_other_path, 404),
+            (
+                request("PUT", &keys, REGISTRATIONS_URL, "PUT", registration_body()),
+                405,
+            ),
+            (
+                request("POST", &keys, REGISTRATIONS_URL, "POST", b"{}".to_vec()),
+                400,
+            ),
+            (
+                request(
+                    "POST",
+                    &keys,
+                    REGISTRATIONS_URL,
+                    "POST",
+                    json!({"apnsToken": "", "relays": [], "locale": "en"})
+                        .to_string()
+                        .into_bytes(),
+                ),
+                400,
+            ),
+        ];
+
+        for (request, expected_status) in cases {
+            let result = handle_with(
+                &RegisterHandlerMock::new(),
+                &UnregisterHandlerMock::new(),
+                request,
+            );
+            match result {
+                Err(err) => assert_eq!(err.status(), expected_status),
+                Ok(_) => panic!("expected an error"),
+            }
+        }
+    }
+
+    #[test]
+    fn handler_errors_result_in_internal_errors() {
+        let result = handle_with(
+            &RegisterHandlerMock::new_failing(),
+            &UnregisterHandlerMock::new(),
+            request(
+                "POST",
+                &fixtures::some_keys(),
+                REGISTRATIONS_URL,
+                "POST",
+                registration_body(),
+            ),
+        );
+
+        assert_eq!(result, Err(Error::Internal(String::from("mock error"))));
+    }
+
+    #[test]
+    fn outdated_requests_result_in_conflicts() {
+        let result = handle_with(
+            &RegisterHandlerMock::new_outdated(),
+            &UnregisterHandlerMock::new(),
+            request(
+                "POST",
+                &fixtures::some_keys(),
+                REGISTRATIONS_URL,
+                "POST",
+                registration_body(),
+            ),
+        );
+
+        assert_eq!(
+            result,
+            Err(Error::Conflict(String::from(
+                "a newer registration is already stored"
+            )))
+        );
+    }
+
+    #[test]
+    fn exceeded_limits_result_in_forbidden_requests() {
+        let result = handle_with(
+            &RegisterHandlerMock::new_limited(),
+            &UnregisterHandlerMock::new(),
+            request(
+                "POST",
+                &fixtures::some_keys(),
+                REGISTRATIONS_URL,
+                "POST",
+                registration_body(),
+            ),
+        );
+
+        assert_eq!(
+            result,
+            Err(Error::Forbidden(String::from(
+                "the service doesn't accept new registrations"
+            )))
+        );
+    }
+
+    #[test]
+    fn server_responds_with_status_codes() {
+        let register = RegisterHandlerMock::new();
+        let unregister = UnregisterHandlerMock::new();
+        let commands = app::Commands::new(&register, &unregister);
+        let get_registration = GetRegistrationHandlerMock::new(vec![]);
+        let queries = app::Queries::new(&get_registration);
+        let app = app::Application::new(&commands, &queries);
+
+        let server = Server::new(&app, config(limits(1024, 5000), RateLimits::default())).unwrap();
+        let address = server.listener.local_addr().unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| server.serve());
+
+            let keys = fixtures::some_keys();
+            let body = registration_body();
+            let event = fixtures::http_auth_event(&keys, REGISTRATIONS_URL, "POST", Some(&body));
+            let response = send(address, "POST", Some(&authorization(&event)), &body);
+            assert!(response.starts_with("HTTP/1.1 204"), "{response}");
+
+            let response = send(address, "POST", None, &body);
+            assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+            assert!(response.contains("WWW-Authenticate: Nostr"), "{response}");
+            assert!(
+                response.ends_with(r#"{"error":"unauthorized: missing authorization header"}"#),
+                "{response}"
+            );
+
+            let response = send(address, "POST", None, &[b' '; 2048]);
+            assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+
+            server.shut_down();
+        });
+
+        assert_eq!(register.pub_keys().len(), 1);
+    }
+
+    #[test]
+    fn auth_events_can_only_be_used_once() {
+        let keys = fixtures::some_keys();
+        let request = request(
+            "POST",
+            &keys,
+            REGISTRATIONS_URL,
+            "POST",
+            registration_body(),
+        );
+        let replayed = Request {
+            method: request.method.clone(),
+            url: request.url.clone(),
+            authorization: request.authorization.clone(),
+            body: request.body.clone(),
+        };
+
+        let register = RegisterHandlerMock::new();
+        let results = handle_all_with(
+            &register,
+            &UnregisterHandlerMock::new(),
+            RateLimits::default(),
+            vec![request, replayed],
+        );
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Err(Error::Unauthorized(String::from(
+                    "event has already been used"
+                )))
+            ]
+        );
+        assert_eq!(register.pub_keys().len(), 1);
+    }
+
+    #[test]
+    fn used_events_are_forgotten_once_they_expire() {
+        let used_events = UsedEvents::new(Duration::from_secs(60));
+        let event =
+            fixtures::http_auth_event(&fixtures::some_keys(), REGISTRATIONS_URL, "POST", None);
+        let now = event.created_at;
+
+        assert!(used_events.insert(&event, now));
+        assert!(!used_events.insert(&event, nostr::Timestamp::from(now.as_u64() + 60)));
+        assert!(used_events.insert(&event, nostr::Timestamp::from(now.as_u64() + 61)));
+    }
+
+    #[test]
+    fn requests_sent_from_the_same_address_are_rate_limited() {
+        let requests = (0..3)
+            .map(|_| {
+                request(
+                    "POST",
+                    &fixtures::some_keys(),
+                    REGISTRATIONS_URL,
+                    "POST",
+                    registration_body(),
+                )
+            })
+            .collect();
+
+        let register = RegisterHandlerMock::new();
+        let results = handle_all_with(
+            &register,
+            &UnregisterHandlerMock::new(),
+            rate_limits(2, 10),
+            requests,
+        );
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Ok(()),
+                Err(Error::TooManyRequests(String::from(
+                    "too many requests sent from this address"
+                )))
+            ]
+        );
+        assert_eq!(register.pub_keys().len(), 2);
+    }
+
+    #[test]
+    fn requests_sent_for_the_same_pub_key_are_rate_limited() {
+        let keys = fixtures::some_keys();
+        let requests = (0..3)
+            .map(|_| {
+                request(
+                    "POST",
+                    &keys,
+                    REGISTRATIONS_URL,
+                    "POST",
+                    registration_body(),
+                )
+            })
+            .collect();
+
+        let register = RegisterHandlerMock::new();
+        let results = handle_all_with(
+            &register,
+            &UnregisterHandlerMock::new(),
+            rate_limits(10, 2),
+            requests,
+        );
+
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Ok(()),
+                Err(Error::TooManyRequests(String::from(
+                    "too many requests sent for this pub key"
+                )))
+            ]
+        );
+        assert_eq!(register.pub_keys().len(), 2);
+    }
+
+    #[test]
+    fn server_times_out_slow_requests_without_blocking_others() {
+        let register = RegisterHandlerMock::new();
+        let unregister = UnregisterHandlerMock::new();
+        let commands = app::Commands::new(&register, &unregister);
+        let get_registration = GetRegistrationHandlerMock::new(vec![]);
+        let queries = app::Queries::new(&get_registration);
+        let app = app::Application::new(&commands, &queries);
+
+        let server = Server::new(&app, config(limits(1024, 300), RateLimits::default())).unwrap();
+        let address = server.listener.local_addr().unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| server.serve());
+
+            let mut slow = TcpStream::connect(address).unwrap();
+            slow.write_all(b"POST /v1/registrations HTTP/1.1\r\n")
+                .unwrap();
+
+            let response = send(address, "POST", None, b"{}");
+            assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+
+            let start = Instant::now();
+            for byte in b"Host: localhost\r\n".iter().cycle() {
+                if slow.write_all(&[*byte]).is_err() || start.elapsed() > ms(2000) {
+                    break;
+                }
+                std::thread::sleep(ms(50));
+            }
+            assert!(start.elapsed() < ms(1000));
+
+            server.shut_down();
+        });
+    }
+
+    fn handle_with(
+        register: &RegisterHandlerMock,
+        unregister: &UnregisterHandlerMock,
+        request: Request,
+    ) -> std::result::Result<(), Error> {
+        handle_all_with(register, unregister, RateLimits::default(), vec![request]).remove(0)
+    }
+
+    fn handle_all_with(
+        register: &RegisterHandlerMock,
+        unregister: &UnregisterHandlerMock,
+        rate_limits: RateLimits,
+        requests: Vec<Request>,
+    ) -> Vec<std::result::Result<(), Error>> {
+        let commands = app::Commands::new(register, unregister);
+        let get_registration = GetRegistrationHandlerMock::new(vec![]);
+        let queries = app::Queries::new(&get_registration);
+        let app = app::Application::new(&commands, &queries);
+        let server = Server::new(&app, config(limits(1024, 5000), rate_limits)).unwrap();
+
+        requests
+            .iter()
+            .map(|request| server.handle(localhost(), request))
+            .collect()
+    }
+
+    fn config(limits: http::Limits, rate_limits: RateLimits) -> Config {
+        Config::new(
+            String::from("127.0.0.1:0"),
+            nostr::Url::parse(PUBLIC_URL).unwrap(),
+            &limits,
+            &rate_limits,
+        )
+        .unwrap()
+    }
+
+    fn limits(max_body_size: usize, timeout_millis: u64) -> http::Limits {
+        let timeout = Duration::from_millis(timeout_millis);
+        http::Limits::new(10, max_body_size, timeout, timeout).unwrap()
+    }
+
+    fn rate_limits(per_ip_burst: u32, per_pub_key_burst: u32) -> RateLimits {
+        RateLimits::new(
+            RateLimit::new(per_ip_burst, 1).unwrap(),
+            RateLimit::new(per_pub_key_burst, 1).unwrap(),
+        )
+    }
+
+    fn localhost() -> IpAddr {
+        IpAddr::from([127, 0, 0, 1])
+    }
+
+    fn request(
+        method: &str,
+        keys: &nostr::Keys,
+        auth_url: &str,
+        auth_method: &str,
+        body: Vec<u8>,
+    ) -> Request {
+        let event = fixtures::http_auth_event(keys, auth_url, auth_method, Some(&body));
+        Request {
+            method: String::from(method),
+            url: String::from(REGISTRATIONS_PATH),
+            authorization: Some(authorization(&event)),
+            body,
+        }
+    }
+
+    fn authorization(event: &nostr::Event) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(event.as_json());
+        format!("{AUTHORIZATION_SCHEME} {encoded}")
+    }
+
+    fn registration_body() -> Vec<u8> {
+        serde_json::to_vec(&fixtures::some_registration_event_content()).unwrap()
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn send(
+        address: std::net::SocketAddr,
+        method: &str,
+        authorization: Option<&str>,
+        body: &[u8],
+    ) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut head = format!(
+            "{method} {REGISTRATIONS_PATH} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            body.len()
+        );
+        if let Some(authorization) = authorization {
+            head.push_str(&format!("Authorization: {authorization}\r\n"));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+}